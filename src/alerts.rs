@@ -0,0 +1,390 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::audit::AuditChange;
+
+pub const WARNING: &str = "warning";
+pub const CRITICAL: &str = "critical";
+const HIGH: &str = "high";
+const LOW: &str = "low";
+// probes without their own thresholds use the limits the temps widget always turned amber and red at
+const DEFAULT_WARNING_HIGH: i32 = 26;
+const DEFAULT_CRITICAL_HIGH: i32 = 28;
+const DEFAULT_HYSTERESIS: i32 = 1;
+const DEFAULT_CONSECUTIVE_SAMPLES: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum AlertError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("temperature probe not found")]
+    NotFound,
+    #[error("critical thresholds have to be past the warning ones, low below high, hysteresis positive and at least one sample")]
+    InvalidThresholds
+}
+
+/// Limits for one probe, any of them can be left out. An alert opens once `consecutive_samples` readings
+/// in a row are at or past a threshold and closes once a reading is `hysteresis` degrees back inside it.
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct TemperatureThresholds {
+    temperature_id: u32,
+    warning_high: Option<i32>,
+    critical_high: Option<i32>,
+    warning_low: Option<i32>,
+    critical_low: Option<i32>,
+    hysteresis: i32,
+    consecutive_samples: u32
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct TemperatureAlert {
+    id: u32,
+    temperature_id: u32,
+    label: String,
+    severity: String,
+    // high when the probe got too hot, low when it got too cold
+    direction: String,
+    threshold: i32,
+    opened_time: u32,
+    closed_time: Option<u32>,
+    last_temp: i32,
+    // the hottest reading of a high alert, the coldest of a low one
+    peak_temp: i32
+}
+
+pub async fn create_alert_tables(pool: &SqlitePool) -> Result<(), AlertError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS temperature_thresholds (temperature_id INTEGER PRIMARY KEY, warning_high INTEGER, critical_high INTEGER, warning_low INTEGER, critical_low INTEGER, hysteresis INTEGER NOT NULL, consecutive_samples INTEGER NOT NULL)")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS temperature_alerts (id INTEGER PRIMARY KEY AUTOINCREMENT, temperature_id INTEGER NOT NULL, severity TEXT NOT NULL, direction TEXT NOT NULL, threshold INTEGER NOT NULL, opened_time INTEGER NOT NULL, closed_time INTEGER, last_temp INTEGER NOT NULL, peak_temp INTEGER NOT NULL)")
+        .execute(pool).await?;
+    // thresholds saved before a band was required get the smallest one allowed
+    sqlx::query("UPDATE temperature_thresholds SET hysteresis = 1 WHERE hysteresis < 1")
+        .execute(pool).await?;
+    Ok(())
+}
+
+// default limits are in celsius, fahrenheit probes get them converted and rounded
+fn in_unit(celsius: i32, unit: &str) -> i32 {
+    match unit {
+        "F" => ((celsius * 9) as f64 / 5.0).round() as i32 + 32,
+        _ => celsius
+    }
+}
+
+fn default_thresholds(temperature_id: u32, unit: &str) -> TemperatureThresholds {
+    TemperatureThresholds {
+        temperature_id,
+        warning_high: Some(in_unit(DEFAULT_WARNING_HIGH, unit)),
+        critical_high: Some(in_unit(DEFAULT_CRITICAL_HIGH, unit)),
+        warning_low: None,
+        critical_low: None,
+        hysteresis: DEFAULT_HYSTERESIS,
+        consecutive_samples: DEFAULT_CONSECUTIVE_SAMPLES
+    }
+}
+
+async fn get_thresholds(temperature_id: u32, pool: &SqlitePool) -> Result<TemperatureThresholds, AlertError> {
+    let query = sqlx::query_as::<_, TemperatureThresholds>("SELECT * FROM temperature_thresholds WHERE temperature_id = $1").bind(temperature_id);
+    if let Some(thresholds) = query.fetch_optional(pool).await? {
+        return Ok(thresholds);
+    }
+    let unit: Option<String> = sqlx::query_scalar("SELECT unit FROM temperatures WHERE id = $1")
+        .bind(temperature_id)
+        .fetch_optional(pool).await?;
+    Ok(default_thresholds(temperature_id, unit.as_deref().unwrap_or_default()))
+}
+
+impl TemperatureThresholds {
+    // most severe first, so the first level every recent reading is past is the one to alert at
+    fn levels(&self) -> Vec<(&'static str, &'static str, i32)> {
+        [
+            (CRITICAL, HIGH, self.critical_high),
+            (CRITICAL, LOW, self.critical_low),
+            (WARNING, HIGH, self.warning_high),
+            (WARNING, LOW, self.warning_low)
+        ].into_iter()
+            .filter_map(|(severity, direction, threshold)| threshold.map(|threshold| (severity, direction, threshold)))
+            .collect()
+    }
+
+    fn is_valid(&self) -> bool {
+        let ordered = |lower: Option<i32>, upper: Option<i32>| match (lower, upper) {
+            (Some(lower), Some(upper)) => lower <= upper,
+            _ => true
+        };
+        ordered(self.warning_high, self.critical_high)
+            && ordered(self.critical_low, self.warning_low)
+            && ordered(self.warning_low.or(self.critical_low), self.warning_high.or(self.critical_high))
+            // without a band an alert would close and reopen every time a reading lands on the threshold
+            && self.hysteresis >= 1
+            && self.consecutive_samples >= 1
+    }
+}
+
+fn is_past(direction: &str, temp: i32, threshold: i32) -> bool {
+    match direction {
+        HIGH => temp >= threshold,
+        _ => temp <= threshold
+    }
+}
+
+fn is_cleared(alert: &TemperatureAlert, temp: i32, hysteresis: i32) -> bool {
+    match alert.direction.as_str() {
+        HIGH => temp <= alert.threshold - hysteresis,
+        _ => temp >= alert.threshold + hysteresis
+    }
+}
+
+/// Checks the latest readings of a probe against its thresholds, run after every reading is stored.
+///
+/// A probe has at most one open alert. It closes once a reading is back past the hysteresis band, and
+/// is raised to critical in place when the readings get worse, so a critical alert that closes while
+/// the probe is still past its warning threshold is followed by a new warning alert.
+pub async fn evaluate_temperature_alerts(temperature_id: u32, pool: &SqlitePool) -> Result<(), AlertError> {
+    let thresholds = get_thresholds(temperature_id, pool).await?;
+    let readings: Vec<i32> = sqlx::query_scalar("SELECT temp FROM temperature_readings WHERE temperature_id = $1 ORDER BY reading_time DESC, id DESC LIMIT $2")
+        .bind(temperature_id)
+        .bind(thresholds.consecutive_samples)
+        .fetch_all(pool).await?;
+    let latest = match readings.first() {
+        Some(latest) => *latest,
+        None => return Ok(())
+    };
+    let time = now()?;
+
+    let query = sqlx::query_as::<_, TemperatureAlert>("SELECT temperature_alerts.*, temperatures.label FROM temperature_alerts JOIN temperatures ON temperatures.id = temperature_alerts.temperature_id WHERE temperature_id = $1 AND closed_time IS NULL").bind(temperature_id);
+    let mut open = query.fetch_optional(pool).await?;
+    if let Some(alert) = &open {
+        let peak = match alert.direction.as_str() {
+            HIGH => alert.peak_temp.max(latest),
+            _ => alert.peak_temp.min(latest)
+        };
+        let closed_time = is_cleared(alert, latest, thresholds.hysteresis).then_some(time);
+        sqlx::query("UPDATE temperature_alerts SET last_temp = $1, peak_temp = $2, closed_time = $3 WHERE id = $4")
+            .bind(latest)
+            .bind(peak)
+            .bind(closed_time)
+            .bind(alert.id)
+            .execute(pool).await?;
+        if closed_time.is_some() {
+            open = None;
+        }
+    }
+
+    if readings.len() < thresholds.consecutive_samples as usize {
+        return Ok(());
+    }
+    let level = thresholds.levels().into_iter()
+        .find(|(_, direction, threshold)| readings.iter().all(|temp| is_past(direction, *temp, *threshold)));
+    let (severity, direction, threshold) = match level {
+        Some(level) => level,
+        None => return Ok(())
+    };
+
+    match open {
+        None => {
+            let peak = match direction {
+                HIGH => readings.iter().max(),
+                _ => readings.iter().min()
+            }.copied().unwrap_or(latest);
+            sqlx::query("INSERT INTO temperature_alerts (temperature_id, severity, direction, threshold, opened_time, last_temp, peak_temp) values ($1, $2, $3, $4, $5, $6, $7)")
+                .bind(temperature_id)
+                .bind(severity)
+                .bind(direction)
+                .bind(threshold)
+                .bind(time)
+                .bind(latest)
+                .bind(peak)
+                .execute(pool).await?;
+        },
+        Some(alert) if alert.severity == WARNING && severity == CRITICAL && alert.direction == direction => {
+            sqlx::query("UPDATE temperature_alerts SET severity = $1, threshold = $2 WHERE id = $3")
+                .bind(severity)
+                .bind(threshold)
+                .bind(alert.id)
+                .execute(pool).await?;
+        },
+        Some(_) => {}
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlertQuery {
+    #[serde(default)]
+    open: bool
+}
+
+pub async fn get_alerts(query: web::Query<AlertQuery>, data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let sql = match query.open {
+        true => "SELECT temperature_alerts.*, temperatures.label FROM temperature_alerts JOIN temperatures ON temperatures.id = temperature_alerts.temperature_id WHERE closed_time IS NULL ORDER BY opened_time DESC, id DESC",
+        false => "SELECT temperature_alerts.*, temperatures.label FROM temperature_alerts JOIN temperatures ON temperatures.id = temperature_alerts.temperature_id ORDER BY opened_time DESC, id DESC"
+    };
+    let rows: Vec<TemperatureAlert> = sqlx::query_as::<_, TemperatureAlert>(sql).fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+async fn ensure_probe_exists(temperature_id: u32, pool: &SqlitePool) -> Result<(), AlertError> {
+    let probe: Option<u32> = sqlx::query_scalar("SELECT id FROM temperatures WHERE id = $1")
+        .bind(temperature_id)
+        .fetch_optional(pool).await?;
+    probe.map(|_| ()).ok_or(AlertError::NotFound)
+}
+
+pub async fn get_temperature_thresholds(id: web::Path<u32>, data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    ensure_probe_exists(*id, &data.db_pool).await?;
+    let thresholds = get_thresholds(*id, &data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(thresholds))
+}
+
+fn default_hysteresis() -> i32 {
+    DEFAULT_HYSTERESIS
+}
+
+fn default_consecutive_samples() -> u32 {
+    DEFAULT_CONSECUTIVE_SAMPLES
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateThresholds {
+    warning_high: Option<i32>,
+    critical_high: Option<i32>,
+    warning_low: Option<i32>,
+    critical_low: Option<i32>,
+    #[serde(default = "default_hysteresis")]
+    hysteresis: i32,
+    #[serde(default = "default_consecutive_samples")]
+    consecutive_samples: u32
+}
+
+// replaces every threshold of the probe, ones left out are turned off
+pub async fn update_temperature_thresholds(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    update: web::Json<UpdateThresholds>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    ensure_probe_exists(*id, &data.db_pool).await?;
+    let thresholds = TemperatureThresholds {
+        temperature_id: *id,
+        warning_high: update.warning_high,
+        critical_high: update.critical_high,
+        warning_low: update.warning_low,
+        critical_low: update.critical_low,
+        hysteresis: update.hysteresis,
+        consecutive_samples: update.consecutive_samples
+    };
+    if !thresholds.is_valid() {
+        return Err(AlertError::InvalidThresholds.into());
+    }
+
+    let before = get_thresholds(*id, &data.db_pool).await?;
+    let query = sqlx::query_as::<_, TemperatureThresholds>("INSERT INTO temperature_thresholds (temperature_id, warning_high, critical_high, warning_low, critical_low, hysteresis, consecutive_samples) values ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT(temperature_id) DO UPDATE SET warning_high = $2, critical_high = $3, warning_low = $4, critical_low = $5, hysteresis = $6, consecutive_samples = $7 RETURNING *")
+        .bind(thresholds.temperature_id)
+        .bind(thresholds.warning_high)
+        .bind(thresholds.critical_high)
+        .bind(thresholds.warning_low)
+        .bind(thresholds.critical_low)
+        .bind(thresholds.hysteresis)
+        .bind(thresholds.consecutive_samples);
+    let row: TemperatureThresholds = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("temperature_thresholds", row.temperature_id).before(&before).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pool;
+    use crate::temperatures::create_temperature_probe;
+
+    fn thresholds(hysteresis: i32, consecutive_samples: u32) -> TemperatureThresholds {
+        TemperatureThresholds {
+            temperature_id: 1,
+            warning_high: Some(26),
+            critical_high: Some(28),
+            warning_low: Some(15),
+            critical_low: Some(12),
+            hysteresis,
+            consecutive_samples
+        }
+    }
+
+    #[test]
+    fn thresholds_need_a_band_and_a_sample() {
+        assert!(thresholds(1, 1).is_valid());
+        assert!(!thresholds(0, 3).is_valid());
+        assert!(!thresholds(-1, 3).is_valid());
+        assert!(!thresholds(2, 0).is_valid());
+        assert!(!TemperatureThresholds { critical_high: Some(25), ..thresholds(2, 3) }.is_valid());
+        assert!(!TemperatureThresholds { critical_low: Some(16), ..thresholds(2, 3) }.is_valid());
+        assert!(!TemperatureThresholds { warning_low: Some(27), critical_low: None, ..thresholds(2, 3) }.is_valid());
+    }
+
+    // stores a reading and evaluates it the way update_temperature does
+    async fn read(temperature_id: u32, temp: i32, pool: &SqlitePool) -> Vec<(String, String, i32, bool, i32)> {
+        sqlx::query("INSERT INTO temperature_readings (temperature_id, reading_time, temp) values ($1, $2, $3)")
+            .bind(temperature_id)
+            .bind(now().unwrap())
+            .bind(temp)
+            .execute(pool).await.unwrap();
+        evaluate_temperature_alerts(temperature_id, pool).await.unwrap();
+        sqlx::query_as("SELECT severity, direction, threshold, closed_time IS NOT NULL, peak_temp FROM temperature_alerts WHERE temperature_id = $1 ORDER BY id ASC")
+            .bind(temperature_id)
+            .fetch_all(pool).await.unwrap()
+    }
+
+    fn alert(severity: &str, direction: &str, threshold: i32, closed: bool, peak_temp: i32) -> (String, String, i32, bool, i32) {
+        (severity.to_string(), direction.to_string(), threshold, closed, peak_temp)
+    }
+
+    #[actix_rt::test]
+    async fn alerts_open_escalate_and_close() {
+        let pool = test_pool().await;
+        create_temperature_probe("server room", None, "C", 0, &pool).await.unwrap();
+        let id = 1;
+        sqlx::query("INSERT INTO temperature_thresholds (temperature_id, warning_high, critical_high, hysteresis, consecutive_samples) values ($1, 26, 28, 2, 3)")
+            .bind(id)
+            .execute(&pool).await.unwrap();
+
+        // a reading back under the threshold starts the count again
+        for temp in [27, 27, 25, 27, 27] {
+            assert!(read(id, temp, &pool).await.is_empty());
+        }
+        assert_eq!(read(id, 27, &pool).await, vec![alert(WARNING, HIGH, 26, false, 27)]);
+
+        // the alert is raised to critical in place once enough readings are past the critical threshold
+        assert_eq!(read(id, 29, &pool).await, vec![alert(WARNING, HIGH, 26, false, 29)]);
+        assert_eq!(read(id, 30, &pool).await, vec![alert(WARNING, HIGH, 26, false, 30)]);
+        assert_eq!(read(id, 29, &pool).await, vec![alert(CRITICAL, HIGH, 28, false, 30)]);
+
+        // back under the threshold but inside the band keeps it open
+        assert_eq!(read(id, 27, &pool).await, vec![alert(CRITICAL, HIGH, 28, false, 30)]);
+        // out of the band closes it, and the probe is still past its warning threshold
+        assert_eq!(read(id, 26, &pool).await, vec![alert(CRITICAL, HIGH, 28, true, 30), alert(WARNING, HIGH, 26, false, 29)]);
+
+        assert_eq!(read(id, 25, &pool).await, vec![alert(CRITICAL, HIGH, 28, true, 30), alert(WARNING, HIGH, 26, false, 29)]);
+        assert_eq!(read(id, 24, &pool).await, vec![alert(CRITICAL, HIGH, 28, true, 30), alert(WARNING, HIGH, 26, true, 29)]);
+        assert_eq!(read(id, 24, &pool).await.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn low_alerts_use_the_band_above_the_threshold() {
+        let pool = test_pool().await;
+        create_temperature_probe("fridge", None, "C", 0, &pool).await.unwrap();
+        sqlx::query("INSERT INTO temperature_thresholds (temperature_id, warning_low, critical_low, hysteresis, consecutive_samples) values (1, 4, 2, 1, 1)")
+            .execute(&pool).await.unwrap();
+
+        assert_eq!(read(1, 1, &pool).await, vec![alert(CRITICAL, LOW, 2, false, 1)]);
+        assert_eq!(read(1, 2, &pool).await, vec![alert(CRITICAL, LOW, 2, false, 1)]);
+        assert_eq!(read(1, 3, &pool).await, vec![alert(CRITICAL, LOW, 2, true, 1), alert(WARNING, LOW, 4, false, 3)]);
+    }
+}
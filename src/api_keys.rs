@@ -0,0 +1,228 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::{SystemTime, SystemTimeError};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, add_column_if_missing, hash_secret};
+use crate::audit::AuditChange;
+use crate::scopes::{is_valid_scope, parse_scopes};
+use crate::users::{AuthenticatedUser, UserToken, TOKEN_ISSUER, TOKEN_AUDIENCE};
+
+#[derive(Error, Debug)]
+pub enum ApiKeyError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    SigningError(#[from] jwt::Error),
+    #[error("api key not found")]
+    NotFound,
+    #[error("unknown scope {0}")]
+    InvalidScope(String)
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct ApiKey {
+    id: u32,
+    name: String,
+    owner: String,
+    #[serde(skip_serializing)]
+    secret_hash: String,
+    created_time: u32,
+    last_used_time: Option<u32>,
+    revoked: bool,
+    scopes: Option<String>
+}
+
+pub async fn create_api_key_table(pool: &SqlitePool) -> Result<(), ApiKeyError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS api_keys (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, owner TEXT NOT NULL, secret_hash TEXT NOT NULL, created_time INTEGER, last_used_time INTEGER, revoked INTEGER, scopes TEXT)")
+        .execute(pool).await?;
+    add_column_if_missing(pool, "api_keys", "scopes", "TEXT").await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewApiKey {
+    name: String,
+    scopes: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewApiKeyResponse {
+    id: u32,
+    name: String,
+    scopes: Vec<String>,
+    token: String
+}
+
+pub async fn create_api_key(
+    req: HttpRequest,
+    new_key: web::Json<NewApiKey>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if let Some(scope) = new_key.scopes.iter().find(|scope| !is_valid_scope(scope)) {
+        return Err(ApiKeyError::InvalidScope(scope.clone()).into());
+    }
+
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32;
+    let mut transaction = data.db_pool.begin().await?;
+
+    // the row id becomes the jti claim so the key has to exist before it can be signed
+    let id: u32 = sqlx::query_scalar("INSERT INTO api_keys (name, owner, secret_hash, created_time, revoked, scopes) values ($1, $2, $3, $4, $5, $6) RETURNING id")
+        .bind(&new_key.name)
+        .bind(&auth.username)
+        .bind("")
+        .bind(time)
+        .bind(false)
+        .bind(new_key.scopes.join(" "))
+        .fetch_one(&mut transaction).await?;
+
+    let claims = UserToken {
+        iss: TOKEN_ISSUER.to_string(),
+        aud: TOKEN_AUDIENCE.to_string(),
+        sub: auth.username.clone(),
+        initials: auth.initials.clone(),
+        iat: time as u64,
+        nbf: time as u64,
+        exp: None,
+        role: None,
+        jti: Some(id.to_string()),
+        generation: None
+    };
+
+    let token = data.jwt_keys.sign(claims).map_err(ApiKeyError::from)?;
+
+    sqlx::query("UPDATE api_keys SET secret_hash = $1 WHERE id = $2")
+        .bind(hash_secret(&token))
+        .bind(id)
+        .execute(&mut transaction).await?;
+    transaction.commit().await?;
+
+    // the token itself is never logged, only what it grants
+    AuditChange::new("api_key", id).after(&new_key.0).record(&req);
+
+    Ok(HttpResponse::Ok().json(NewApiKeyResponse { id, name: new_key.name.clone(), scopes: new_key.scopes.clone(), token }))
+}
+
+pub async fn get_api_keys(
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, ApiKey>("SELECT * FROM api_keys ORDER BY created_time ASC");
+    let rows: Vec<ApiKey> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+pub async fn revoke_api_key(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, ApiKey>("UPDATE api_keys SET revoked = $1 WHERE id = $2 RETURNING *")
+        .bind(true)
+        .bind(*id);
+    let key: ApiKey = match query.fetch_optional(&data.db_pool).await? {
+        Some(key) => key,
+        None => return Err(ApiKeyError::NotFound.into())
+    };
+    AuditChange::new("api_key", key.id).after(&key).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+// checks that the key behind a jti claim exists, is not revoked and matches the presented token
+// returns the scopes granted to the key when it is valid
+pub async fn validate_api_key(jti: &str, token: &str, pool: &SqlitePool) -> Result<Option<Vec<String>>, ApiKeyError> {
+    let id: u32 = match jti.parse() {
+        Ok(id) => id,
+        Err(_) => return Ok(None)
+    };
+    let query = sqlx::query_as::<_, ApiKey>("SELECT * FROM api_keys WHERE id = $1").bind(id);
+    let key: ApiKey = match query.fetch_optional(pool).await? {
+        Some(key) => key,
+        None => return Ok(None)
+    };
+
+    if key.revoked || key.secret_hash != hash_secret(token) {
+        return Ok(None);
+    }
+
+    sqlx::query("UPDATE api_keys SET last_used_time = $1 WHERE id = $2")
+        .bind(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32)
+        .bind(id)
+        .execute(pool).await?;
+
+    Ok(Some(parse_scopes(&key.scopes.unwrap_or_default())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::{StatusCode, header::AUTHORIZATION};
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use crate::{test_pool, test_state};
+    use crate::users::{bearer_auth_validator, create_user, get_user_by_username, issue_access_token};
+
+    async fn whoami(auth: AuthenticatedUser) -> HttpResponse {
+        HttpResponse::Ok().body(auth.username)
+    }
+
+    #[actix_rt::test]
+    async fn revoked_keys_are_rejected() {
+        let pool = test_pool().await;
+        create_user("carol", "CA", "pass123", "admin", &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .service(web::scope("/api")
+                .wrap(HttpAuthentication::with_fn(bearer_auth_validator))
+                .route("/me", web::get().to(whoami))
+                .route("/apikeys", web::post().to(create_api_key))
+                .route("/apikeys/{id}", web::delete().to(revoke_api_key)))).await;
+        let user = get_user_by_username("carol", &pool).await.unwrap();
+        let login_token = issue_access_token(&user, &test_state(&pool).jwt_keys).unwrap();
+        let bearer = |request: TestRequest, token: &str| request.insert_header((AUTHORIZATION, format!("Bearer {}", token)));
+
+        let request = bearer(TestRequest::post(), &login_token)
+            .uri("/api/apikeys")
+            .set_json(serde_json::json!({ "name": "wall display", "scopes": ["reminders:read"] }))
+            .to_request();
+        let key: NewApiKeyResponse = call_and_read_body_json(&app, request).await;
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), &key.token).uri("/api/me").to_request()).await.status(), StatusCode::OK);
+        let last_used: Option<u32> = sqlx::query_scalar("SELECT last_used_time FROM api_keys WHERE id = $1")
+            .bind(key.id)
+            .fetch_one(&pool).await.unwrap();
+        assert!(last_used.is_some());
+
+        let request = bearer(TestRequest::delete(), &login_token).uri(&format!("/api/apikeys/{}", key.id)).to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), &key.token).uri("/api/me").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        // the login token that managed the key is unaffected
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), &login_token).uri("/api/me").to_request()).await.status(), StatusCode::OK);
+
+        let request = bearer(TestRequest::delete(), &login_token).uri("/api/apikeys/999").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn keys_only_match_the_token_they_were_issued_with() {
+        let pool = test_pool().await;
+        create_api_key_table(&pool).await.unwrap();
+        sqlx::query("INSERT INTO api_keys (name, owner, secret_hash, created_time, revoked, scopes) values ($1, $2, $3, $4, $5, $6)")
+            .bind("wall display")
+            .bind("carol")
+            .bind(hash_secret("issued-token"))
+            .bind(0)
+            .bind(false)
+            .bind("reminders:read presence:read")
+            .execute(&pool).await.unwrap();
+
+        assert_eq!(validate_api_key("1", "issued-token", &pool).await.unwrap(), Some(vec!["reminders:read".to_string(), "presence:read".to_string()]));
+        assert_eq!(validate_api_key("1", "another-token", &pool).await.unwrap(), None);
+        assert_eq!(validate_api_key("2", "issued-token", &pool).await.unwrap(), None);
+        assert_eq!(validate_api_key("not-a-number", "issued-token", &pool).await.unwrap(), None);
+    }
+}
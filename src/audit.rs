@@ -0,0 +1,313 @@
+use actix_web::{
+    Error, HttpMessage, HttpRequest, HttpResponse, Responder, web,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+};
+use sqlx::{SqlitePool};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::time::{SystemTime, SystemTimeError};
+use serde::{Serialize, Deserialize, Serializer};
+use serde_json::Value;
+use thiserror::Error;
+use crate::{AppState, AppError};
+use crate::users::AuthenticatedUser;
+
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+// entries older than this are removed by the daily prune
+const AUDIT_RETENTION: u32 = 60 * 60 * 24 * 365;
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error)
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    id: u32,
+    created_time: u32,
+    actor: Option<String>,
+    api_key_id: Option<u32>,
+    action: String,
+    entity_type: Option<String>,
+    entity_id: Option<String>,
+    #[serde(serialize_with = "stored_json")]
+    before: Option<String>,
+    #[serde(serialize_with = "stored_json")]
+    after: Option<String>,
+    status: u16
+}
+
+// before and after are stored as text but returned as json rather than escaped strings
+fn stored_json<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    let parsed: Option<Value> = value.as_deref().and_then(|value| serde_json::from_str(value).ok());
+    parsed.serialize(serializer)
+}
+
+pub async fn create_audit_log_table(pool: &SqlitePool) -> Result<(), AuditError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS audit_log (id INTEGER PRIMARY KEY AUTOINCREMENT, created_time INTEGER, actor TEXT, api_key_id INTEGER, action TEXT NOT NULL, entity_type TEXT, entity_id TEXT, before TEXT, after TEXT, status INTEGER)")
+        .execute(pool).await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS audit_log_created_time ON audit_log (created_time)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+/// What a handler changed, picked up by the `AuditLog` middleware once the response is ready.
+///
+/// Requests whose handler did not record a change are still logged, with the entity taken from the route.
+#[derive(Clone, Debug, Default)]
+pub struct AuditChange {
+    actor: Option<String>,
+    entity_type: String,
+    entity_id: String,
+    before: Option<Value>,
+    after: Option<Value>
+}
+
+impl AuditChange {
+    pub fn new(entity_type: &str, entity_id: impl ToString) -> AuditChange {
+        AuditChange { entity_type: entity_type.to_string(), entity_id: entity_id.to_string(), ..Default::default() }
+    }
+
+    // for public routes where nobody was authenticated before the handler ran
+    pub fn actor(mut self, username: &str) -> AuditChange {
+        self.actor = Some(username.to_string());
+        self
+    }
+
+    pub fn before<T: Serialize>(mut self, value: &T) -> AuditChange {
+        self.before = serde_json::to_value(value).ok();
+        self
+    }
+
+    pub fn after<T: Serialize>(mut self, value: &T) -> AuditChange {
+        self.after = serde_json::to_value(value).ok();
+        self
+    }
+
+    pub fn record(self, req: &HttpRequest) {
+        req.extensions_mut().insert(self);
+    }
+}
+
+// marks a request the AuditLog middleware should leave out
+#[derive(Clone, Copy, Debug)]
+struct NotAudited;
+
+/// Leaves a successful request out of the audit log, for routes devices call constantly where every entry
+/// would be the same. Requests rejected before the handler runs are still logged.
+pub fn skip_audit(req: &HttpRequest) {
+    req.extensions_mut().insert(NotAudited);
+}
+
+/// Whether anything was logged against the entity in the last `within` seconds, for routes that only keep a
+/// sample of their requests in the log.
+pub async fn audited_within(entity_type: &str, entity_id: impl ToString, within: u32, pool: &SqlitePool) -> Result<bool, AuditError> {
+    let since = (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32).saturating_sub(within);
+    let found: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM audit_log WHERE entity_type = $1 AND entity_id = $2 AND created_time >= $3)")
+        .bind(entity_type)
+        .bind(entity_id.to_string())
+        .bind(since)
+        .fetch_one(pool).await?;
+    Ok(found)
+}
+
+struct NewAuditEntry {
+    actor: Option<String>,
+    api_key_id: Option<u32>,
+    action: String,
+    entity_type: Option<String>,
+    entity_id: Option<String>,
+    before: Option<Value>,
+    after: Option<Value>,
+    status: u16
+}
+
+impl NewAuditEntry {
+    fn from_request(req: &HttpRequest, status: u16) -> NewAuditEntry {
+        let pattern = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+        let user = req.extensions().get::<AuthenticatedUser>().cloned();
+        let change = req.extensions().get::<AuditChange>().cloned();
+
+        let (actor, entity_type, entity_id, before, after) = match change {
+            Some(change) => (change.actor, Some(change.entity_type), Some(change.entity_id), change.before, change.after),
+            None => {
+                // requests that failed before the handler recorded anything are logged against the first path segment
+                let entity_type = pattern.trim_start_matches("/api/").split('/').next().filter(|segment| !segment.is_empty()).map(|segment| segment.to_string());
+                (None, entity_type, req.match_info().get("id").map(|id| id.to_string()), None, None)
+            }
+        };
+
+        NewAuditEntry {
+            actor: user.as_ref().map(|user| user.username.clone()).or(actor),
+            api_key_id: user.and_then(|user| user.api_key_id),
+            action: format!("{} {}", req.method(), pattern),
+            entity_type,
+            entity_id,
+            before,
+            after,
+            status
+        }
+    }
+}
+
+async fn write_audit_entry(entry: NewAuditEntry, pool: &SqlitePool) -> Result<(), AuditError> {
+    let before = entry.before.map(|value| serde_json::to_string(&value)).transpose()?;
+    let after = entry.after.map(|value| serde_json::to_string(&value)).transpose()?;
+    sqlx::query("INSERT INTO audit_log (created_time, actor, api_key_id, action, entity_type, entity_id, before, after, status) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)")
+        .bind(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32)
+        .bind(entry.actor)
+        .bind(entry.api_key_id)
+        .bind(entry.action)
+        .bind(entry.entity_type)
+        .bind(entry.entity_id)
+        .bind(before)
+        .bind(after)
+        .bind(entry.status)
+        .execute(pool).await?;
+    Ok(())
+}
+
+/// Middleware that writes every POST, PUT, PATCH and DELETE to the audit log, including rejected ones.
+/// It has to sit inside the authentication middleware to see who made the request.
+pub struct AuditLog;
+
+impl<S, B> Transform<S, ServiceRequest> for AuditLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuditLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditLogMiddleware { service }))
+    }
+}
+
+pub struct AuditLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let mutating = matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            if mutating && res.request().extensions().get::<NotAudited>().is_none() {
+                let entry = NewAuditEntry::from_request(res.request(), res.status().as_u16());
+                if let Some(data) = res.request().app_data::<web::Data<AppState>>() {
+                    if let Err(e) = write_audit_entry(entry, &data.db_pool).await {
+                        println!("unable to write audit log - {:?}", e);
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Removes audit entries older than a year, returning how many were removed.
+pub async fn prune_audit_log(pool: &SqlitePool) -> Result<u64, AuditError> {
+    let cutoff = (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32).saturating_sub(AUDIT_RETENTION);
+    let result = sqlx::query("DELETE FROM audit_log WHERE created_time < $1")
+        .bind(cutoff)
+        .execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    user: Option<String>,
+    entity_type: Option<String>,
+    entity_id: Option<String>,
+    from: Option<u32>,
+    to: Option<u32>,
+    limit: Option<u32>,
+    offset: Option<u32>
+}
+
+pub async fn get_audit_log(
+    filter: web::Query<AuditQuery>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let query = sqlx::query_as::<_, AuditEntry>("SELECT * FROM audit_log WHERE ($1 IS NULL OR actor = $1) AND ($2 IS NULL OR entity_type = $2) AND ($3 IS NULL OR entity_id = $3) AND ($4 IS NULL OR created_time >= $4) AND ($5 IS NULL OR created_time <= $5) ORDER BY id DESC LIMIT $6 OFFSET $7")
+        .bind(&filter.user)
+        .bind(&filter.entity_type)
+        .bind(&filter.entity_id)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(limit)
+        .bind(filter.offset.unwrap_or(0));
+    let rows: Vec<AuditEntry> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, test};
+    use crate::{test_pool, test_state};
+
+    async fn count_entries(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM audit_log").fetch_one(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn skipped_requests_are_not_logged() {
+        let pool = test_pool().await;
+        let state = web::Data::new(test_state(&pool));
+        let app = test::init_service(App::new()
+            .app_data(state)
+            .wrap(AuditLog)
+            .route("/readings", web::post().to(|req: HttpRequest| async move {
+                skip_audit(&req);
+                HttpResponse::Ok().finish()
+            }))
+            .route("/changes", web::post().to(|| async { HttpResponse::Ok().finish() }))).await;
+
+        test::call_service(&app, test::TestRequest::post().uri("/readings").to_request()).await;
+        assert_eq!(count_entries(&pool).await, 0);
+        test::call_service(&app, test::TestRequest::post().uri("/changes").to_request()).await;
+        assert_eq!(count_entries(&pool).await, 1);
+    }
+
+    #[actix_rt::test]
+    async fn prune_removes_entries_past_retention() {
+        let pool = test_pool().await;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as u32;
+        for created_time in [now - AUDIT_RETENTION - 60, now - AUDIT_RETENTION + 60, now] {
+            sqlx::query("INSERT INTO audit_log (created_time, action, status) values ($1, $2, $3)")
+                .bind(created_time)
+                .bind("POST /api/test")
+                .bind(200)
+                .execute(&pool).await.unwrap();
+        }
+
+        assert_eq!(prune_audit_log(&pool).await.unwrap(), 1);
+        assert_eq!(count_entries(&pool).await, 2);
+    }
+}
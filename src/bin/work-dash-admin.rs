@@ -0,0 +1,101 @@
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+use sqlx::{sqlite::{SqlitePool}, migrate::MigrateDatabase};
+use work_dash_backend::{
+    DB_URL, create_tables,
+    users::{create_user, list_users, reset_password},
+    roles::{ADMIN_ROLE, DEFAULT_ROLE, set_role},
+    totp::remove_totp
+};
+
+const USAGE: &str = "usage: work-dash-admin <command>
+
+commands:
+    list                                     list all users
+    create <username> <initials> [--admin]   create a user, password is read from stdin
+    reset <username>                         reset a user's password, password is read from stdin
+    promote <username>                       give a user the admin role
+    role <username> <role>                   set a user's role, one of viewer, member, admin or device
+    reset-totp <username>                    turn off a user's two-factor authentication";
+
+fn read_password() -> io::Result<String> {
+    print!("Password: ");
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(1)
+}
+
+#[actix_web::main]
+async fn main() {
+    dotenv::dotenv().ok();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    if !sqlx::Sqlite::database_exists(DB_URL).await.expect("check if DB exists failed") {
+        sqlx::Sqlite::create_database(DB_URL).await.expect("create DB failed");
+    }
+
+    let pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    create_tables(&pool).await.expect("create tables failed");
+
+    match args.as_slice() {
+        ["list"] => {
+            let users = list_users(&pool).await.unwrap_or_else(|e| fail(&format!("unable to list users - {}", e)));
+            println!("{:<6}{:<24}{:<10}role", "id", "username", "initials");
+            for user in users {
+                println!("{:<6}{:<24}{:<10}{}", user.id, user.username, user.initials, user.role);
+            }
+        },
+        ["create", username, initials, flags @ ..] => {
+            let role = match flags {
+                [] => DEFAULT_ROLE,
+                ["--admin"] => ADMIN_ROLE,
+                _ => fail(USAGE)
+            };
+            let password = read_password().unwrap_or_else(|e| fail(&format!("unable to read password - {}", e)));
+            if password.is_empty() {
+                fail("password cannot be empty");
+            }
+            match create_user(username, initials, &password, role, &pool).await {
+                Ok(user) => println!("created user {} ({})", user.username, user.id),
+                Err(e) => fail(&format!("unable to create user - {}", e))
+            }
+        },
+        ["reset", username] => {
+            let password = read_password().unwrap_or_else(|e| fail(&format!("unable to read password - {}", e)));
+            if password.is_empty() {
+                fail("password cannot be empty");
+            }
+            match reset_password(username, &password, &pool).await {
+                Ok(_) => println!("password reset for {}", username),
+                Err(e) => fail(&format!("unable to reset password - {}", e))
+            }
+        },
+        ["promote", username] => {
+            match set_role(username, ADMIN_ROLE, &pool).await {
+                Ok(_) => println!("{} is now an admin", username),
+                Err(e) => fail(&format!("unable to promote user - {}", e))
+            }
+        },
+        ["role", username, role] => {
+            match set_role(username, role, &pool).await {
+                Ok(_) => println!("{} now has the {} role", username, role),
+                Err(e) => fail(&format!("unable to set role - {}", e))
+            }
+        },
+        ["reset-totp", username] => {
+            match remove_totp(username, &pool).await {
+                Ok(_) => println!("two-factor authentication turned off for {}", username),
+                Err(e) => fail(&format!("unable to reset two-factor authentication - {}", e))
+            }
+        },
+        _ => fail(USAGE)
+    }
+}
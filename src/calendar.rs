@@ -0,0 +1,875 @@
+use actix_web::{Responder, HttpMessage, HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use ical::IcalParser;
+use ical::parser::ParserError;
+use ical::parser::ical::component::IcalEvent;
+use ical::property::Property;
+use reqwest::{Url, header::LOCATION, redirect::Policy};
+use sqlx::{SqlitePool};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::audit::AuditChange;
+use crate::users::AuthenticatedUser;
+use crate::scopes::GrantedScopes;
+use crate::recurrence::RecurrenceRule;
+use crate::presence::{PresenceError, clear_synced_presence, set_synced_presence};
+
+pub const CALENDAR_SOURCE: &str = "calendar";
+// events are expanded this far ahead, which is also the furthest /api/calendar/upcoming looks
+const CALENDAR_WINDOW_DAYS: i64 = 60;
+const DEFAULT_UPCOMING_DAYS: i64 = 7;
+const ADMIN_SCOPE: &str = "calendar:admin";
+// feeds are fetched with these limits so a slow or huge response can't tie up the refresh job
+const FETCH_TIMEOUT_SECONDS: u64 = 20;
+const MAX_CALENDAR_BYTES: usize = 5 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum CalendarError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+    #[error(transparent)]
+    ParseError(#[from] ParserError),
+    #[error(transparent)]
+    PresenceError(#[from] PresenceError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("a calendar needs either a url or ics content")]
+    InvalidSource,
+    #[error("calendar urls have to be http or https")]
+    InvalidUrl,
+    #[error("calendar urls can't point at private, loopback or link-local addresses")]
+    BlockedAddress,
+    #[error("the calendar is larger than 5 MB")]
+    TooLarge,
+    #[error("the calendar url redirected too many times")]
+    TooManyRedirects,
+    #[error("calendar not found")]
+    NotFound,
+    #[error("only calendar admins can manage team calendars and other people's calendars")]
+    Forbidden
+}
+
+// calendars without a username belong to the whole team
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct Calendar {
+    id: u32,
+    name: String,
+    username: Option<String>,
+    url: Option<String>,
+    #[serde(skip_serializing)]
+    ics: Option<String>,
+    created_time: u32,
+    last_sync_time: Option<u32>,
+    last_error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CalendarEvent {
+    uid: String,
+    summary: String,
+    location: Option<String>,
+    start_time: u32,
+    end_time: u32,
+    all_day: bool,
+    // busy, tentative, free or oof, following the Outlook busy status
+    show_as: String
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct UpcomingEvent {
+    id: u32,
+    calendar_id: u32,
+    calendar_name: String,
+    username: Option<String>,
+    uid: String,
+    summary: String,
+    location: Option<String>,
+    start_time: u32,
+    end_time: u32,
+    all_day: bool,
+    show_as: String
+}
+
+pub async fn create_calendar_tables(pool: &SqlitePool) -> Result<(), CalendarError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS calendars (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, username TEXT, url TEXT, ics TEXT, created_time INTEGER, last_sync_time INTEGER, last_error TEXT)")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS calendar_events (id INTEGER PRIMARY KEY AUTOINCREMENT, calendar_id INTEGER NOT NULL, uid TEXT NOT NULL, summary TEXT NOT NULL, location TEXT, start_time INTEGER, end_time INTEGER, all_day INTEGER, show_as TEXT NOT NULL)")
+        .execute(pool).await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS calendar_events_time ON calendar_events (start_time, end_time)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+// Outlook and Exchange name zones the Windows way, these are the IANA zones CLDR maps them to
+const WINDOWS_ZONES: &[(&str, &str)] = &[
+    ("Dateline Standard Time", "Etc/GMT+12"),
+    ("UTC-11", "Etc/GMT+11"),
+    ("Hawaiian Standard Time", "Pacific/Honolulu"),
+    ("Alaskan Standard Time", "America/Anchorage"),
+    ("Pacific Standard Time", "America/Los_Angeles"),
+    ("US Mountain Standard Time", "America/Phoenix"),
+    ("Mountain Standard Time", "America/Denver"),
+    ("Central America Standard Time", "America/Guatemala"),
+    ("Central Standard Time", "America/Chicago"),
+    ("Central Standard Time (Mexico)", "America/Mexico_City"),
+    ("Canada Central Standard Time", "America/Regina"),
+    ("SA Pacific Standard Time", "America/Bogota"),
+    ("Eastern Standard Time", "America/New_York"),
+    ("US Eastern Standard Time", "America/Indiana/Indianapolis"),
+    ("Venezuela Standard Time", "America/Caracas"),
+    ("Atlantic Standard Time", "America/Halifax"),
+    ("SA Western Standard Time", "America/La_Paz"),
+    ("Pacific SA Standard Time", "America/Santiago"),
+    ("Newfoundland Standard Time", "America/St_Johns"),
+    ("E. South America Standard Time", "America/Sao_Paulo"),
+    ("Argentina Standard Time", "America/Argentina/Buenos_Aires"),
+    ("UTC", "Etc/UTC"),
+    ("GMT Standard Time", "Europe/London"),
+    ("Greenwich Standard Time", "Atlantic/Reykjavik"),
+    ("Morocco Standard Time", "Africa/Casablanca"),
+    ("W. Europe Standard Time", "Europe/Berlin"),
+    ("Central Europe Standard Time", "Europe/Budapest"),
+    ("Romance Standard Time", "Europe/Paris"),
+    ("Central European Standard Time", "Europe/Warsaw"),
+    ("W. Central Africa Standard Time", "Africa/Lagos"),
+    ("GTB Standard Time", "Europe/Bucharest"),
+    ("E. Europe Standard Time", "Europe/Chisinau"),
+    ("FLE Standard Time", "Europe/Kiev"),
+    ("Israel Standard Time", "Asia/Jerusalem"),
+    ("Egypt Standard Time", "Africa/Cairo"),
+    ("South Africa Standard Time", "Africa/Johannesburg"),
+    ("Turkey Standard Time", "Europe/Istanbul"),
+    ("Russian Standard Time", "Europe/Moscow"),
+    ("Arab Standard Time", "Asia/Riyadh"),
+    ("E. Africa Standard Time", "Africa/Nairobi"),
+    ("Iran Standard Time", "Asia/Tehran"),
+    ("Arabian Standard Time", "Asia/Dubai"),
+    ("Pakistan Standard Time", "Asia/Karachi"),
+    ("India Standard Time", "Asia/Kolkata"),
+    ("Sri Lanka Standard Time", "Asia/Colombo"),
+    ("Nepal Standard Time", "Asia/Kathmandu"),
+    ("Bangladesh Standard Time", "Asia/Dhaka"),
+    ("Myanmar Standard Time", "Asia/Yangon"),
+    ("SE Asia Standard Time", "Asia/Bangkok"),
+    ("China Standard Time", "Asia/Shanghai"),
+    ("Singapore Standard Time", "Asia/Singapore"),
+    ("W. Australia Standard Time", "Australia/Perth"),
+    ("Taipei Standard Time", "Asia/Taipei"),
+    ("Tokyo Standard Time", "Asia/Tokyo"),
+    ("Korea Standard Time", "Asia/Seoul"),
+    ("Cen. Australia Standard Time", "Australia/Adelaide"),
+    ("AUS Central Standard Time", "Australia/Darwin"),
+    ("E. Australia Standard Time", "Australia/Brisbane"),
+    ("AUS Eastern Standard Time", "Australia/Sydney"),
+    ("Tasmania Standard Time", "Australia/Hobart"),
+    ("New Zealand Standard Time", "Pacific/Auckland"),
+    ("Tonga Standard Time", "Pacific/Tongatapu")
+];
+
+fn parse_tzid(tzid: &str) -> Option<Tz> {
+    let tzid = tzid.trim_matches('"');
+    tzid.parse::<Tz>().ok().or_else(|| {
+        WINDOWS_ZONES.iter()
+            .find(|(windows, _)| *windows == tzid)
+            .and_then(|(_, iana)| iana.parse::<Tz>().ok())
+    })
+}
+
+// floating times and time zones we cannot identify use the server's zone
+#[derive(Clone, Copy)]
+enum EventZone {
+    Utc,
+    Named(Tz),
+    Local
+}
+
+impl EventZone {
+    fn to_utc(self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            EventZone::Utc => Some(Utc.from_utc_datetime(&local)),
+            EventZone::Named(tz) => tz.from_local_datetime(&local).earliest().map(|time| time.with_timezone(&Utc)),
+            EventZone::Local => Local.from_local_datetime(&local).earliest().map(|time| time.with_timezone(&Utc))
+        }
+    }
+
+    fn to_local(self, utc: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            EventZone::Utc => utc.naive_utc(),
+            EventZone::Named(tz) => utc.with_timezone(&tz).naive_local(),
+            EventZone::Local => utc.with_timezone(&Local).naive_local()
+        }
+    }
+}
+
+fn property<'a>(event: &'a IcalEvent, name: &str) -> Option<&'a Property> {
+    event.properties.iter().find(|property| property.name == name)
+}
+
+fn property_value<'a>(event: &'a IcalEvent, name: &str) -> Option<&'a str> {
+    property(event, name).and_then(|property| property.value.as_deref())
+}
+
+fn param<'a>(property: &'a Property, name: &str) -> Option<&'a str> {
+    property.params.as_ref()?.iter()
+        .find(|(param, _)| param == name)
+        .and_then(|(_, values)| values.first())
+        .map(|value| value.as_str())
+}
+
+// returns the local time, its zone and whether it was a date without a time
+fn parse_time(value: &str, tzid: Option<&str>) -> Option<(NaiveDateTime, EventZone, bool)> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Some((date.and_hms_opt(0, 0, 0)?, EventZone::Local, true));
+    }
+    let (value, zone) = match value.strip_suffix('Z') {
+        Some(value) => (value, EventZone::Utc),
+        None => (value, tzid.and_then(parse_tzid).map(EventZone::Named).unwrap_or(EventZone::Local))
+    };
+    Some((NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?, zone, false))
+}
+
+fn property_time(property: &Property) -> Option<(NaiveDateTime, EventZone, bool)> {
+    parse_time(property.value.as_deref()?, param(property, "TZID"))
+}
+
+// ISO 8601 durations as used by DURATION, e.g. PT1H30M or P1D
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value.trim_start_matches('+'))
+    };
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    for c in value.strip_prefix('P')?.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => {},
+            unit => {
+                let amount: i64 = number.parse().ok()?;
+                number.clear();
+                total += match unit {
+                    'W' => Duration::weeks(amount),
+                    'D' => Duration::days(amount),
+                    'H' => Duration::hours(amount),
+                    'M' => Duration::minutes(amount),
+                    'S' => Duration::seconds(amount),
+                    _ => return None
+                };
+            }
+        }
+    }
+    Some(if negative { -total } else { total })
+}
+
+fn show_as(event: &IcalEvent) -> String {
+    match property_value(event, "X-MICROSOFT-CDO-BUSYSTATUS") {
+        Some("OOF") => "oof",
+        Some("TENTATIVE") => "tentative",
+        Some("FREE") | Some("WORKINGELSEWHERE") => "free",
+        Some(_) => "busy",
+        None if property_value(event, "TRANSP") == Some("TRANSPARENT") => "free",
+        None => "busy"
+    }.to_string()
+}
+
+// EXDATE can repeat and each one can hold a comma separated list
+fn excluded_times(event: &IcalEvent) -> HashSet<i64> {
+    event.properties.iter()
+        .filter(|property| property.name == "EXDATE")
+        .flat_map(|property| {
+            let tzid = param(property, "TZID");
+            property.value.as_deref().unwrap_or_default().split(',')
+                .filter_map(|value| parse_time(value.trim(), tzid))
+                .filter_map(|(time, zone, _)| zone.to_utc(time))
+                .map(|time| time.timestamp())
+                .collect::<Vec<i64>>()
+        })
+        .collect()
+}
+
+/// Parses ics content into the events that overlap the window, with recurring events expanded into
+/// one event per occurrence. Moved or changed occurrences (RECURRENCE-ID) replace the occurrence they
+/// were generated from, and cancelled events are left out.
+pub fn parse_calendar(content: &str, window_start: DateTime<Utc>, window_end: DateTime<Utc>) -> Result<Vec<CalendarEvent>, CalendarError> {
+    let mut events: Vec<CalendarEvent> = Vec::new();
+    let mut overridden: HashSet<(String, i64)> = HashSet::new();
+    let mut generated: Vec<CalendarEvent> = Vec::new();
+
+    for calendar in IcalParser::new(content.as_bytes()) {
+        for event in calendar?.events {
+            // a cancelled override still removes the occurrence it replaces
+            let uid = property_value(&event, "UID").unwrap_or_default().to_string();
+            if let Some((recurrence_id, recurrence_zone, _)) = property(&event, "RECURRENCE-ID").and_then(property_time) {
+                if let Some(recurrence_id) = recurrence_zone.to_utc(recurrence_id) {
+                    overridden.insert((uid.clone(), recurrence_id.timestamp()));
+                }
+            }
+            if property_value(&event, "STATUS") == Some("CANCELLED") {
+                continue;
+            }
+            let (start, zone, all_day) = match property(&event, "DTSTART").and_then(property_time) {
+                Some(start) => start,
+                None => continue
+            };
+            let start_utc = match zone.to_utc(start) {
+                Some(start_utc) => start_utc,
+                None => continue
+            };
+            let length = match (property(&event, "DTEND").and_then(property_time), property_value(&event, "DURATION").and_then(parse_duration)) {
+                (Some((end, end_zone, _)), _) => end_zone.to_utc(end).map(|end| end - start_utc).unwrap_or_else(Duration::zero),
+                (None, Some(duration)) => duration,
+                (None, None) if all_day => Duration::days(1),
+                (None, None) => Duration::zero()
+            };
+
+            let starts: Vec<DateTime<Utc>> = match property_value(&event, "RRULE").map(|rule| rule.parse::<RecurrenceRule>()) {
+                Some(Ok(rule)) => {
+                    let excluded = excluded_times(&event);
+                    // occurrences that started before the window can still be running inside it
+                    let end = zone.to_local(window_end);
+                    rule.occurrences(start, end, |local| zone.to_utc(local).map(|time| time.naive_utc()).unwrap_or(local))
+                        .into_iter()
+                        .filter_map(|occurrence| zone.to_utc(occurrence))
+                        .filter(|occurrence| !excluded.contains(&occurrence.timestamp()))
+                        .collect()
+                },
+                // rules we cannot expand still show their first occurrence
+                Some(Err(e)) => {
+                    println!("unable to expand event {} - {}", uid, e);
+                    vec![start_utc]
+                },
+                None => vec![start_utc]
+            };
+
+            let recurring = property(&event, "RRULE").is_some() && property(&event, "RECURRENCE-ID").is_none();
+            for occurrence in starts {
+                let end = occurrence + length;
+                if end <= window_start || occurrence >= window_end {
+                    continue;
+                }
+                let parsed = CalendarEvent {
+                    uid: uid.clone(),
+                    summary: property_value(&event, "SUMMARY").unwrap_or_default().to_string(),
+                    location: property_value(&event, "LOCATION").filter(|location| !location.is_empty()).map(|location| location.to_string()),
+                    start_time: occurrence.timestamp() as u32,
+                    end_time: end.timestamp() as u32,
+                    all_day,
+                    show_as: show_as(&event)
+                };
+                if recurring { generated.push(parsed) } else { events.push(parsed) }
+            }
+        }
+    }
+
+    events.extend(generated.into_iter().filter(|event| !overridden.contains(&(event.uid.clone(), event.start_time as i64))));
+    events.sort_by_key(|event| event.start_time);
+    Ok(events)
+}
+
+// only addresses on the internet are fetched, so a calendar url can't be used to reach services on the server's own network
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 0.0.0.0/8 and the 100.64.0.0/10 carrier-grade NAT range aren't covered by the std checks
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || ip.is_documentation() || first == 0 || (first == 100 && (second & 0xc0) == 64))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_address(IpAddr::V4(mapped)),
+            // fc00::/7 is unique local and fe80::/10 link-local
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+fn calendar_url(url: &str) -> Result<Url, CalendarError> {
+    let url = Url::parse(url).map_err(|_| CalendarError::InvalidUrl)?;
+    match url.scheme() {
+        "http" | "https" if url.host().is_some() => Ok(url),
+        _ => Err(CalendarError::InvalidUrl)
+    }
+}
+
+// every address the host resolves to has to be allowed, the first is returned so the request goes where was checked
+async fn resolve_allowed(url: &Url, is_allowed: fn(IpAddr) -> bool) -> Result<SocketAddr, CalendarError> {
+    let port = url.port_or_known_default().ok_or(CalendarError::InvalidUrl)?;
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.to_string();
+            web::block(move || (domain.as_str(), port).to_socket_addrs()).await.map_err(|_| CalendarError::InvalidUrl)??.collect()
+        },
+        Some(url::Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(url::Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        None => return Err(CalendarError::InvalidUrl)
+    };
+    match addresses.first() {
+        Some(address) if addresses.iter().all(|address| is_allowed(address.ip())) => Ok(*address),
+        Some(_) => Err(CalendarError::BlockedAddress),
+        None => Err(CalendarError::InvalidUrl)
+    }
+}
+
+// redirects are followed by hand so each new location is checked the same way as the first
+async fn download_calendar(url: &str, is_allowed: fn(IpAddr) -> bool) -> Result<String, CalendarError> {
+    let mut url = calendar_url(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let address = resolve_allowed(&url, is_allowed).await?;
+        let mut client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .timeout(std::time::Duration::from_secs(FETCH_TIMEOUT_SECONDS));
+        // pinning the checked address stops a second lookup from getting a different answer
+        if let Some(url::Host::Domain(domain)) = url.host() {
+            client = client.resolve(domain, address);
+        }
+        let mut response = client.build()?.get(url.clone()).send().await?;
+
+        if response.status().is_redirection() {
+            let location = response.headers().get(LOCATION).and_then(|location| location.to_str().ok()).ok_or(CalendarError::InvalidUrl)?;
+            url = calendar_url(url.join(location).map_err(|_| CalendarError::InvalidUrl)?.as_str())?;
+            continue;
+        }
+        response = response.error_for_status()?;
+        if response.content_length().is_some_and(|length| length > MAX_CALENDAR_BYTES as u64) {
+            return Err(CalendarError::TooLarge);
+        }
+        let mut content = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            content.extend_from_slice(&chunk);
+            if content.len() > MAX_CALENDAR_BYTES {
+                return Err(CalendarError::TooLarge);
+            }
+        }
+        return Ok(String::from_utf8_lossy(&content).into_owned());
+    }
+    Err(CalendarError::TooManyRedirects)
+}
+
+async fn calendar_content(calendar: &Calendar) -> Result<String, CalendarError> {
+    match (&calendar.url, &calendar.ics) {
+        (Some(url), _) => download_calendar(url, is_public_address).await,
+        (None, Some(ics)) => Ok(ics.clone()),
+        (None, None) => Err(CalendarError::InvalidSource)
+    }
+}
+
+// what the calendar owner sees in last_error, upstream responses and internal details only go to the server log
+fn refresh_error_message(error: &CalendarError) -> &'static str {
+    match error {
+        CalendarError::InvalidUrl => "the calendar url is not valid",
+        CalendarError::BlockedAddress => "the calendar url points at a private address",
+        CalendarError::TooLarge => "the calendar is too large",
+        CalendarError::TooManyRedirects => "the calendar url redirected too many times",
+        CalendarError::RequestError(_) | CalendarError::IOError(_) => "the calendar could not be downloaded",
+        CalendarError::ParseError(_) => "the calendar could not be read",
+        _ => "the calendar could not be refreshed"
+    }
+}
+
+async fn store_events(calendar: &Calendar, pool: &SqlitePool) -> Result<(), CalendarError> {
+    let content = calendar_content(calendar).await?;
+    let start = Utc::now() - Duration::days(1);
+    let events = parse_calendar(&content, start, start + Duration::days(CALENDAR_WINDOW_DAYS + 1))?;
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query("DELETE FROM calendar_events WHERE calendar_id = $1")
+        .bind(calendar.id)
+        .execute(&mut transaction).await?;
+    for event in events {
+        sqlx::query("INSERT INTO calendar_events (calendar_id, uid, summary, location, start_time, end_time, all_day, show_as) values ($1, $2, $3, $4, $5, $6, $7, $8)")
+            .bind(calendar.id)
+            .bind(event.uid)
+            .bind(event.summary)
+            .bind(event.location)
+            .bind(event.start_time)
+            .bind(event.end_time)
+            .bind(event.all_day)
+            .bind(event.show_as)
+            .execute(&mut transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+// the outcome is stored against the calendar so a broken feed shows up in /api/calendars
+async fn refresh_calendar(calendar: &Calendar, pool: &SqlitePool) -> Result<Calendar, CalendarError> {
+    let result = store_events(calendar, pool).await;
+    if let Err(e) = &result {
+        println!("calendar {} refresh failed - {}", calendar.id, e);
+    }
+    let query = sqlx::query_as::<_, Calendar>("UPDATE calendars SET last_sync_time = $1, last_error = $2 WHERE id = $3 RETURNING *")
+        .bind(now()?)
+        .bind(result.err().map(|e| refresh_error_message(&e)))
+        .bind(calendar.id);
+    Ok(query.fetch_one(pool).await?)
+}
+
+pub async fn refresh_calendars(pool: &SqlitePool) -> Result<(), CalendarError> {
+    let query = sqlx::query_as::<_, Calendar>("SELECT * FROM calendars");
+    let calendars: Vec<Calendar> = query.fetch_all(pool).await?;
+    for calendar in calendars {
+        refresh_calendar(&calendar, pool).await?;
+    }
+    Ok(())
+}
+
+/// Marks people with a personal calendar busy during meetings and away during out of office blocks.
+/// People whose presence comes from Microsoft Graph are skipped, Teams already reflects their calendar.
+pub async fn update_calendar_presence(pool: &SqlitePool) -> Result<(), CalendarError> {
+    // people whose calendar was removed are included so the status it set gets cleared
+    let usernames: Vec<String> = sqlx::query_scalar("SELECT username FROM calendars WHERE username IS NOT NULL UNION SELECT username FROM presence WHERE source = $1 EXCEPT SELECT username FROM presence_sync WHERE enabled = 1")
+        .bind(CALENDAR_SOURCE)
+        .fetch_all(pool).await?;
+    let time = now()?;
+
+    for username in usernames {
+        // out of office wins over a meeting in the same block
+        let show_as: Option<String> = sqlx::query_scalar("SELECT calendar_events.show_as FROM calendar_events JOIN calendars ON calendars.id = calendar_events.calendar_id WHERE calendars.username = $1 AND calendar_events.start_time <= $2 AND calendar_events.end_time > $2 AND calendar_events.show_as != 'free' ORDER BY calendar_events.show_as = 'oof' DESC LIMIT 1")
+            .bind(&username)
+            .bind(time)
+            .fetch_optional(pool).await?;
+        match show_as.as_deref() {
+            Some("oof") => set_synced_presence(&username, "away", CALENDAR_SOURCE, pool).await?,
+            Some(_) => set_synced_presence(&username, "busy", CALENDAR_SOURCE, pool).await?,
+            None => clear_synced_presence(&username, CALENDAR_SOURCE, pool).await?
+        };
+    }
+    Ok(())
+}
+
+pub async fn get_calendars(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, Calendar>("SELECT * FROM calendars ORDER BY name ASC");
+    let mut rows: Vec<Calendar> = query.fetch_all(&data.db_pool).await?;
+
+    // feed urls often carry a private token, only the owner and calendar admins see them
+    let admin = is_calendar_admin(&req);
+    for calendar in rows.iter_mut() {
+        if !admin && calendar.username.as_deref() != Some(auth.username.as_str()) {
+            calendar.url = None;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewCalendar {
+    name: String,
+    url: Option<String>,
+    // the contents of an uploaded .ics file
+    ics: Option<String>,
+    #[serde(default)]
+    team: bool
+}
+
+fn is_calendar_admin(req: &HttpRequest) -> bool {
+    req.extensions().get::<GrantedScopes>().is_some_and(|scopes| scopes.contains(ADMIN_SCOPE))
+}
+
+pub async fn create_calendar(
+    req: HttpRequest,
+    calendar: web::Json<NewCalendar>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if calendar.url.is_some() == calendar.ics.is_some() {
+        return Err(CalendarError::InvalidSource.into());
+    }
+    if calendar.team && !is_calendar_admin(&req) {
+        return Err(CalendarError::Forbidden.into());
+    }
+    // uploads are checked up front, urls are fetched in the background and any error is stored on the calendar
+    if let Some(ics) = &calendar.ics {
+        let start = Utc::now();
+        parse_calendar(ics, start, start)?;
+    }
+    if let Some(url) = &calendar.url {
+        resolve_allowed(&calendar_url(url)?, is_public_address).await?;
+    }
+
+    let query = sqlx::query_as::<_, Calendar>("INSERT INTO calendars (name, username, url, ics, created_time) values ($1, $2, $3, $4, $5) RETURNING *")
+        .bind(&calendar.name)
+        .bind(if calendar.team { None } else { Some(&auth.username) })
+        .bind(&calendar.url)
+        .bind(&calendar.ics)
+        .bind(now()?);
+    let row: Calendar = query.fetch_one(&data.db_pool).await?;
+    if row.url.is_some() {
+        AuditChange::new("calendar", row.id).after(&row).record(&req);
+        let response = HttpResponse::Ok().json(&row);
+        let pool = data.db_pool.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = refresh_calendar(&row, &pool).await {
+                println!("first calendar refresh failed - {}", e);
+            }
+        });
+        return Ok(response);
+    }
+    let row = refresh_calendar(&row, &data.db_pool).await?;
+    AuditChange::new("calendar", row.id).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+pub async fn delete_calendar(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, Calendar>("SELECT * FROM calendars WHERE id = $1").bind(*id);
+    let calendar: Calendar = match query.fetch_optional(&data.db_pool).await? {
+        Some(calendar) => calendar,
+        None => return Err(CalendarError::NotFound.into())
+    };
+    if calendar.username.as_deref() != Some(auth.username.as_str()) && !is_calendar_admin(&req) {
+        return Err(CalendarError::Forbidden.into());
+    }
+
+    let mut transaction = data.db_pool.begin().await?;
+    sqlx::query("DELETE FROM calendar_events WHERE calendar_id = $1")
+        .bind(calendar.id)
+        .execute(&mut transaction).await?;
+    sqlx::query("DELETE FROM calendars WHERE id = $1")
+        .bind(calendar.id)
+        .execute(&mut transaction).await?;
+    transaction.commit().await?;
+    AuditChange::new("calendar", calendar.id).before(&calendar).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[derive(Deserialize)]
+pub struct UpcomingQuery {
+    days: Option<i64>,
+    user: Option<String>
+}
+
+// events still running are included so the widget can show what is on right now
+pub async fn get_upcoming_events(
+    filter: web::Query<UpcomingQuery>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let days = filter.days.unwrap_or(DEFAULT_UPCOMING_DAYS).clamp(1, CALENDAR_WINDOW_DAYS);
+    let time = now()?;
+    let query = sqlx::query_as::<_, UpcomingEvent>("SELECT calendar_events.id, calendar_events.calendar_id, calendars.name AS calendar_name, calendars.username, calendar_events.uid, calendar_events.summary, calendar_events.location, calendar_events.start_time, calendar_events.end_time, calendar_events.all_day, calendar_events.show_as FROM calendar_events JOIN calendars ON calendars.id = calendar_events.calendar_id WHERE calendar_events.end_time > $1 AND calendar_events.start_time < $2 AND ($3 IS NULL OR calendars.username = $3) ORDER BY calendar_events.start_time ASC")
+        .bind(time)
+        .bind(time + (days * 24 * 60 * 60) as u32)
+        .bind(&filter.user);
+    let rows: Vec<UpcomingEvent> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use actix_web::test::{TestRequest, call_and_read_body_json, init_service};
+    use crate::{test_pool, test_state};
+
+    // the mock feed lives on 127.0.0.1, so the tests only let that one address through
+    fn only_localhost(ip: IpAddr) -> bool {
+        ip == IpAddr::from([127, 0, 0, 1])
+    }
+
+    const FEED: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nEND:VCALENDAR\r\n";
+
+    async fn start_mock_feed() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/feed.ics", web::get().to(|| async { HttpResponse::Ok().body(FEED) }))
+                .route("/moved", web::get().to(|| async { HttpResponse::Found().insert_header((LOCATION, "/feed.ics")).finish() }))
+                .route("/metadata", web::get().to(|| async { HttpResponse::Found().insert_header((LOCATION, "http://169.254.169.254/latest/meta-data")).finish() }))
+                .route("/loop", web::get().to(|| async { HttpResponse::Found().insert_header((LOCATION, "/loop")).finish() }))
+                .route("/large.ics", web::get().to(|| async { HttpResponse::Ok().body(vec![b'x'; MAX_CALENDAR_BYTES + 1]) }))
+        }).workers(1).listen(listener).unwrap().run();
+        actix_rt::spawn(server);
+
+        base_url
+    }
+
+    #[test]
+    fn only_public_addresses_are_allowed() {
+        for address in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(!is_public_address(address.parse().unwrap()), "{} should be blocked", address);
+        }
+        for address in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public_address(address.parse().unwrap()), "{} should be allowed", address);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn only_http_urls_are_fetched() {
+        for url in ["file:///etc/passwd", "ftp://example.com/cal.ics", "gopher://example.com", "not a url"] {
+            assert!(matches!(download_calendar(url, only_localhost).await, Err(CalendarError::InvalidUrl)), "{} should be rejected", url);
+        }
+        assert!(matches!(download_calendar("http://127.0.0.1:1/cal.ics", is_public_address).await, Err(CalendarError::BlockedAddress)));
+        assert!(matches!(download_calendar("http://localhost:1/cal.ics", is_public_address).await, Err(CalendarError::BlockedAddress)));
+    }
+
+    #[actix_rt::test]
+    async fn redirects_are_checked_on_every_hop() {
+        let base_url = start_mock_feed().await;
+
+        assert_eq!(download_calendar(&format!("{}/feed.ics", base_url), only_localhost).await.unwrap(), FEED);
+        assert_eq!(download_calendar(&format!("{}/moved", base_url), only_localhost).await.unwrap(), FEED);
+        assert!(matches!(download_calendar(&format!("{}/metadata", base_url), only_localhost).await, Err(CalendarError::BlockedAddress)));
+        assert!(matches!(download_calendar(&format!("{}/loop", base_url), only_localhost).await, Err(CalendarError::TooManyRedirects)));
+        assert!(matches!(download_calendar(&format!("{}/large.ics", base_url), only_localhost).await, Err(CalendarError::TooLarge)));
+    }
+
+    #[actix_rt::test]
+    async fn refresh_stores_a_generic_error() {
+        let pool = test_pool().await;
+        let query = sqlx::query_as::<_, Calendar>("INSERT INTO calendars (name, url, ics, created_time) values ($1, $2, $3, 0) RETURNING *");
+        let private = query.bind("private").bind("http://127.0.0.1:1/cal.ics").bind(None::<String>).fetch_one(&pool).await.unwrap();
+        let query = sqlx::query_as::<_, Calendar>("INSERT INTO calendars (name, url, ics, created_time) values ($1, $2, $3, 0) RETURNING *");
+        let broken = query.bind("broken").bind(None::<String>).bind("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n").fetch_one(&pool).await.unwrap();
+
+        let private = refresh_calendar(&private, &pool).await.unwrap();
+        assert_eq!(private.last_error.as_deref(), Some("the calendar url points at a private address"));
+        let broken = refresh_calendar(&broken, &pool).await.unwrap();
+        assert_eq!(broken.last_error.as_deref(), Some("the calendar could not be read"));
+    }
+
+    #[actix_rt::test]
+    async fn feed_urls_are_only_shown_to_the_owner_and_admins() {
+        let pool = test_pool().await;
+        for (name, username) in [("alice", Some("alice")), ("bob", Some("bob")), ("team", None)] {
+            sqlx::query("INSERT INTO calendars (name, username, url, created_time) values ($1, $2, $3, 0)")
+                .bind(name)
+                .bind(username)
+                .bind(format!("https://example.com/{}.ics?token=secret", name))
+                .execute(&pool).await.unwrap();
+        }
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/calendars", web::get().to(get_calendars))).await;
+        let list = |scopes: &[&str]| {
+            let request = TestRequest::get().uri("/api/calendars").to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: "alice".to_string(), initials: "AL".to_string(), api_key_id: None });
+            request.extensions_mut().insert(GrantedScopes(scopes.iter().map(|scope| scope.to_string()).collect()));
+            request
+        };
+        let urls = |calendars: serde_json::Value| -> Vec<Option<String>> {
+            calendars.as_array().unwrap().iter().map(|calendar| calendar["url"].as_str().map(str::to_string)).collect()
+        };
+
+        let calendars = call_and_read_body_json(&app, list(&["calendar:read"])).await;
+        assert_eq!(urls(calendars), vec![Some("https://example.com/alice.ics?token=secret".to_string()), None, None]);
+        let calendars = call_and_read_body_json(&app, list(&["calendar:read", ADMIN_SCOPE])).await;
+        assert!(urls(calendars).iter().all(Option::is_some));
+    }
+
+    fn ics(events: &[&[&str]]) -> String {
+        let mut lines = vec!["BEGIN:VCALENDAR", "VERSION:2.0"];
+        for event in events {
+            lines.push("BEGIN:VEVENT");
+            lines.extend_from_slice(event);
+            lines.push("END:VEVENT");
+        }
+        lines.push("END:VCALENDAR");
+        lines.join("\r\n")
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    fn starts(events: &[CalendarEvent]) -> Vec<DateTime<Utc>> {
+        events.iter().map(|event| Utc.timestamp_opt(event.start_time as i64, 0).unwrap()).collect()
+    }
+
+    #[test]
+    fn count_still_applies_to_a_later_window() {
+        let content = ics(&[&["UID:standup", "SUMMARY:Standup", "DTSTART:20240101T090000Z", "DURATION:PT15M", "RRULE:FREQ=DAILY;COUNT=5"]]);
+        let events = parse_calendar(&content, utc(2024, 1, 3, 0, 0), utc(2024, 1, 31, 0, 0)).unwrap();
+        assert_eq!(starts(&events), vec![utc(2024, 1, 3, 9, 0), utc(2024, 1, 4, 9, 0), utc(2024, 1, 5, 9, 0)]);
+        assert!(events.iter().all(|event| event.end_time - event.start_time == 15 * 60));
+
+        // an occurrence that started before the window is still shown while it runs
+        let events = parse_calendar(&content, utc(2024, 1, 2, 9, 10), utc(2024, 1, 2, 23, 0)).unwrap();
+        assert_eq!(starts(&events), vec![utc(2024, 1, 2, 9, 0)]);
+    }
+
+    #[test]
+    fn exdates_and_overrides_replace_occurrences() {
+        let content = ics(&[
+            &["UID:review", "SUMMARY:Review", "DTSTART;TZID=Europe/London:20240101T100000", "DTEND;TZID=Europe/London:20240101T110000",
+                "RRULE:FREQ=WEEKLY;BYDAY=MO", "EXDATE;TZID=Europe/London:20240108T100000,20240122T100000"],
+            &["UID:review", "SUMMARY:Review (moved)", "RECURRENCE-ID;TZID=Europe/London:20240115T100000",
+                "DTSTART;TZID=Europe/London:20240116T140000", "DTEND;TZID=Europe/London:20240116T150000"],
+            // cancelling a single occurrence sends an override with the cancelled status
+            &["UID:review", "SUMMARY:Review", "STATUS:CANCELLED", "RECURRENCE-ID;TZID=Europe/London:20240129T100000",
+                "DTSTART;TZID=Europe/London:20240129T100000", "DTEND;TZID=Europe/London:20240129T110000"],
+            &["UID:review-cancelled", "SUMMARY:Cancelled", "STATUS:CANCELLED", "DTSTART:20240102T100000Z"]
+        ]);
+        let events = parse_calendar(&content, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0)).unwrap();
+        assert_eq!(starts(&events), vec![utc(2024, 1, 1, 10, 0), utc(2024, 1, 16, 14, 0)]);
+        assert_eq!(events[1].summary, "Review (moved)");
+    }
+
+    #[test]
+    fn windows_zone_names_are_understood() {
+        for (windows, iana) in WINDOWS_ZONES {
+            assert!(iana.parse::<Tz>().is_ok(), "{} maps to unknown zone {}", windows, iana);
+        }
+
+        let content = ics(&[
+            &["UID:outlook", "SUMMARY:Planning", "DTSTART;TZID=W. Europe Standard Time:20240701T090000",
+                "DTEND;TZID=W. Europe Standard Time:20240701T100000", "RRULE:FREQ=DAILY;COUNT=2",
+                "EXDATE;TZID=W. Europe Standard Time:20240702T090000"],
+            &["UID:quoted", "SUMMARY:Call", "DTSTART;TZID=\"Eastern Standard Time\":20240115T090000", "DURATION:PT1H"]
+        ]);
+        let events = parse_calendar(&content, utc(2024, 1, 1, 0, 0), utc(2024, 12, 1, 0, 0)).unwrap();
+        assert_eq!(starts(&events), vec![utc(2024, 1, 15, 14, 0), utc(2024, 7, 1, 7, 0)]);
+        assert_eq!(events[1].end_time - events[1].start_time, 60 * 60);
+    }
+
+    #[test]
+    fn occurrences_keep_their_local_time_across_dst() {
+        // clocks in New York go forward on 10 March 2024 and back on 3 November 2024
+        let content = ics(&[
+            &["UID:sync", "SUMMARY:Sync", "DTSTART;TZID=America/New_York:20240308T090000", "DURATION:PT30M",
+                "RRULE:FREQ=DAILY;COUNT=3", "EXDATE;TZID=America/New_York:20240309T090000"],
+            &["UID:late", "SUMMARY:Late", "DTSTART;TZID=America/New_York:20241103T013000", "DURATION:PT30M"]
+        ]);
+        let events = parse_calendar(&content, utc(2024, 3, 1, 0, 0), utc(2024, 12, 1, 0, 0)).unwrap();
+        // a local time that happens twice uses the earlier of the two
+        assert_eq!(starts(&events), vec![utc(2024, 3, 8, 14, 0), utc(2024, 3, 10, 13, 0), utc(2024, 11, 3, 5, 30)]);
+    }
+
+    #[test]
+    fn all_day_events_and_bad_rules() {
+        let content = ics(&[
+            &["UID:holiday", "SUMMARY:Holiday", "DTSTART;VALUE=DATE:20240105", "X-MICROSOFT-CDO-BUSYSTATUS:OOF"],
+            &["UID:odd", "SUMMARY:Odd", "DTSTART:20240103T120000Z", "RRULE:FREQ=MINUTELY", "TRANSP:TRANSPARENT"]
+        ]);
+        let events = parse_calendar(&content, utc(2024, 1, 1, 0, 0), utc(2024, 2, 1, 0, 0)).unwrap();
+        assert_eq!(events.len(), 2);
+        // a rule that can't be expanded still shows its first occurrence
+        assert_eq!((events[0].summary.as_str(), events[0].show_as.as_str(), events[0].all_day), ("Odd", "free", false));
+        assert_eq!((events[1].summary.as_str(), events[1].show_as.as_str(), events[1].all_day), ("Holiday", "oof", true));
+        assert_eq!(events[1].end_time - events[1].start_time, 24 * 60 * 60);
+    }
+}
@@ -0,0 +1,373 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use reqwest::Url;
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::audit::AuditChange;
+use crate::users::get_user;
+use crate::presence::{PresenceError, UNKNOWN_STATUS, expire_presence, set_synced_presence};
+
+pub const GRAPH_SOURCE: &str = "graph";
+const DEFAULT_SCOPE: &str = "https://graph.microsoft.com/.default";
+const DEFAULT_POLL_SECONDS: u32 = 60;
+// anything shorter gets throttled by graph, and 0 would run the scheduler in a tight loop
+const MIN_POLL_SECONDS: u32 = 10;
+
+#[derive(Error, Debug)]
+pub enum GraphError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlError(#[from] url::ParseError),
+    #[error(transparent)]
+    PresenceError(#[from] PresenceError),
+    #[error("{0} is not a directory object id or user principal name")]
+    InvalidUserId(String)
+}
+
+/// Settings for syncing presence from Microsoft Graph, read from the environment.
+///
+/// Syncing is enabled when `GRAPH_API_URL` is set, usually `https://graph.microsoft.com/v1.0`
+/// but any server with the same `/communications/presences/{id}` endpoint works. When
+/// `GRAPH_TOKEN_URL` is set an app token is requested with the client credentials grant using
+/// `GRAPH_CLIENT_ID`, `GRAPH_CLIENT_SECRET` and `GRAPH_SCOPE` (default the Graph `.default`
+/// scope), otherwise requests are sent without one. `GRAPH_POLL_SECONDS` sets how often
+/// presence is fetched (default 60, at least 10).
+#[derive(Clone, Debug)]
+pub struct GraphConfig {
+    pub api_url: String,
+    pub token_url: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: String,
+    pub poll_seconds: u32
+}
+
+impl GraphConfig {
+    pub fn from_env() -> Option<GraphConfig> {
+        let api_url = std::env::var("GRAPH_API_URL").ok()?;
+        let token_url = std::env::var("GRAPH_TOKEN_URL").ok();
+        let credential = |name: &str| match token_url {
+            Some(_) => std::env::var(name).unwrap_or_else(|_| panic!("{} environment variable is not set", name)),
+            None => String::new()
+        };
+        Some(GraphConfig {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            client_id: credential("GRAPH_CLIENT_ID"),
+            client_secret: credential("GRAPH_CLIENT_SECRET"),
+            token_url,
+            scope: std::env::var("GRAPH_SCOPE").unwrap_or_else(|_| DEFAULT_SCOPE.to_string()),
+            poll_seconds: std::env::var("GRAPH_POLL_SECONDS").ok().and_then(|value| value.parse().ok()).unwrap_or(DEFAULT_POLL_SECONDS).max(MIN_POLL_SECONDS)
+        })
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct PresenceSync {
+    id: u32,
+    username: String,
+    graph_user_id: String,
+    enabled: bool,
+    last_sync_time: Option<u32>,
+    last_error: Option<String>
+}
+
+pub async fn create_presence_sync_table(pool: &SqlitePool) -> Result<(), GraphError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS presence_sync (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT UNIQUE NOT NULL, graph_user_id TEXT NOT NULL, enabled INTEGER, last_sync_time INTEGER, last_error TEXT)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GraphPresence {
+    availability: String
+}
+
+// graph has more availability values than the dashboard has states
+fn dashboard_status(availability: &str) -> &'static str {
+    match availability {
+        "Available" | "AvailableIdle" => "free",
+        "Busy" | "BusyIdle" | "DoNotDisturb" => "busy",
+        "Away" | "BeRightBack" => "away",
+        _ => UNKNOWN_STATUS
+    }
+}
+
+// graph accepts an object id, which is a GUID, or a user principal name, which looks like an email address
+fn is_valid_graph_user_id(graph_user_id: &str) -> bool {
+    let is_guid = graph_user_id.len() == 36 && graph_user_id.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit()
+    });
+    let is_upn = match graph_user_id.split_once('@') {
+        Some((name, domain)) => !name.is_empty() && !domain.is_empty() && !domain.contains('@')
+            && graph_user_id.chars().all(|c| c.is_ascii_alphanumeric() || "'.-_!#^~@".contains(c)),
+        None => false
+    };
+    is_guid || is_upn
+}
+
+async fn request_access_token(config: &GraphConfig, client: &reqwest::Client) -> Result<Option<String>, GraphError> {
+    let token_url = match &config.token_url {
+        Some(token_url) => token_url,
+        None => return Ok(None)
+    };
+    let form = [
+        ("grant_type", "client_credentials"),
+        ("client_id", config.client_id.as_str()),
+        ("client_secret", config.client_secret.as_str()),
+        ("scope", config.scope.as_str())
+    ];
+    let token: TokenResponse = client.post(token_url)
+        .form(&form)
+        .send().await?
+        .error_for_status()?
+        .json().await?;
+    Ok(Some(token.access_token))
+}
+
+async fn fetch_presence(graph_user_id: &str, token: Option<&str>, config: &GraphConfig, client: &reqwest::Client) -> Result<GraphPresence, GraphError> {
+    // the id goes in as a single path segment so characters like # and / are escaped rather than changing the url
+    let mut url = Url::parse(&config.api_url)?;
+    url.path_segments_mut()
+        .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+        .pop_if_empty()
+        .extend(["communications", "presences", graph_user_id]);
+    let mut request = client.get(url);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    Ok(request.send().await?.error_for_status()?.json().await?)
+}
+
+/// Fetches the presence of every user with syncing enabled and updates their status.
+/// A failure for one user is stored against that user and does not stop the others.
+pub async fn sync_presence(config: &GraphConfig, pool: &SqlitePool) -> Result<(), GraphError> {
+    let query = sqlx::query_as::<_, PresenceSync>("SELECT * FROM presence_sync WHERE enabled = 1");
+    let users: Vec<PresenceSync> = query.fetch_all(pool).await?;
+    if users.is_empty() {
+        return Ok(());
+    }
+
+    // manual statuses that have run out no longer block the synced ones
+    expire_presence(pool).await?;
+    let client = reqwest::Client::new();
+    let token = request_access_token(config, &client).await?;
+
+    for user in users {
+        let result = match fetch_presence(&user.graph_user_id, token.as_deref(), config, &client).await {
+            Ok(presence) => set_synced_presence(&user.username, dashboard_status(&presence.availability), GRAPH_SOURCE, pool).await.map_err(GraphError::from),
+            Err(e) => Err(e)
+        };
+        sqlx::query("UPDATE presence_sync SET last_sync_time = $1, last_error = $2 WHERE id = $3")
+            .bind(now()?)
+            .bind(result.err().map(|e| e.to_string()))
+            .bind(user.id)
+            .execute(pool).await?;
+    }
+    Ok(())
+}
+
+pub async fn get_presence_sync(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, PresenceSync>("SELECT * FROM presence_sync ORDER BY username ASC");
+    let rows: Vec<PresenceSync> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdatePresenceSync {
+    // the user's id or user principal name in the directory graph reads from
+    graph_user_id: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool
+}
+
+pub async fn update_presence_sync(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    update: web::Json<UpdatePresenceSync>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    if !is_valid_graph_user_id(&update.graph_user_id) {
+        return Err(GraphError::InvalidUserId(update.graph_user_id.clone()).into());
+    }
+    let user = get_user(*id, &data.db_pool).await?;
+    let query = sqlx::query_as::<_, PresenceSync>("SELECT * FROM presence_sync WHERE username = $1").bind(&user.username);
+    let before: Option<PresenceSync> = query.fetch_optional(&data.db_pool).await?;
+
+    let query = sqlx::query_as::<_, PresenceSync>("INSERT INTO presence_sync (username, graph_user_id, enabled) values ($1, $2, $3) ON CONFLICT(username) DO UPDATE SET graph_user_id = $2, enabled = $3, last_error = NULL RETURNING *")
+        .bind(&user.username)
+        .bind(&update.graph_user_id)
+        .bind(update.enabled);
+    let row: PresenceSync = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("presence_sync", user.id).before(&before).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::test_pool;
+
+    const TOKEN: &str = "graph-token";
+
+    // a stand-in for the token and presence endpoints, holding the availability of each known user id
+    async fn start_mock_graph(availability: &[(&str, &str)], token_fails: bool) -> GraphConfig {
+        let availability: HashMap<String, String> = availability.iter().map(|(id, value)| (id.to_string(), value.to_string())).collect();
+        let availability = web::Data::new(availability);
+        let token_fails = web::Data::new(AtomicBool::new(token_fails));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(availability.clone())
+                .app_data(token_fails.clone())
+                .route("/token", web::post().to(|fails: web::Data<AtomicBool>| async move {
+                    match fails.load(Ordering::SeqCst) {
+                        true => HttpResponse::InternalServerError().finish(),
+                        false => HttpResponse::Ok().json(serde_json::json!({ "access_token": TOKEN }))
+                    }
+                }))
+                .route("/v1.0/communications/presences/{id}", web::get().to(|req: HttpRequest, id: web::Path<String>, availability: web::Data<HashMap<String, String>>| async move {
+                    let authorized = req.headers().get("Authorization").and_then(|value| value.to_str().ok()) == Some(&format!("Bearer {}", TOKEN));
+                    match availability.get(id.as_str()) {
+                        _ if !authorized => HttpResponse::Unauthorized().finish(),
+                        Some(value) => HttpResponse::Ok().json(serde_json::json!({ "id": id.as_str(), "availability": value })),
+                        None => HttpResponse::NotFound().finish()
+                    }
+                }))
+        }).workers(1).listen(listener).unwrap().run();
+        actix_rt::spawn(server);
+
+        GraphConfig {
+            api_url: format!("{}/v1.0", base_url),
+            token_url: Some(format!("{}/token", base_url)),
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            scope: DEFAULT_SCOPE.to_string(),
+            poll_seconds: DEFAULT_POLL_SECONDS
+        }
+    }
+
+    async fn enable_sync(username: &str, graph_user_id: &str, pool: &SqlitePool) {
+        sqlx::query("INSERT INTO presence_sync (username, graph_user_id, enabled) values ($1, $2, $3)")
+            .bind(username)
+            .bind(graph_user_id)
+            .bind(true)
+            .execute(pool).await.unwrap();
+    }
+
+    async fn status(username: &str, pool: &SqlitePool) -> Option<String> {
+        sqlx::query_scalar("SELECT status FROM presence WHERE username = $1").bind(username).fetch_optional(pool).await.unwrap()
+    }
+
+    async fn last_error(username: &str, pool: &SqlitePool) -> Option<String> {
+        sqlx::query_scalar("SELECT last_error FROM presence_sync WHERE username = $1").bind(username).fetch_one(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn availability_maps_to_dashboard_status() {
+        let users = [
+            ("sam", "4f1c2b3a-0d9e-4c7b-8a6f-123456789abc", "Available", "free"),
+            ("lee", "lee@example.com", "DoNotDisturb", "busy"),
+            ("kim", "kim@example.com", "BeRightBack", "away"),
+            ("max", "max@example.com", "PresenceUnknown", "unknown"),
+            // escaped in the url so the # reaches graph instead of starting a fragment
+            ("pat", "pat#ext@example.com", "Busy", "busy")
+        ];
+        let availability: Vec<(&str, &str)> = users.iter().map(|(_, id, value, _)| (*id, *value)).collect();
+        let config = start_mock_graph(&availability, false).await;
+        let pool = test_pool().await;
+        for (username, id, _, _) in users {
+            enable_sync(username, id, &pool).await;
+        }
+
+        sync_presence(&config, &pool).await.unwrap();
+        for (username, _, _, expected) in users {
+            assert_eq!(status(username, &pool).await.as_deref(), Some(expected), "{}", username);
+            assert_eq!(last_error(username, &pool).await, None);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn token_failure_stops_the_sync() {
+        let config = start_mock_graph(&[("sam@example.com", "Available")], true).await;
+        let pool = test_pool().await;
+        enable_sync("sam", "sam@example.com", &pool).await;
+
+        assert!(matches!(sync_presence(&config, &pool).await, Err(GraphError::RequestError(_))));
+        assert_eq!(status("sam", &pool).await, None);
+    }
+
+    #[actix_rt::test]
+    async fn unknown_user_is_recorded_without_stopping_others() {
+        let config = start_mock_graph(&[("sam@example.com", "Away")], false).await;
+        let pool = test_pool().await;
+        enable_sync("gone", "gone@example.com", &pool).await;
+        enable_sync("sam", "sam@example.com", &pool).await;
+
+        sync_presence(&config, &pool).await.unwrap();
+        assert!(last_error("gone", &pool).await.is_some_and(|error| error.contains("404")));
+        assert_eq!(status("gone", &pool).await, None);
+        assert_eq!(status("sam", &pool).await.as_deref(), Some("away"));
+    }
+
+    #[actix_rt::test]
+    async fn manual_presence_overrides_synced_until_it_expires() {
+        let config = start_mock_graph(&[("sam@example.com", "Busy")], false).await;
+        let pool = test_pool().await;
+        enable_sync("sam", "sam@example.com", &pool).await;
+        let time = now().unwrap();
+        sqlx::query("INSERT INTO presence (username, status, set_time, expires_time, revert_to, source) values ($1, $2, $3, $4, $5, $6)")
+            .bind("sam")
+            .bind("free")
+            .bind(time)
+            .bind(time + 3600)
+            .bind(UNKNOWN_STATUS)
+            .bind("manual")
+            .execute(&pool).await.unwrap();
+
+        sync_presence(&config, &pool).await.unwrap();
+        assert_eq!(status("sam", &pool).await.as_deref(), Some("free"));
+
+        sqlx::query("UPDATE presence SET expires_time = $1 WHERE username = $2")
+            .bind(time - 1)
+            .bind("sam")
+            .execute(&pool).await.unwrap();
+        sync_presence(&config, &pool).await.unwrap();
+        assert_eq!(status("sam", &pool).await.as_deref(), Some("busy"));
+    }
+
+    #[test]
+    fn graph_user_ids_are_guids_or_principal_names() {
+        assert!(is_valid_graph_user_id("4f1c2b3a-0d9e-4c7b-8a6f-123456789abc"));
+        assert!(is_valid_graph_user_id("o'brien.sam@example.com"));
+        assert!(!is_valid_graph_user_id("../../users"));
+        assert!(!is_valid_graph_user_id("sam@example.com/../../users"));
+        assert!(!is_valid_graph_user_id("sam@example.com?$select=id"));
+        assert!(!is_valid_graph_user_id("@example.com"));
+        assert!(!is_valid_graph_user_id("4f1c2b3a-0d9e-4c7b-8a6f-123456789abz"));
+    }
+}
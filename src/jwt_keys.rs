@@ -0,0 +1,137 @@
+use hmac::{Hmac, Mac};
+use jwt::{AlgorithmType, Header, SignWithKey, Token, VerifyWithKey, Unverified};
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+
+/// HMAC keys used to sign and verify tokens, looked up by the `kid` header.
+///
+/// New tokens are always signed with `JWT_KEY` under the id `JWT_KEY_ID` (default `1`).
+/// During a rotation the old secrets stay verifiable by listing them in
+/// `JWT_PREVIOUS_KEYS` as comma separated `kid:secret` pairs. Login tokens expire within
+/// the hour but api keys never do, so a previous key has to stay listed until every api
+/// key signed with it has been revoked and reissued.
+#[derive(Clone)]
+pub struct JwtKeys {
+    current: String,
+    keys: BTreeMap<String, Hmac<Sha256>>
+}
+
+impl JwtKeys {
+    pub fn new(current_id: &str, current_secret: &str) -> JwtKeys {
+        let mut keys = BTreeMap::new();
+        keys.insert(current_id.to_string(), Hmac::<Sha256>::new_from_slice(current_secret.as_bytes()).expect("HMAC can take key of any size"));
+        JwtKeys { current: current_id.to_string(), keys }
+    }
+
+    pub fn from_env() -> JwtKeys {
+        let jwt_key_string: String = std::env::var("JWT_KEY").expect("JWT_KEY environment variable is not set");
+        let jwt_key_id: String = std::env::var("JWT_KEY_ID").unwrap_or_else(|_| "1".to_string());
+        let mut keys = JwtKeys::new(&jwt_key_id, &jwt_key_string);
+
+        if let Ok(previous) = std::env::var("JWT_PREVIOUS_KEYS") {
+            for pair in previous.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let (kid, secret) = pair.trim().split_once(':').expect("JWT_PREVIOUS_KEYS entries must be kid:secret");
+                keys.add_verification_key(kid, secret);
+            }
+        }
+        keys
+    }
+
+    pub fn add_verification_key(&mut self, kid: &str, secret: &str) {
+        if kid == self.current {
+            panic!("previous JWT key id {} is the same as the current key id", kid);
+        }
+        self.keys.insert(kid.to_string(), Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size"));
+    }
+
+    pub fn sign<C: Serialize>(&self, claims: C) -> Result<String, jwt::Error> {
+        let header = Header {
+            algorithm: AlgorithmType::Hs256,
+            key_id: Some(self.current.clone()),
+            ..Default::default()
+        };
+        let token = Token::new(header, claims).sign_with_key(&self.keys[&self.current])?;
+        Ok(token.as_str().to_string())
+    }
+
+    /// Verifies the signature and returns the claims, without checking any of them.
+    pub fn verify<C: DeserializeOwned>(&self, token: &str) -> Result<C, jwt::Error> {
+        let unverified: Token<Header, C, Unverified> = Token::parse_unverified(token)?;
+        match unverified.header().key_id.clone() {
+            Some(kid) => {
+                let key = self.keys.get(&kid).ok_or(jwt::Error::NoKeyWithKeyId(kid))?;
+                let (_, claims): (Header, C) = unverified.verify_with_key(key)?.into();
+                Ok(claims)
+            },
+            // tokens from before key ids were added can only have been signed by one of the configured keys
+            None => {
+                for key in self.keys.values() {
+                    if let Ok(claims) = VerifyWithKey::<C>::verify_with_key(token, key) {
+                        return Ok(claims);
+                    }
+                }
+                Err(jwt::Error::InvalidSignature)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> BTreeMap<String, String> {
+        BTreeMap::from([("sub".to_string(), "jordan".to_string())])
+    }
+
+    fn kid(token: &str) -> Option<String> {
+        let unverified: Token<Header, BTreeMap<String, String>, Unverified> = Token::parse_unverified(token).unwrap();
+        unverified.header().key_id.clone()
+    }
+
+    #[test]
+    fn tokens_are_signed_with_the_current_key() {
+        let mut keys = JwtKeys::new("2", "new secret");
+        keys.add_verification_key("1", "old secret");
+        let token = keys.sign(claims()).unwrap();
+        assert_eq!(kid(&token).as_deref(), Some("2"));
+        assert_eq!(keys.verify::<BTreeMap<String, String>>(&token).unwrap(), claims());
+
+        // a token from before the rotation still verifies against the previous key
+        let old_token = JwtKeys::new("1", "old secret").sign(claims()).unwrap();
+        assert_eq!(keys.verify::<BTreeMap<String, String>>(&old_token).unwrap(), claims());
+        assert!(JwtKeys::new("2", "new secret").verify::<BTreeMap<String, String>>(&old_token).is_err());
+    }
+
+    #[test]
+    fn the_kid_picks_the_key() {
+        let keys = JwtKeys::new("1", "secret");
+        let unknown = JwtKeys::new("9", "secret").sign(claims()).unwrap();
+        assert!(matches!(keys.verify::<BTreeMap<String, String>>(&unknown), Err(jwt::Error::NoKeyWithKeyId(kid)) if kid == "9"));
+
+        // the same kid with a different secret is a forgery
+        let forged = JwtKeys::new("1", "other secret").sign(claims()).unwrap();
+        assert!(keys.verify::<BTreeMap<String, String>>(&forged).is_err());
+    }
+
+    #[test]
+    fn tokens_without_a_kid_try_every_key() {
+        let mut keys = JwtKeys::new("2", "new secret");
+        keys.add_verification_key("1", "old secret");
+        let key = Hmac::<Sha256>::new_from_slice(b"old secret").unwrap();
+        let legacy = claims().sign_with_key(&key).unwrap();
+        assert_eq!(kid(&legacy), None);
+        assert_eq!(keys.verify::<BTreeMap<String, String>>(&legacy).unwrap(), claims());
+
+        let key = Hmac::<Sha256>::new_from_slice(b"unknown secret").unwrap();
+        let legacy = claims().sign_with_key(&key).unwrap();
+        assert!(matches!(keys.verify::<BTreeMap<String, String>>(&legacy), Err(jwt::Error::InvalidSignature)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn previous_keys_cannot_reuse_the_current_kid() {
+        JwtKeys::new("1", "secret").add_verification_key("1", "other secret");
+    }
+}
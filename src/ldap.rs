@@ -0,0 +1,379 @@
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, LdapError as Ldap3Error, Scope, SearchEntry, ldap_escape};
+use sqlx::{SqlitePool};
+use std::time::Duration;
+use thiserror::Error;
+use crate::users::{User, UserError, create_external_user, get_user_by_username};
+use crate::roles::{RoleError, ADMIN_ROLE, DEFAULT_ROLE, set_role};
+
+// result code returned by a bind with the wrong password
+const INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Error, Debug)]
+pub enum LdapError {
+    #[error(transparent)]
+    DirectoryError(#[from] Ldap3Error),
+    #[error(transparent)]
+    UserError(#[from] UserError),
+    #[error(transparent)]
+    RoleError(#[from] RoleError),
+    #[error("the directory rejected the LDAP_BIND_DN credentials")]
+    ServiceBindRejected
+}
+
+/// Settings for checking logins against an LDAP or Active Directory server.
+///
+/// Enabled with `AUTH_BACKEND=ldap`. The user entry is found by searching `LDAP_BASE_DN` with
+/// `LDAP_USER_FILTER` (default `(uid={username})`, use `(sAMAccountName={username})` for AD),
+/// binding first as `LDAP_BIND_DN` / `LDAP_BIND_PASSWORD` when the directory does not allow
+/// anonymous searches. The password is then checked by binding as the entry that was found.
+/// Members of `LDAP_ADMIN_GROUP` get the admin role, through either the user's `memberOf`
+/// attribute or the group's `member` / `uniqueMember` attribute.
+#[derive(Clone, Debug)]
+pub struct LdapConfig {
+    pub url: String,
+    pub base_dn: String,
+    pub user_filter: String,
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub admin_group: Option<String>,
+    pub initials_attribute: String
+}
+
+impl LdapConfig {
+    pub fn from_env() -> Option<LdapConfig> {
+        match std::env::var("AUTH_BACKEND").as_deref() {
+            Ok("ldap") => {},
+            Ok("local") | Err(_) => return None,
+            Ok(backend) => panic!("unknown AUTH_BACKEND {}, expected local or ldap", backend)
+        }
+        Some(LdapConfig {
+            url: std::env::var("LDAP_URL").expect("LDAP_URL environment variable is not set"),
+            base_dn: std::env::var("LDAP_BASE_DN").expect("LDAP_BASE_DN environment variable is not set"),
+            user_filter: std::env::var("LDAP_USER_FILTER").unwrap_or_else(|_| "(uid={username})".to_string()),
+            bind_dn: std::env::var("LDAP_BIND_DN").ok(),
+            bind_password: std::env::var("LDAP_BIND_PASSWORD").ok(),
+            admin_group: std::env::var("LDAP_ADMIN_GROUP").ok(),
+            initials_attribute: std::env::var("LDAP_INITIALS_ATTRIBUTE").unwrap_or_else(|_| "initials".to_string())
+        })
+    }
+}
+
+/// A directory entry whose password has been checked.
+#[derive(Debug)]
+pub struct LdapUser {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub initials: Option<String>,
+    pub is_admin: bool
+}
+
+// the directory operations a login needs, separate from ldap3 so the login logic can run against a fake directory
+pub(crate) trait Directory {
+    // false when the directory rejects the credentials
+    async fn bind(&mut self, dn: &str, password: &str) -> Result<bool, LdapError>;
+    async fn search(&mut self, base: &str, scope: Scope, filter: &str, attributes: Vec<&str>) -> Result<Vec<SearchEntry>, LdapError>;
+    async fn unbind(&mut self) -> Result<(), LdapError>;
+}
+
+struct LdapDirectory {
+    ldap: Ldap
+}
+
+impl LdapDirectory {
+    async fn connect(config: &LdapConfig) -> Result<LdapDirectory, LdapError> {
+        let settings = LdapConnSettings::new().set_conn_timeout(Duration::from_secs(5));
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+        ldap3::drive!(conn);
+        Ok(LdapDirectory { ldap })
+    }
+}
+
+impl Directory for LdapDirectory {
+    async fn bind(&mut self, dn: &str, password: &str) -> Result<bool, LdapError> {
+        let result = self.ldap.simple_bind(dn, password).await?;
+        if result.rc == INVALID_CREDENTIALS {
+            return Ok(false);
+        }
+        result.success()?;
+        Ok(true)
+    }
+
+    async fn search(&mut self, base: &str, scope: Scope, filter: &str, attributes: Vec<&str>) -> Result<Vec<SearchEntry>, LdapError> {
+        let (entries, _) = self.ldap.search(base, scope, filter, attributes).await?.success()?;
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    async fn unbind(&mut self) -> Result<(), LdapError> {
+        Ok(self.ldap.unbind().await?)
+    }
+}
+
+fn first_attribute(entry: &SearchEntry, name: &str) -> Option<String> {
+    entry.attrs.get(name).and_then(|values| values.first()).cloned()
+}
+
+/// Checks the credentials against the directory, returning `None` when the user does not
+/// exist there or the password is wrong.
+pub(crate) async fn ldap_authenticate<D: Directory>(directory: &mut D, username: &str, password: &str, config: &LdapConfig) -> Result<Option<LdapUser>, LdapError> {
+    if let (Some(bind_dn), Some(bind_password)) = (&config.bind_dn, &config.bind_password) {
+        if !directory.bind(bind_dn, bind_password).await? {
+            return Err(LdapError::ServiceBindRejected);
+        }
+    }
+
+    let filter = config.user_filter.replace("{username}", &ldap_escape(username));
+    let attributes = vec!["cn", "displayName", "memberOf", config.initials_attribute.as_str()];
+    let entries = directory.search(&config.base_dn, Scope::Subtree, &filter, attributes).await?;
+    let entry = match entries.len() {
+        1 => entries.into_iter().next().expect("one entry"),
+        _ => {
+            directory.unbind().await?;
+            return Ok(None);
+        }
+    };
+
+    if !directory.bind(&entry.dn, password).await? {
+        directory.unbind().await?;
+        return Ok(None);
+    }
+
+    let is_admin = match &config.admin_group {
+        Some(group) => {
+            let member_of = entry.attrs.get("memberOf").map(|groups| groups.iter().any(|dn| dn.eq_ignore_ascii_case(group))).unwrap_or(false);
+            if member_of {
+                true
+            } else {
+                let dn = ldap_escape(&entry.dn);
+                let filter = format!("(|(member={})(uniqueMember={}))", dn, dn);
+                !directory.search(group, Scope::Base, &filter, vec!["dn"]).await?.is_empty()
+            }
+        },
+        None => false
+    };
+
+    let user = LdapUser {
+        username: username.to_string(),
+        display_name: first_attribute(&entry, "displayName").or_else(|| first_attribute(&entry, "cn")),
+        initials: first_attribute(&entry, &config.initials_attribute),
+        is_admin
+    };
+    directory.unbind().await?;
+
+    Ok(Some(user))
+}
+
+/// Authenticates against the directory and creates or updates the matching local user,
+/// keeping the admin role in line with the admin group.
+pub async fn ldap_login(username: &str, password: &str, config: &LdapConfig, pool: &SqlitePool) -> Result<Option<User>, LdapError> {
+    // an empty password is an unauthenticated bind which most servers accept
+    if username.is_empty() || password.is_empty() {
+        return Ok(None);
+    }
+
+    let mut directory = LdapDirectory::connect(config).await?;
+    directory_login(&mut directory, username, password, config, pool).await
+}
+
+async fn directory_login<D: Directory>(directory: &mut D, username: &str, password: &str, config: &LdapConfig, pool: &SqlitePool) -> Result<Option<User>, LdapError> {
+    let ldap_user = match ldap_authenticate(directory, username, password, config).await? {
+        Some(ldap_user) => ldap_user,
+        None => return Ok(None)
+    };
+
+    // admin group members get the admin role, anyone who has left the group drops back to the default role
+    let group_role = match config.admin_group {
+        Some(_) if ldap_user.is_admin => Some(ADMIN_ROLE),
+        Some(_) => Some(DEFAULT_ROLE),
+        None => None
+    };
+
+    match get_user_by_username(&ldap_user.username, pool).await {
+        Ok(user) => match group_role {
+            Some(role) if (user.role == ADMIN_ROLE) != (role == ADMIN_ROLE) => match set_role(&user.username, role, pool).await {
+                Ok(user) => Ok(Some(user)),
+                // the last admin keeps the role rather than leave nobody able to manage users
+                Err(RoleError::UserError(UserError::LastAdmin)) => Ok(Some(user)),
+                Err(e) => Err(e.into())
+            },
+            _ => Ok(Some(user))
+        },
+        Err(UserError::NotFound) => {
+            let role = group_role.unwrap_or(DEFAULT_ROLE);
+            let user = create_external_user(&ldap_user.username, ldap_user.initials.as_deref(), ldap_user.display_name.as_deref(), role, pool).await?;
+            Ok(Some(user))
+        },
+        Err(e) => Err(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, web, http::StatusCode, test};
+    use std::collections::HashMap;
+    use crate::AppState;
+    use crate::users::{create_user, login};
+    use crate::{test_pool, test_state};
+
+    const ADMIN_GROUP: &str = "cn=admins,ou=groups,dc=example,dc=com";
+
+    struct FakeUser {
+        uid: &'static str,
+        password: &'static str,
+        attrs: Vec<(&'static str, &'static str)>
+    }
+
+    #[derive(Default)]
+    struct FakeDirectory {
+        users: Vec<FakeUser>,
+        // member dns of the admin group entry
+        admin_members: Vec<String>,
+        service_password: Option<&'static str>
+    }
+
+    fn user_dn(uid: &str) -> String {
+        format!("uid={},ou=people,dc=example,dc=com", uid)
+    }
+
+    impl Directory for FakeDirectory {
+        async fn bind(&mut self, dn: &str, password: &str) -> Result<bool, LdapError> {
+            if dn == "cn=service,dc=example,dc=com" {
+                return Ok(self.service_password == Some(password));
+            }
+            Ok(self.users.iter().any(|user| user_dn(user.uid) == dn && user.password == password))
+        }
+
+        async fn search(&mut self, base: &str, scope: Scope, filter: &str, _attributes: Vec<&str>) -> Result<Vec<SearchEntry>, LdapError> {
+            if scope == Scope::Base {
+                let found = base == ADMIN_GROUP && self.admin_members.iter().any(|dn| filter.contains(&format!("(member={})", dn)));
+                return Ok(match found {
+                    true => vec![SearchEntry { dn: ADMIN_GROUP.to_string(), attrs: HashMap::new(), bin_attrs: HashMap::new() }],
+                    false => vec![]
+                });
+            }
+            Ok(self.users.iter()
+                .filter(|user| filter == format!("(uid={})", user.uid))
+                .map(|user| {
+                    let mut attrs: HashMap<String, Vec<String>> = HashMap::new();
+                    for (name, value) in &user.attrs {
+                        attrs.entry(name.to_string()).or_default().push(value.to_string());
+                    }
+                    SearchEntry { dn: user_dn(user.uid), attrs, bin_attrs: HashMap::new() }
+                })
+                .collect())
+        }
+
+        async fn unbind(&mut self) -> Result<(), LdapError> {
+            Ok(())
+        }
+    }
+
+    fn config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            base_dn: "dc=example,dc=com".to_string(),
+            user_filter: "(uid={username})".to_string(),
+            bind_dn: None,
+            bind_password: None,
+            admin_group: Some(ADMIN_GROUP.to_string()),
+            initials_attribute: "initials".to_string()
+        }
+    }
+
+    fn directory() -> FakeDirectory {
+        FakeDirectory {
+            users: vec![
+                FakeUser { uid: "sam", password: "directory-pass", attrs: vec![("displayName", "Sam Smith"), ("memberOf", ADMIN_GROUP)] },
+                FakeUser { uid: "lee", password: "directory-pass", attrs: vec![("cn", "Lee Jones"), ("initials", "lj")] }
+            ],
+            ..FakeDirectory::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn wrong_password_is_rejected() {
+        let result = ldap_authenticate(&mut directory(), "sam", "wrong", &config("ldap://fake")).await.unwrap();
+        assert!(result.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn rejected_service_bind_is_an_error() {
+        let mut directory = FakeDirectory { service_password: Some("service-pass"), ..directory() };
+        let mut config = config("ldap://fake");
+        config.bind_dn = Some("cn=service,dc=example,dc=com".to_string());
+        config.bind_password = Some("wrong".to_string());
+        let result = ldap_authenticate(&mut directory, "sam", "directory-pass", &config).await;
+        assert!(matches!(result, Err(LdapError::ServiceBindRejected)));
+
+        config.bind_password = Some("service-pass".to_string());
+        assert!(ldap_authenticate(&mut directory, "sam", "directory-pass", &config).await.unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn unknown_user_is_not_found() {
+        let pool = test_pool().await;
+        let user = directory_login(&mut directory(), "nobody", "directory-pass", &config("ldap://fake"), &pool).await.unwrap();
+        assert!(user.is_none());
+        assert_eq!(sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn taken_directory_initials_get_a_number() {
+        let pool = test_pool().await;
+        create_user("lucy", "LJ", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+        let lee = directory_login(&mut directory(), "lee", "directory-pass", &config("ldap://fake"), &pool).await.unwrap().unwrap();
+        assert_eq!(lee.initials, "LJ2");
+    }
+
+    #[actix_rt::test]
+    async fn admin_group_membership_sets_role() {
+        let pool = test_pool().await;
+        create_user("jordan", "JG", "pass123", ADMIN_ROLE, &pool).await.unwrap();
+        let config = config("ldap://fake");
+
+        let sam = directory_login(&mut directory(), "sam", "directory-pass", &config, &pool).await.unwrap().unwrap();
+        assert_eq!((sam.role.as_str(), sam.initials.as_str()), (ADMIN_ROLE, "SS"));
+        let lee = directory_login(&mut directory(), "lee", "directory-pass", &config, &pool).await.unwrap().unwrap();
+        assert_eq!((lee.role.as_str(), lee.initials.as_str()), (DEFAULT_ROLE, "LJ"));
+
+        // membership listed on the group entry counts as well as memberOf
+        let mut promoted = FakeDirectory { admin_members: vec![user_dn("lee")], ..directory() };
+        let lee = directory_login(&mut promoted, "lee", "directory-pass", &config, &pool).await.unwrap().unwrap();
+        assert_eq!(lee.role, ADMIN_ROLE);
+
+        // leaving the group drops back to the default role at the next login
+        let mut demoted = directory();
+        demoted.users[0].attrs.retain(|(name, _)| *name != "memberOf");
+        let sam = directory_login(&mut demoted, "sam", "directory-pass", &config, &pool).await.unwrap().unwrap();
+        assert_eq!(sam.role, DEFAULT_ROLE);
+    }
+
+    #[actix_rt::test]
+    async fn last_admin_keeps_role_after_leaving_group() {
+        let pool = test_pool().await;
+        let config = config("ldap://fake");
+        directory_login(&mut directory(), "sam", "directory-pass", &config, &pool).await.unwrap().unwrap();
+
+        let mut demoted = directory();
+        demoted.users[0].attrs.retain(|(name, _)| *name != "memberOf");
+        let sam = directory_login(&mut demoted, "sam", "directory-pass", &config, &pool).await.unwrap().unwrap();
+        assert_eq!(sam.role, ADMIN_ROLE);
+    }
+
+    #[actix_rt::test]
+    async fn unreachable_directory_falls_back_to_local_users() {
+        let pool = test_pool().await;
+        create_user("jordan", "JG", "pass123", ADMIN_ROLE, &pool).await.unwrap();
+        let state = web::Data::new(AppState {
+            // nothing listens on port 1 so the connection is refused straight away
+            ldap: Some(config("ldap://127.0.0.1:1")),
+            ..test_state(&pool)
+        });
+        let app = test::init_service(App::new().app_data(state).route("/api/login", web::post().to(login))).await;
+
+        let request = test::TestRequest::post().uri("/api/login").set_json(serde_json::json!({ "username": "jordan", "password": "pass123" })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+        let request = test::TestRequest::post().uri("/api/login").set_json(serde_json::json!({ "username": "jordan", "password": "wrong" })).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,21 +1,67 @@
-use crate::reminders::ReminderError;
-use crate::users::UserError;
-use hmac::Hmac;
+use crate::reminders::{ReminderError, create_reminder_table};
+use crate::reminder_templates::{ReminderTemplateError, create_reminder_template_table};
+use crate::reminder_comments::{ReminderCommentError, create_reminder_comment_table};
+use crate::notifications::{NotificationError, create_notification_table};
+use crate::users::{UserError, create_user_table};
+use crate::api_keys::{ApiKeyError, create_api_key_table};
+use crate::refresh_tokens::{RefreshTokenError, create_refresh_token_table};
+use crate::sessions::{SessionError, create_session_table};
+use crate::passwords::{PasswordError, create_password_reset_table};
+use crate::jwt_keys::JwtKeys;
+use crate::oidc::{OidcError, OidcConfig, create_oidc_tables};
+use crate::ldap::LdapConfig;
+use crate::totp::{TotpError, create_totp_tables};
+use crate::roles::{RoleError, create_role_permission_table};
+use crate::audit::{AuditError, create_audit_log_table};
+use crate::login_throttle::{LoginThrottleError, LoginThrottleConfig, create_login_attempt_table};
+use crate::temperatures::{TemperatureError, create_temperature_table};
+use crate::alerts::{AlertError, create_alert_tables};
+use crate::presence::{PresenceError, create_presence_table};
+use crate::graph::{GraphError, create_presence_sync_table};
+use crate::calendar::{CalendarError, create_calendar_tables};
+use crate::rss::{RSSError, create_rss_feed_table, create_rss_feed_item_table};
+use crate::ping::{PingError, create_ping_table};
+use rand::RngCore;
 use serde::Serialize;
-use sha2::Sha256;
+use sha2::{Sha256, Digest};
 use sqlx::{SqlitePool};
 use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
 use thiserror::Error;
 
 pub mod users;
+pub mod jwt_keys;
+pub mod scopes;
+pub mod roles;
+pub mod audit;
+pub mod api_keys;
+pub mod refresh_tokens;
+pub mod sessions;
+pub mod passwords;
+pub mod login_throttle;
+pub mod oidc;
+pub mod ldap;
+pub mod totp;
+pub mod presence;
+pub mod graph;
+pub mod recurrence;
+pub mod calendar;
 pub mod reminders;
+pub mod reminder_templates;
+pub mod reminder_comments;
+pub mod notifications;
 pub mod temperatures;
+pub mod alerts;
 pub mod rss;
 pub mod ping;
 
+pub const DB_URL: &str = "sqlite://data.db";
+
 pub struct AppState {
     pub db_pool: SqlitePool,
-    pub jwt_key: Hmac<Sha256>,
+    pub jwt_keys: JwtKeys,
+    pub login_throttle: LoginThrottleConfig,
+    pub oidc: Option<OidcConfig>,
+    pub ldap: Option<LdapConfig>,
 }
 
 #[derive(Error, Debug)]
@@ -25,8 +71,46 @@ pub enum AppError {
     #[error(transparent)]
     ReminderError(#[from] ReminderError),
     #[error(transparent)]
+    ReminderTemplateError(#[from] ReminderTemplateError),
+    #[error(transparent)]
+    ReminderCommentError(#[from] ReminderCommentError),
+    #[error(transparent)]
+    NotificationError(#[from] NotificationError),
+    #[error(transparent)]
+    PresenceError(#[from] PresenceError),
+    #[error(transparent)]
+    GraphError(#[from] GraphError),
+    #[error(transparent)]
+    CalendarError(#[from] CalendarError),
+    #[error(transparent)]
     UserError(#[from] UserError),
     #[error(transparent)]
+    RoleError(#[from] RoleError),
+    #[error(transparent)]
+    AuditError(#[from] AuditError),
+    #[error(transparent)]
+    ApiKeyError(#[from] ApiKeyError),
+    #[error(transparent)]
+    RefreshTokenError(#[from] RefreshTokenError),
+    #[error(transparent)]
+    SessionError(#[from] SessionError),
+    #[error(transparent)]
+    PasswordError(#[from] PasswordError),
+    #[error(transparent)]
+    LoginThrottleError(#[from] LoginThrottleError),
+    #[error(transparent)]
+    OidcError(#[from] OidcError),
+    #[error(transparent)]
+    TotpError(#[from] TotpError),
+    #[error(transparent)]
+    TemperatureError(#[from] TemperatureError),
+    #[error(transparent)]
+    AlertError(#[from] AlertError),
+    #[error(transparent)]
+    RSSError(#[from] RSSError),
+    #[error(transparent)]
+    PingError(#[from] PingError),
+    #[error(transparent)]
     JSONError(#[from] serde_json::Error),
     #[error(transparent)]
     IOError(#[from] std::io::Error),
@@ -34,6 +118,85 @@ pub enum AppError {
     TimeError(#[from] std::time::SystemTimeError)
 }
 
+// current unix time in seconds, which is how every table stores its times
+pub(crate) fn now() -> Result<u32, std::time::SystemTimeError> {
+    Ok(std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH)?.as_secs() as u32)
+}
+
+pub(crate) fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+// random 256 bit value encoded as hex, used for opaque tokens handed to clients
+pub(crate) fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// tables are created with CREATE TABLE IF NOT EXISTS so columns added later have to be added to existing databases
+// returns true when the column was added
+pub async fn add_column_if_missing(pool: &SqlitePool, table: &str, column: &str, definition: &str) -> Result<bool, sqlx::Error> {
+    let columns: Vec<String> = sqlx::query_scalar(&format!("SELECT name FROM pragma_table_info('{}')", table))
+        .fetch_all(pool).await?;
+    if columns.iter().any(|existing| existing == column) {
+        return Ok(false);
+    }
+    sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+        .execute(pool).await?;
+    Ok(true)
+}
+
+pub async fn create_tables(pool: &SqlitePool) -> Result<(), AppError> {
+    create_user_table(pool).await?;
+    create_role_permission_table(pool).await?;
+    create_audit_log_table(pool).await?;
+    create_api_key_table(pool).await?;
+    create_refresh_token_table(pool).await?;
+    create_session_table(pool).await?;
+    create_password_reset_table(pool).await?;
+    create_login_attempt_table(pool).await?;
+    create_oidc_tables(pool).await?;
+    create_totp_tables(pool).await?;
+    create_presence_table(pool).await?;
+    create_presence_sync_table(pool).await?;
+    create_calendar_tables(pool).await?;
+    create_reminder_table(pool).await?;
+    create_reminder_template_table(pool).await?;
+    create_reminder_comment_table(pool).await?;
+    create_notification_table(pool).await?;
+    create_temperature_table(pool).await?;
+    create_alert_tables(pool).await?;
+    create_rss_feed_table(pool).await?;
+    create_rss_feed_item_table(pool).await?;
+    create_ping_table(pool).await?;
+    Ok(())
+}
+
+// each module's tests get a fresh in-memory database with the full schema, an in-memory database
+// only lives as long as its connection so the pool keeps exactly one open
+#[cfg(test)]
+pub(crate) async fn test_pool() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:").await.expect("DB connection failed");
+    create_tables(&pool).await.expect("create tables failed");
+    pool
+}
+
+#[cfg(test)]
+pub(crate) fn test_state(pool: &SqlitePool) -> AppState {
+    AppState {
+        db_pool: pool.clone(),
+        jwt_keys: JwtKeys::new("test", "secret"),
+        login_throttle: LoginThrottleConfig { backoff_after: 5, backoff_seconds: 1, lockout_after: 10, lockout_seconds: 60 },
+        oidc: None,
+        ldap: None
+    }
+}
+
 #[derive(Serialize)]
 pub struct AppErrorResponse {
     pub error: String
@@ -44,11 +207,87 @@ impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self {
             AppError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ReminderError(ReminderError::InvalidSchedule) => StatusCode::BAD_REQUEST,
+            AppError::ReminderError(ReminderError::InvalidPriority(_)) => StatusCode::BAD_REQUEST,
+            AppError::ReminderError(ReminderError::UnknownInitials(_)) => StatusCode::BAD_REQUEST,
+            AppError::ReminderError(ReminderError::Expired) => StatusCode::CONFLICT,
+            AppError::ReminderError(ReminderError::InvalidStatus(_)) => StatusCode::BAD_REQUEST,
+            AppError::ReminderError(ReminderError::NotFound) => StatusCode::NOT_FOUND,
             AppError::ReminderError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ReminderTemplateError(ReminderTemplateError::InvalidSchedule(_)) => StatusCode::BAD_REQUEST,
+            AppError::ReminderTemplateError(ReminderTemplateError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::ReminderTemplateError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ReminderCommentError(ReminderCommentError::EmptyComment) => StatusCode::BAD_REQUEST,
+            AppError::ReminderCommentError(ReminderCommentError::ReminderError(ReminderError::NotFound)) => StatusCode::NOT_FOUND,
+            AppError::ReminderCommentError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotificationError(NotificationError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::NotificationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::TemperatureError(TemperatureError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::TemperatureError(TemperatureError::InvalidRange) => StatusCode::BAD_REQUEST,
+            AppError::TemperatureError(TemperatureError::InvalidUnit(_)) => StatusCode::BAD_REQUEST,
+            AppError::TemperatureError(TemperatureError::UnitInUse) => StatusCode::CONFLICT,
+            AppError::TemperatureError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::AlertError(AlertError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::AlertError(AlertError::InvalidThresholds) => StatusCode::BAD_REQUEST,
+            AppError::AlertError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PresenceError(PresenceError::InvalidStatus(_)) => StatusCode::BAD_REQUEST,
+            AppError::PresenceError(PresenceError::InvalidExpiry) => StatusCode::BAD_REQUEST,
+            AppError::PresenceError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::GraphError(GraphError::RequestError(_)) => StatusCode::BAD_GATEWAY,
+            AppError::GraphError(GraphError::InvalidUserId(_)) => StatusCode::BAD_REQUEST,
+            AppError::GraphError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::CalendarError(CalendarError::ParseError(_)) => StatusCode::BAD_REQUEST,
+            AppError::CalendarError(CalendarError::InvalidSource) => StatusCode::BAD_REQUEST,
+            AppError::CalendarError(CalendarError::InvalidUrl) => StatusCode::BAD_REQUEST,
+            AppError::CalendarError(CalendarError::BlockedAddress) => StatusCode::BAD_REQUEST,
+            AppError::CalendarError(CalendarError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::CalendarError(CalendarError::Forbidden) => StatusCode::FORBIDDEN,
+            AppError::CalendarError(CalendarError::RequestError(_)) => StatusCode::BAD_GATEWAY,
+            AppError::CalendarError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RSSError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PingError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::JSONError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::IOError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::TimeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            AppError::UserError(_) => todo!(),
+            AppError::UserError(UserError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::UserError(UserError::LastAdmin) => StatusCode::CONFLICT,
+            AppError::UserError(UserError::InvalidRole(_)) => StatusCode::BAD_REQUEST,
+            AppError::UserError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RoleError(RoleError::UserError(UserError::NotFound)) => StatusCode::NOT_FOUND,
+            AppError::RoleError(RoleError::UserError(UserError::LastAdmin)) => StatusCode::CONFLICT,
+            AppError::RoleError(RoleError::UnknownRole(_)) => StatusCode::BAD_REQUEST,
+            AppError::RoleError(RoleError::UnknownPermission(_)) => StatusCode::BAD_REQUEST,
+            AppError::RoleError(RoleError::ProtectedPermission) => StatusCode::CONFLICT,
+            AppError::RoleError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::AuditError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ApiKeyError(ApiKeyError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::ApiKeyError(ApiKeyError::InvalidScope(_)) => StatusCode::BAD_REQUEST,
+            AppError::ApiKeyError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::RefreshTokenError(RefreshTokenError::Invalid) => StatusCode::UNAUTHORIZED,
+            AppError::RefreshTokenError(RefreshTokenError::Reused) => StatusCode::UNAUTHORIZED,
+            AppError::RefreshTokenError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::SessionError(SessionError::InvalidCsrfToken) => StatusCode::FORBIDDEN,
+            AppError::SessionError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PasswordError(PasswordError::EmptyPassword) => StatusCode::BAD_REQUEST,
+            AppError::PasswordError(PasswordError::IncorrectPassword) => StatusCode::FORBIDDEN,
+            AppError::PasswordError(PasswordError::InvalidResetToken) => StatusCode::UNAUTHORIZED,
+            AppError::PasswordError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::LoginThrottleError(LoginThrottleError::NotFound) => StatusCode::NOT_FOUND,
+            AppError::LoginThrottleError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::OidcError(OidcError::Disabled) => StatusCode::NOT_FOUND,
+            AppError::OidcError(OidcError::InvalidState) => StatusCode::UNAUTHORIZED,
+            AppError::OidcError(OidcError::InvalidToken(_)) => StatusCode::UNAUTHORIZED,
+            AppError::OidcError(OidcError::UnknownUser) => StatusCode::FORBIDDEN,
+            AppError::OidcError(OidcError::UnverifiedEmail) => StatusCode::FORBIDDEN,
+            AppError::OidcError(OidcError::LinkRequired) => StatusCode::FORBIDDEN,
+            AppError::OidcError(OidcError::AlreadyLinked) => StatusCode::CONFLICT,
+            AppError::OidcError(OidcError::RequestError(_)) => StatusCode::BAD_GATEWAY,
+            AppError::OidcError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::TotpError(TotpError::AlreadyEnabled) => StatusCode::CONFLICT,
+            AppError::TotpError(TotpError::NotEnrolled) => StatusCode::NOT_FOUND,
+            AppError::TotpError(TotpError::InvalidCode) => StatusCode::UNAUTHORIZED,
+            AppError::TotpError(TotpError::InvalidChallenge) => StatusCode::UNAUTHORIZED,
+            AppError::TotpError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
     
@@ -0,0 +1,355 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::audit::AuditChange;
+use crate::users::AuthenticatedUser;
+
+#[derive(Error, Debug)]
+pub enum LoginThrottleError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("lockout not found")]
+    NotFound
+}
+
+/// Thresholds for failed logins, read from the environment with the defaults below.
+///
+/// `LOGIN_BACKOFF_AFTER` failures are allowed before each further attempt has to wait
+/// `LOGIN_BACKOFF_SECONDS` doubled for every extra failure, and `LOGIN_LOCKOUT_AFTER`
+/// failures lock the username or address out for `LOGIN_LOCKOUT_SECONDS`. Failures are
+/// forgotten once `LOGIN_LOCKOUT_SECONDS` pass without another one.
+#[derive(Clone, Debug)]
+pub struct LoginThrottleConfig {
+    pub backoff_after: u32,
+    pub backoff_seconds: u32,
+    pub lockout_after: u32,
+    pub lockout_seconds: u32
+}
+
+impl LoginThrottleConfig {
+    pub fn from_env() -> LoginThrottleConfig {
+        fn env_or(name: &str, default: u32) -> u32 {
+            match std::env::var(name) {
+                Ok(value) => value.parse().unwrap_or_else(|_| panic!("{} must be a positive number", name)),
+                Err(_) => default
+            }
+        }
+        LoginThrottleConfig {
+            backoff_after: env_or("LOGIN_BACKOFF_AFTER", 3),
+            backoff_seconds: env_or("LOGIN_BACKOFF_SECONDS", 1),
+            lockout_after: env_or("LOGIN_LOCKOUT_AFTER", 10),
+            lockout_seconds: env_or("LOGIN_LOCKOUT_SECONDS", 900)
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct LoginAttempt {
+    id: u32,
+    kind: String,
+    subject: String,
+    failures: u32,
+    last_failure_time: u32,
+    locked_until: Option<u32>
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct LockoutEvent {
+    id: u32,
+    kind: String,
+    subject: String,
+    failures: u32,
+    locked_time: u32,
+    locked_until: u32,
+    cleared_by: Option<String>,
+    cleared_time: Option<u32>
+}
+
+pub async fn create_login_attempt_table(pool: &SqlitePool) -> Result<(), LoginThrottleError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS login_attempts (id INTEGER PRIMARY KEY AUTOINCREMENT, kind TEXT NOT NULL, subject TEXT NOT NULL, failures INTEGER, last_failure_time INTEGER, locked_until INTEGER, UNIQUE(kind, subject))")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS lockout_events (id INTEGER PRIMARY KEY AUTOINCREMENT, kind TEXT NOT NULL, subject TEXT NOT NULL, failures INTEGER, locked_time INTEGER, locked_until INTEGER, cleared_by TEXT, cleared_time INTEGER)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+// failures this old no longer count, so an address is never throttled forever
+fn is_stale(attempt: &LoginAttempt, config: &LoginThrottleConfig, time: u32) -> bool {
+    attempt.last_failure_time.saturating_add(config.lockout_seconds) <= time
+}
+
+// seconds until the subject may try again, 0 when an attempt is allowed now
+fn retry_after(attempt: &LoginAttempt, config: &LoginThrottleConfig, time: u32) -> u32 {
+    if let Some(locked_until) = attempt.locked_until {
+        if locked_until > time {
+            return locked_until - time;
+        }
+    }
+    if attempt.failures < config.backoff_after || is_stale(attempt, config, time) {
+        return 0;
+    }
+    let exponent = (attempt.failures - config.backoff_after).min(16);
+    let wait_until = attempt.last_failure_time + config.backoff_seconds.saturating_mul(1 << exponent);
+    wait_until.saturating_sub(time)
+}
+
+/// Returns how many seconds the caller has to wait before the next login attempt for
+/// any of the given `(kind, subject)` pairs, `0` when logging in is allowed.
+pub async fn check_login_allowed(subjects: &[(&str, &str)], config: &LoginThrottleConfig, pool: &SqlitePool) -> Result<u32, LoginThrottleError> {
+    let time = now()?;
+    let mut wait = 0;
+    for (kind, subject) in subjects {
+        let query = sqlx::query_as::<_, LoginAttempt>("SELECT * FROM login_attempts WHERE kind = $1 AND subject = $2")
+            .bind(kind)
+            .bind(subject);
+        if let Some(attempt) = query.fetch_optional(pool).await? {
+            wait = wait.max(retry_after(&attempt, config, time));
+        }
+    }
+    Ok(wait)
+}
+
+pub async fn record_login_failure(subjects: &[(&str, &str)], config: &LoginThrottleConfig, pool: &SqlitePool) -> Result<(), LoginThrottleError> {
+    let time = now()?;
+    for (kind, subject) in subjects {
+        let attempt = sqlx::query_as::<_, LoginAttempt>("INSERT INTO login_attempts (kind, subject, failures, last_failure_time) values ($1, $2, 1, $3) ON CONFLICT(kind, subject) DO UPDATE SET failures = CASE WHEN last_failure_time <= $4 THEN 1 ELSE failures + 1 END, last_failure_time = $3 RETURNING *")
+            .bind(kind)
+            .bind(subject)
+            .bind(time)
+            .bind(time.saturating_sub(config.lockout_seconds))
+            .fetch_one(pool).await?;
+
+        if attempt.failures >= config.lockout_after && attempt.locked_until.unwrap_or_default() <= time {
+            let locked_until = time + config.lockout_seconds;
+            sqlx::query("UPDATE login_attempts SET locked_until = $1 WHERE id = $2")
+                .bind(locked_until)
+                .bind(attempt.id)
+                .execute(pool).await?;
+            sqlx::query("INSERT INTO lockout_events (kind, subject, failures, locked_time, locked_until) values ($1, $2, $3, $4, $5)")
+                .bind(kind)
+                .bind(subject)
+                .bind(attempt.failures)
+                .bind(time)
+                .bind(locked_until)
+                .execute(pool).await?;
+            println!("login locked out for {} {} after {} failures", kind, subject, attempt.failures);
+        }
+    }
+    Ok(())
+}
+
+pub async fn clear_login_failures(subjects: &[(&str, &str)], pool: &SqlitePool) -> Result<(), LoginThrottleError> {
+    for (kind, subject) in subjects {
+        sqlx::query("DELETE FROM login_attempts WHERE kind = $1 AND subject = $2")
+            .bind(kind)
+            .bind(subject)
+            .execute(pool).await?;
+    }
+    Ok(())
+}
+
+pub async fn get_lockouts(
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, LoginAttempt>("SELECT * FROM login_attempts WHERE locked_until > $1 ORDER BY locked_until DESC").bind(now()?);
+    let rows: Vec<LoginAttempt> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+pub async fn get_lockout_events(
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, LockoutEvent>("SELECT * FROM lockout_events ORDER BY locked_time DESC");
+    let rows: Vec<LockoutEvent> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+pub async fn clear_lockout(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, LoginAttempt>("DELETE FROM login_attempts WHERE id = $1 RETURNING *").bind(*id);
+    let attempt: LoginAttempt = match query.fetch_optional(&data.db_pool).await? {
+        Some(attempt) => attempt,
+        None => return Err(LoginThrottleError::NotFound.into())
+    };
+
+    sqlx::query("UPDATE lockout_events SET cleared_by = $1, cleared_time = $2 WHERE kind = $3 AND subject = $4 AND cleared_time IS NULL")
+        .bind(&auth.username)
+        .bind(now()?)
+        .bind(&attempt.kind)
+        .bind(&attempt.subject)
+        .execute(&data.db_pool).await?;
+    AuditChange::new("lockout", attempt.id).before(&attempt).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpMessage};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use crate::{test_pool, test_state};
+    use crate::users::{create_user, login};
+
+    fn config() -> LoginThrottleConfig {
+        LoginThrottleConfig { backoff_after: 3, backoff_seconds: 2, lockout_after: 10, lockout_seconds: 900 }
+    }
+
+    fn attempt(failures: u32, last_failure_time: u32, locked_until: Option<u32>) -> LoginAttempt {
+        LoginAttempt { id: 1, kind: "ip".to_string(), subject: "10.0.0.1".to_string(), failures, last_failure_time, locked_until }
+    }
+
+    async fn failures(kind: &str, subject: &str, pool: &SqlitePool) -> Option<u32> {
+        sqlx::query_scalar("SELECT failures FROM login_attempts WHERE kind = $1 AND subject = $2")
+            .bind(kind)
+            .bind(subject)
+            .fetch_optional(pool).await.unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_allowed_failures() {
+        let config = config();
+        assert_eq!(retry_after(&attempt(2, 1000, None), &config, 1000), 0);
+        assert_eq!(retry_after(&attempt(3, 1000, None), &config, 1000), 2);
+        assert_eq!(retry_after(&attempt(4, 1000, None), &config, 1000), 4);
+        assert_eq!(retry_after(&attempt(6, 1000, None), &config, 1000), 16);
+        // time already waited counts towards the backoff
+        assert_eq!(retry_after(&attempt(6, 1000, None), &config, 1010), 6);
+        assert_eq!(retry_after(&attempt(6, 1000, None), &config, 1016), 0);
+    }
+
+    #[test]
+    fn backoff_is_capped_and_forgotten_after_the_lockout_period() {
+        let slow = LoginThrottleConfig { backoff_after: 1, backoff_seconds: 1, lockout_after: 1000, lockout_seconds: 100000 };
+        assert_eq!(retry_after(&attempt(17, 1000, None), &slow, 1000), 1 << 16);
+        assert_eq!(retry_after(&attempt(500, 1000, None), &slow, 1000), 1 << 16);
+
+        let config = config();
+        assert_eq!(retry_after(&attempt(20, 1000, None), &config, 1899), 2 * (1 << 16) - 899);
+        assert_eq!(retry_after(&attempt(20, 1000, None), &config, 1900), 0);
+    }
+
+    #[test]
+    fn lockouts_take_precedence_until_they_end() {
+        let config = config();
+        assert_eq!(retry_after(&attempt(10, 1000, Some(1900)), &config, 1000), 900);
+        assert_eq!(retry_after(&attempt(10, 1000, Some(1900)), &config, 1500), 400);
+        assert_eq!(retry_after(&attempt(10, 1000, Some(1900)), &config, 1900), 0);
+    }
+
+    #[actix_rt::test]
+    async fn failures_lock_out_and_the_longest_wait_wins() {
+        let pool = test_pool().await;
+        let config = config();
+        let subjects = [("username", "jordan"), ("ip", "10.0.0.1")];
+
+        for _ in 0..2 {
+            record_login_failure(&subjects, &config, &pool).await.unwrap();
+        }
+        assert_eq!(check_login_allowed(&subjects, &config, &pool).await.unwrap(), 0);
+
+        // the address has failed more often on other usernames, so it sets the wait
+        for _ in 0..7 {
+            record_login_failure(&[("ip", "10.0.0.1")], &config, &pool).await.unwrap();
+        }
+        assert_eq!(check_login_allowed(&[("username", "jordan")], &config, &pool).await.unwrap(), 0);
+        let wait = check_login_allowed(&subjects, &config, &pool).await.unwrap();
+        assert!(wait > 2 && wait <= 128, "{}", wait);
+
+        let events: Vec<LockoutEvent> = sqlx::query_as("SELECT * FROM lockout_events").fetch_all(&pool).await.unwrap();
+        assert!(events.is_empty());
+
+        record_login_failure(&subjects, &config, &pool).await.unwrap();
+        let events: Vec<LockoutEvent> = sqlx::query_as("SELECT * FROM lockout_events").fetch_all(&pool).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].kind.as_str(), events[0].subject.as_str(), events[0].failures), ("ip", "10.0.0.1", 10));
+        assert_eq!(events[0].locked_until - events[0].locked_time, 900);
+        let wait = check_login_allowed(&subjects, &config, &pool).await.unwrap();
+        assert!(wait > 890 && wait <= 900, "{}", wait);
+
+        // another failure while locked does not extend the lockout or log a new event
+        record_login_failure(&subjects, &config, &pool).await.unwrap();
+        let count: u32 = sqlx::query_scalar("SELECT COUNT(*) FROM lockout_events").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[actix_rt::test]
+    async fn failures_expire_on_their_own() {
+        let pool = test_pool().await;
+        let config = config();
+        for _ in 0..5 {
+            record_login_failure(&[("ip", "10.0.0.1")], &config, &pool).await.unwrap();
+        }
+        assert_eq!(failures("ip", "10.0.0.1", &pool).await, Some(5));
+
+        sqlx::query("UPDATE login_attempts SET last_failure_time = last_failure_time - 900")
+            .execute(&pool).await.unwrap();
+        assert_eq!(check_login_allowed(&[("ip", "10.0.0.1")], &config, &pool).await.unwrap(), 0);
+        record_login_failure(&[("ip", "10.0.0.1")], &config, &pool).await.unwrap();
+        assert_eq!(failures("ip", "10.0.0.1", &pool).await, Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn logging_in_only_clears_the_username() {
+        let pool = test_pool().await;
+        create_user("mallory", "MA", "pass123", "member", &pool).await.unwrap();
+        let subjects = [("username", "mallory"), ("ip", "10.0.0.1")];
+        let config = test_state(&pool).login_throttle;
+        for _ in 0..3 {
+            record_login_failure(&subjects, &config, &pool).await.unwrap();
+        }
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/login", web::post().to(login))).await;
+        let request = TestRequest::post()
+            .uri("/api/login")
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .set_json(serde_json::json!({ "username": "mallory", "password": "pass123" }))
+            .to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+
+        assert_eq!(failures("username", "mallory", &pool).await, None);
+        assert_eq!(failures("ip", "10.0.0.1", &pool).await, Some(3));
+    }
+
+    #[actix_rt::test]
+    async fn clearing_a_lockout_records_who_cleared_it() {
+        let pool = test_pool().await;
+        let config = config();
+        for _ in 0..10 {
+            record_login_failure(&[("username", "jordan")], &config, &pool).await.unwrap();
+        }
+        let id: u32 = sqlx::query_scalar("SELECT id FROM login_attempts").fetch_one(&pool).await.unwrap();
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/lockouts/{id}", web::delete().to(clear_lockout))).await;
+        let clear = |id: u32| {
+            let request = TestRequest::delete().uri(&format!("/api/lockouts/{}", id)).to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: "carol".to_string(), initials: "CA".to_string(), api_key_id: None });
+            request
+        };
+
+        assert_eq!(call_service(&app, clear(id)).await.status(), StatusCode::OK);
+        assert_eq!(check_login_allowed(&[("username", "jordan")], &config, &pool).await.unwrap(), 0);
+        assert_eq!(failures("username", "jordan", &pool).await, None);
+        let event: LockoutEvent = sqlx::query_as("SELECT * FROM lockout_events").fetch_one(&pool).await.unwrap();
+        assert_eq!(event.cleared_by.as_deref(), Some("carol"));
+        assert!(event.cleared_time.is_some());
+
+        assert_eq!(call_service(&app, clear(id)).await.status(), StatusCode::NOT_FOUND);
+    }
+}
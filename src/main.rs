@@ -3,16 +3,33 @@ use actix_web_httpauth::middleware::HttpAuthentication;
 use tokio_schedule::{every, Job};
 use chrono::{Local, Utc};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
 use actix_files::Files;
 use sqlx::{sqlite::{SqlitePool}, migrate::MigrateDatabase};
 use work_dash_backend::{
-    AppState,
-    users::{create_user_table, login, bearer_auth_validator, get_api_key}, 
-    reminders::{create_reminder_table, get_active_reminders, get_all_reminders, create_reminder, disable_reminder},
-    temperatures::{create_temperature_table, update_temperature, get_temperatures}, 
-    rss::{create_rss_feed_table, get_feeds, download_rss_feeds, create_rss_feed_item_table, get_feed_items, create_rss_feed, dismiss_feed_item}, ping::{create_ping_table, create_ping, get_ping, ping_hosts}
+    AppState, DB_URL, create_tables,
+    jwt_keys::JwtKeys,
+    users::{login, bearer_auth_validator, get_users, add_user, update_user, delete_user}, 
+    api_keys::{create_api_key, get_api_keys, revoke_api_key},
+    refresh_tokens::refresh_access_token,
+    sessions::logout,
+    passwords::{change_own_password, create_password_reset, complete_password_reset},
+    oidc::{OidcConfig, oidc_login, oidc_callback, link_oidc_identity},
+    ldap::LdapConfig,
+    totp::{login_totp, start_totp_enrollment, confirm_totp_enrollment, regenerate_recovery_codes, disable_totp, reset_user_totp},
+    login_throttle::{LoginThrottleConfig, get_lockouts, get_lockout_events, clear_lockout},
+    scopes::RequireScope,
+    roles::{get_roles, update_role, assign_role},
+    audit::{AuditLog, get_audit_log, prune_audit_log},
+    presence::{expire_presence, get_presence, set_presence},
+    graph::{GraphConfig, sync_presence, get_presence_sync, update_presence_sync},
+    calendar::{refresh_calendars, update_calendar_presence, get_calendars, create_calendar, delete_calendar, get_upcoming_events},
+    reminders::{get_active_reminders, get_all_reminders, create_reminder, update_reminder, disable_reminder, get_reminder_history, expire_reminders},
+    reminder_comments::{get_reminder_comments, create_reminder_comment, acknowledge_reminder},
+    notifications::{get_notifications, mark_notification_read, mark_all_notifications_read},
+    reminder_templates::{generate_scheduled_reminders, get_reminder_templates, create_reminder_template, update_reminder_template, delete_reminder_template},
+    temperatures::{update_temperature, get_temperatures, get_temperature_history, rollup_temperature_readings, get_probes, create_probe, update_probe, delete_probe},
+    alerts::{get_alerts, get_temperature_thresholds, update_temperature_thresholds}, 
+    rss::{get_feeds, download_rss_feeds, get_feed_items, create_rss_feed, dismiss_feed_item}, ping::{create_ping, get_ping, ping_hosts}
 };
 
 
@@ -22,7 +39,7 @@ async fn not_found() -> impl Responder {
 
 
 async fn start_rss_scheduler(pool: &SqlitePool) {
-    match download_rss_feeds(&pool).await {
+    match download_rss_feeds(pool).await {
         Ok(_) => println!("First data refresh succeeded"),
         Err(e) => println!("First data refresh failed - {}", e)
     }
@@ -31,7 +48,7 @@ async fn start_rss_scheduler(pool: &SqlitePool) {
         .in_timezone(&Utc)
         .perform(|| async { 
             println!("schedule_task data refresh - {:?}", Local::now()); 
-            match download_rss_feeds(&pool).await {
+            match download_rss_feeds(pool).await {
                 Ok(_) => println!("data refresh succeeded"),
                 Err(e) => println!("data refresh failed - {}", e)
             }
@@ -40,7 +57,7 @@ async fn start_rss_scheduler(pool: &SqlitePool) {
 }
 
 async fn start_ping_scheduler(pool: &SqlitePool) {
-    match ping_hosts(&pool).await {
+    match ping_hosts(pool).await {
         Ok(_) => println!("First ping task succeeded"),
         Err(e) => println!("First ping task failed - {}", e)
     }
@@ -49,7 +66,7 @@ async fn start_ping_scheduler(pool: &SqlitePool) {
         .in_timezone(&Utc)
         .perform(|| async { 
             println!("schedule_task ping - {:?}", Local::now()); 
-            match ping_hosts(&pool).await {
+            match ping_hosts(pool).await {
                 Ok(_) => println!("ping task succeeded"),
                 Err(e) => println!("ping task failed - {}", e)
             }
@@ -57,33 +74,140 @@ async fn start_ping_scheduler(pool: &SqlitePool) {
     every_hour.await;
 }
 
+async fn start_reminder_scheduler(pool: &SqlitePool) {
+    let every_minute = every(1)
+        .minutes()
+        .in_timezone(&Utc)
+        .perform(|| async {
+            match generate_scheduled_reminders(pool).await {
+                Ok(0) => {},
+                Ok(generated) => println!("{} scheduled reminders generated", generated),
+                Err(e) => println!("scheduled reminder generation failed - {}", e)
+            }
+            match expire_reminders(pool).await {
+                Ok(0) => {},
+                Ok(expired) => println!("{} reminders expired", expired),
+                Err(e) => println!("reminder expiry failed - {}", e)
+            }
+        });
+    every_minute.await;
+}
+
+async fn start_temperature_rollup_scheduler(pool: &SqlitePool) {
+    if let Err(e) = rollup_temperature_readings(pool).await {
+        println!("First temperature rollup failed - {}", e);
+    }
+    let every_hour = every(1)
+        .hour()
+        .in_timezone(&Utc)
+        .perform(|| async {
+            if let Err(e) = rollup_temperature_readings(pool).await {
+                println!("temperature rollup failed - {}", e);
+            }
+        });
+    every_hour.await;
+}
+
+async fn start_audit_prune_scheduler(pool: &SqlitePool) {
+    let every_day = every(1)
+        .day()
+        .at(3, 0, 0)
+        .in_timezone(&Utc)
+        .perform(|| async {
+            match prune_audit_log(pool).await {
+                Ok(0) => {},
+                Ok(pruned) => println!("{} audit log entries pruned", pruned),
+                Err(e) => println!("audit log prune failed - {}", e)
+            }
+        });
+    every_day.await;
+}
+
+async fn start_calendar_scheduler(pool: &SqlitePool) {
+    match refresh_calendars(pool).await {
+        Ok(_) => println!("First calendar refresh succeeded"),
+        Err(e) => println!("First calendar refresh failed - {}", e)
+    }
+    let every_fifteen_minutes = every(15)
+        .minutes()
+        .in_timezone(&Utc)
+        .perform(|| async {
+            println!("schedule_task calendar refresh - {:?}", Local::now());
+            match refresh_calendars(pool).await {
+                Ok(_) => println!("calendar refresh succeeded"),
+                Err(e) => println!("calendar refresh failed - {}", e)
+            }
+        });
+    every_fifteen_minutes.await;
+}
+
+async fn start_calendar_presence_scheduler(pool: &SqlitePool) {
+    let every_minute = every(1)
+        .minutes()
+        .in_timezone(&Utc)
+        .perform(|| async {
+            if let Err(e) = update_calendar_presence(pool).await {
+                println!("calendar presence update failed - {}", e);
+            }
+        });
+    every_minute.await;
+}
+
+async fn start_presence_expiry_scheduler(pool: &SqlitePool) {
+    let every_minute = every(1)
+        .minutes()
+        .in_timezone(&Utc)
+        .perform(|| async {
+            match expire_presence(pool).await {
+                Ok(0) => {},
+                Ok(expired) => println!("{} presence statuses expired", expired),
+                Err(e) => println!("presence expiry failed - {}", e)
+            }
+        });
+    every_minute.await;
+}
+
+async fn start_presence_scheduler(pool: &SqlitePool, config: &GraphConfig) {
+    let every_poll = every(config.poll_seconds)
+        .seconds()
+        .in_timezone(&Utc)
+        .perform(|| async {
+            if let Err(e) = sync_presence(config, pool).await {
+                println!("presence sync failed - {}", e);
+            }
+        });
+    every_poll.await;
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=debug");
     env_logger::init();
     dotenv::dotenv().ok();
 
-    let jwt_key_string: String = std::env::var("JWT_KEY").expect("JWT_KEY environment variable is not set");
-    let jwt_key: Hmac<Sha256> = Hmac::<Sha256>::new_from_slice(jwt_key_string.as_bytes()).expect("HMAC can take key of any size");
-
-    let db_url = "sqlite://data.db";
+    let jwt_keys = JwtKeys::from_env();
+    let login_throttle = LoginThrottleConfig::from_env();
+    let oidc = OidcConfig::from_env();
+    let ldap = LdapConfig::from_env();
+    let graph = GraphConfig::from_env();
 
-    if !sqlx::Sqlite::database_exists(&db_url).await.expect("check if DB exists failed") {
-        sqlx::Sqlite::create_database(&db_url).await.expect("create DB failed");
+    if !sqlx::Sqlite::database_exists(DB_URL).await.expect("check if DB exists failed") {
+        sqlx::Sqlite::create_database(DB_URL).await.expect("create DB failed");
     }
 
-    let pool = SqlitePool::connect(&db_url).await.expect("DB connection failed");
-    let rss_pool = SqlitePool::connect(&db_url).await.expect("DB connection failed");
-    let ping_pool = SqlitePool::connect(&db_url).await.expect("DB connection failed");
+    let pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let rss_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let ping_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let reminder_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let temperature_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let audit_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let calendar_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let calendar_presence_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+    let presence_expiry_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
 
 
 
-    create_user_table(&pool).await.expect("create user table failed");
-    create_reminder_table(&pool).await.expect("create reminder table failed");
-    create_temperature_table(&pool).await.expect("create temperature table failed");
-    create_rss_feed_table(&pool).await.expect("create rss feed table failed");
-    create_rss_feed_item_table(&pool).await.expect("create rss feed item table failed");
-    create_ping_table(&pool).await.expect("create ping table failed");
+    create_tables(&pool).await.expect("create tables failed");
 
     ping_hosts(&pool).await.expect("error pinging hosts");
 
@@ -94,6 +218,30 @@ async fn main() -> std::io::Result<()> {
     actix_rt::spawn(async move {
         start_ping_scheduler(&ping_pool).await;
     });
+    actix_rt::spawn(async move {
+        start_reminder_scheduler(&reminder_pool).await;
+    });
+    actix_rt::spawn(async move {
+        start_temperature_rollup_scheduler(&temperature_pool).await;
+    });
+    actix_rt::spawn(async move {
+        start_audit_prune_scheduler(&audit_pool).await;
+    });
+    actix_rt::spawn(async move {
+        start_calendar_scheduler(&calendar_pool).await;
+    });
+    actix_rt::spawn(async move {
+        start_calendar_presence_scheduler(&calendar_presence_pool).await;
+    });
+    actix_rt::spawn(async move {
+        start_presence_expiry_scheduler(&presence_expiry_pool).await;
+    });
+    if let Some(graph) = graph {
+        let presence_pool = SqlitePool::connect(DB_URL).await.expect("DB connection failed");
+        actix_rt::spawn(async move {
+            start_presence_scheduler(&presence_pool, &graph).await;
+        });
+    }
     
 
 
@@ -103,33 +251,92 @@ async fn main() -> std::io::Result<()> {
         .unwrap();
     builder.set_certificate_chain_file("cert.pem").unwrap();
 
-    let auth = HttpAuthentication::bearer(bearer_auth_validator);
+    let auth = HttpAuthentication::with_fn(bearer_auth_validator);
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(AppState { 
                 db_pool: pool.clone(),
-                jwt_key: jwt_key.clone()
+                jwt_keys: jwt_keys.clone(),
+                login_throttle: login_throttle.clone(),
+                oidc: oidc.clone(),
+                ldap: ldap.clone()
             }))
             .default_service(web::route().to(not_found))
-            .route("/api/login", web::post().to(login))
+            .route("/api/login", web::post().to(login).wrap(AuditLog))
+            .route("/api/login/totp", web::post().to(login_totp).wrap(AuditLog))
+            .route("/api/token/refresh", web::post().to(refresh_access_token).wrap(AuditLog))
+            .route("/api/password/reset", web::post().to(complete_password_reset).wrap(AuditLog))
+            .route("/api/oidc/login", web::get().to(oidc_login))
+            .route("/api/oidc/callback", web::get().to(oidc_callback))
             .service(
                 web::scope("/api")
+                    .wrap(AuditLog)
                     .wrap(Logger::default())
                     .wrap(auth.clone())
-                    .route("/apikey", web::get().to(get_api_key))
-                    .route("/reminders", web::get().to(get_all_reminders))
-                    .route("/reminders", web::post().to(create_reminder))
-                    .route("/reminders", web::delete().to(disable_reminder))
-                    .route("/reminders/active", web::get().to(get_active_reminders))
-                    .route("/temperatures", web::get().to(get_temperatures))
-                    .route("/temperatures", web::post().to(update_temperature))
-                    .route("/rss/feeds", web::get().to(get_feeds))
-                    .route("/rss/feed", web::get().to(get_feed_items))
-                    .route("/rss/feed", web::post().to(create_rss_feed))
-                    .route("/rss/feed/dismiss", web::post().to(dismiss_feed_item))
-                    .route("/ping", web::get().to(get_ping))
-                    .route("/ping", web::post().to(create_ping))
+                    .route("/logout", web::post().to(logout))
+                    .route("/apikeys", web::get().to(get_api_keys).wrap(RequireScope("apikeys:admin")))
+                    .route("/apikeys", web::post().to(create_api_key).wrap(RequireScope("apikeys:admin")))
+                    .route("/apikeys/{id}", web::delete().to(revoke_api_key).wrap(RequireScope("apikeys:admin")))
+                    .route("/users", web::get().to(get_users).wrap(RequireScope("users:admin")))
+                    .route("/users", web::post().to(add_user).wrap(RequireScope("users:admin")))
+                    .route("/users/{id}", web::put().to(update_user).wrap(RequireScope("users:admin")))
+                    .route("/users/{id}", web::delete().to(delete_user).wrap(RequireScope("users:admin")))
+                    .route("/users/{id}/role", web::put().to(assign_role).wrap(RequireScope("users:admin")))
+                    .route("/audit", web::get().to(get_audit_log).wrap(RequireScope("users:admin")))
+                    .route("/roles", web::get().to(get_roles).wrap(RequireScope("users:admin")))
+                    .route("/roles/{name}", web::put().to(update_role).wrap(RequireScope("users:admin")))
+                    .route("/users/{id}/password-reset", web::post().to(create_password_reset).wrap(RequireScope("users:admin")))
+                    .route("/users/{id}/totp", web::delete().to(reset_user_totp).wrap(RequireScope("users:admin")))
+                    .route("/lockouts", web::get().to(get_lockouts).wrap(RequireScope("users:admin")))
+                    .route("/lockouts/events", web::get().to(get_lockout_events).wrap(RequireScope("users:admin")))
+                    .route("/lockouts/{id}", web::delete().to(clear_lockout).wrap(RequireScope("users:admin")))
+                    .route("/me/password", web::post().to(change_own_password).wrap(RequireScope("account:write")))
+                    .route("/me/oidc", web::post().to(link_oidc_identity).wrap(RequireScope("account:write")))
+                    .route("/me/totp", web::post().to(start_totp_enrollment).wrap(RequireScope("account:write")))
+                    .route("/me/totp", web::delete().to(disable_totp).wrap(RequireScope("account:write")))
+                    .route("/me/totp/confirm", web::post().to(confirm_totp_enrollment).wrap(RequireScope("account:write")))
+                    .route("/me/totp/recovery-codes", web::post().to(regenerate_recovery_codes).wrap(RequireScope("account:write")))
+                    .route("/presence", web::get().to(get_presence).wrap(RequireScope("presence:read")))
+                    .route("/presence", web::put().to(set_presence).wrap(RequireScope("presence:write")))
+                    .route("/presence/sync", web::get().to(get_presence_sync).wrap(RequireScope("users:admin")))
+                    .route("/users/{id}/presence-sync", web::put().to(update_presence_sync).wrap(RequireScope("users:admin")))
+                    .route("/calendars", web::get().to(get_calendars).wrap(RequireScope("calendar:read")))
+                    .route("/calendars", web::post().to(create_calendar).wrap(RequireScope("calendar:write")))
+                    .route("/calendars/{id}", web::delete().to(delete_calendar).wrap(RequireScope("calendar:write")))
+                    .route("/calendar/upcoming", web::get().to(get_upcoming_events).wrap(RequireScope("calendar:read")))
+                    .route("/reminders", web::get().to(get_all_reminders).wrap(RequireScope("reminders:read")))
+                    .route("/reminders", web::post().to(create_reminder).wrap(RequireScope("reminders:write")))
+                    .route("/reminders", web::delete().to(disable_reminder).wrap(RequireScope("reminders:write")))
+                    .route("/reminders/active", web::get().to(get_active_reminders).wrap(RequireScope("reminders:read")))
+                    .route("/reminders/templates", web::get().to(get_reminder_templates).wrap(RequireScope("reminders:read")))
+                    .route("/reminders/templates", web::post().to(create_reminder_template).wrap(RequireScope("reminders:write")))
+                    .route("/reminders/templates/{id}", web::patch().to(update_reminder_template).wrap(RequireScope("reminders:write")))
+                    .route("/reminders/templates/{id}", web::delete().to(delete_reminder_template).wrap(RequireScope("reminders:write")))
+                    .route("/reminders/{id}", web::patch().to(update_reminder).wrap(RequireScope("reminders:write")))
+                    .route("/reminders/{id}/history", web::get().to(get_reminder_history).wrap(RequireScope("reminders:read")))
+                    .route("/reminders/{id}/comments", web::get().to(get_reminder_comments).wrap(RequireScope("reminders:read")))
+                    .route("/reminders/{id}/comments", web::post().to(create_reminder_comment).wrap(RequireScope("reminders:write")))
+                    .route("/reminders/{id}/acknowledge", web::post().to(acknowledge_reminder).wrap(RequireScope("reminders:write")))
+                    .route("/notifications", web::get().to(get_notifications).wrap(RequireScope("reminders:read")))
+                    .route("/notifications/read", web::post().to(mark_all_notifications_read).wrap(RequireScope("account:write")))
+                    .route("/notifications/{id}/read", web::post().to(mark_notification_read).wrap(RequireScope("account:write")))
+                    .route("/temperatures", web::get().to(get_temperatures).wrap(RequireScope("temperatures:read")))
+                    .route("/temperatures", web::post().to(update_temperature).wrap(RequireScope("temperatures:write")))
+                    .route("/temperatures/probes", web::get().to(get_probes).wrap(RequireScope("temperatures:admin")))
+                    .route("/temperatures/probes", web::post().to(create_probe).wrap(RequireScope("temperatures:admin")))
+                    .route("/temperatures/probes/{id}", web::put().to(update_probe).wrap(RequireScope("temperatures:admin")))
+                    .route("/temperatures/probes/{id}", web::delete().to(delete_probe).wrap(RequireScope("temperatures:admin")))
+                    .route("/temperatures/{id}/history", web::get().to(get_temperature_history).wrap(RequireScope("temperatures:read")))
+                    .route("/temperatures/{id}/thresholds", web::get().to(get_temperature_thresholds).wrap(RequireScope("temperatures:read")))
+                    .route("/temperatures/{id}/thresholds", web::put().to(update_temperature_thresholds).wrap(RequireScope("temperatures:admin")))
+                    .route("/alerts", web::get().to(get_alerts).wrap(RequireScope("temperatures:read")))
+                    .route("/rss/feeds", web::get().to(get_feeds).wrap(RequireScope("rss:read")))
+                    .route("/rss/feed", web::get().to(get_feed_items).wrap(RequireScope("rss:read")))
+                    .route("/rss/feed", web::post().to(create_rss_feed).wrap(RequireScope("rss:admin")))
+                    .route("/rss/feed/dismiss", web::post().to(dismiss_feed_item).wrap(RequireScope("rss:write")))
+                    .route("/ping", web::get().to(get_ping).wrap(RequireScope("ping:read")))
+                    .route("/ping", web::post().to(create_ping).wrap(RequireScope("ping:admin")))
 
 
 
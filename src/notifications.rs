@@ -0,0 +1,199 @@
+use actix_web::{Responder, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::users::AuthenticatedUser;
+
+#[derive(Error, Debug)]
+pub enum NotificationError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("notification not found")]
+    NotFound
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct Notification {
+    id: u32,
+    username: String,
+    created_time: u32,
+    reminder_id: u32,
+    // set when the mention was in a comment rather than the reminder itself
+    comment_id: Option<u32>,
+    mentioned_by: String,
+    // the reminder or comment text the mention was found in
+    message: String,
+    read_time: Option<u32>
+}
+
+pub async fn create_notification_table(pool: &SqlitePool) -> Result<(), NotificationError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS notifications (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT NOT NULL, created_time INTEGER, reminder_id INTEGER NOT NULL, comment_id INTEGER, mentioned_by TEXT NOT NULL, message TEXT NOT NULL, read_time INTEGER)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+/// Initials mentioned as `@XX` in the text, uppercased and without duplicates.
+/// An @ straight after a letter or number is skipped so email addresses are not read as mentions.
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    let mut parts = text.split('@');
+    let mut previous = parts.next().unwrap_or("");
+    for part in parts {
+        let initials: String = part.chars().take_while(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase();
+        if !initials.is_empty() && !previous.ends_with(|c: char| c.is_alphanumeric()) && !mentions.contains(&initials) {
+            mentions.push(initials);
+        }
+        previous = part;
+    }
+    mentions
+}
+
+/// Notifies every user mentioned in `text`, except whoever wrote it. Mentions that were already in
+/// `previous`, the text before an edit, are skipped so editing a reminder does not notify people twice.
+/// Initials that do not belong to a user are ignored. Returns how many notifications were created.
+pub async fn notify_mentions(text: &str, previous: Option<&str>, reminder_id: u32, comment_id: Option<u32>, mentioned_by: &str, pool: &SqlitePool) -> Result<u32, NotificationError> {
+    let already_mentioned = previous.map(parse_mentions).unwrap_or_default();
+    let time = now()?;
+    let mut notified = 0;
+
+    for initials in parse_mentions(text) {
+        if already_mentioned.contains(&initials) || initials.eq_ignore_ascii_case(mentioned_by) {
+            continue;
+        }
+        let username: Option<String> = sqlx::query_scalar("SELECT username FROM users WHERE UPPER(initials) = $1")
+            .bind(&initials)
+            .fetch_optional(pool).await?;
+        let username = match username {
+            Some(username) => username,
+            None => continue
+        };
+        sqlx::query("INSERT INTO notifications (username, created_time, reminder_id, comment_id, mentioned_by, message) values ($1, $2, $3, $4, $5, $6)")
+            .bind(username)
+            .bind(time)
+            .bind(reminder_id)
+            .bind(comment_id)
+            .bind(mentioned_by)
+            .bind(text)
+            .execute(pool).await?;
+        notified += 1;
+    }
+    Ok(notified)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NotificationQuery {
+    #[serde(default)]
+    unread: bool
+}
+
+pub async fn get_notifications(
+    query: web::Query<NotificationQuery>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let sql = match query.unread {
+        true => "SELECT * FROM notifications WHERE username = $1 AND read_time IS NULL ORDER BY created_time DESC",
+        false => "SELECT * FROM notifications WHERE username = $1 ORDER BY created_time DESC"
+    };
+    let rows: Vec<Notification> = sqlx::query_as::<_, Notification>(sql)
+        .bind(&auth.username)
+        .fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+// users can only mark their own notifications, anyone else's show as not found
+pub async fn mark_notification_read(
+    id: web::Path<u32>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, Notification>("UPDATE notifications SET read_time = COALESCE(read_time, $1) WHERE id = $2 AND username = $3 RETURNING *")
+        .bind(now()?)
+        .bind(*id)
+        .bind(&auth.username);
+    let row = query.fetch_optional(&data.db_pool).await?.ok_or(NotificationError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+pub async fn mark_all_notifications_read(
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    sqlx::query("UPDATE notifications SET read_time = $1 WHERE username = $2 AND read_time IS NULL")
+        .bind(now()?)
+        .bind(&auth.username)
+        .execute(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpMessage};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use crate::{test_pool, test_state};
+    use crate::users::create_user;
+
+    async fn notified(pool: &SqlitePool) -> Vec<(String, String)> {
+        sqlx::query_as("SELECT username, mentioned_by FROM notifications ORDER BY id ASC").fetch_all(pool).await.unwrap()
+    }
+
+    #[test]
+    fn mentions_skip_email_addresses_and_repeats() {
+        assert_eq!(parse_mentions("@jo can you check with @AL, then @Jo again"), vec!["JO", "AL"]);
+        assert_eq!(parse_mentions("mail sam@example.com or (@CA) about it"), vec!["CA"]);
+        assert_eq!(parse_mentions("@ alone, @@dd and a trailing @"), vec!["DD"]);
+        assert!(parse_mentions("no mentions here").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn only_new_mentions_of_other_users_are_notified() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        create_user("alice", "AL", "pass123", "member", &pool).await.unwrap();
+
+        // writers are not notified of their own mention and unknown initials are ignored
+        assert_eq!(notify_mentions("@JO @al @ZZ please look", None, 1, None, "JO", &pool).await.unwrap(), 1);
+        assert_eq!(notified(&pool).await, vec![("alice".to_string(), "JO".to_string())]);
+
+        // an edit only notifies people who were not mentioned before
+        assert_eq!(notify_mentions("@AL and now @JO too", Some("@AL please look"), 1, None, "CA", &pool).await.unwrap(), 1);
+        assert_eq!(notify_mentions("@AL and now @JO too", Some("@AL and now @JO too"), 1, None, "CA", &pool).await.unwrap(), 0);
+        assert_eq!(notified(&pool).await[1..], [("jordan".to_string(), "CA".to_string())]);
+    }
+
+    #[actix_rt::test]
+    async fn users_only_mark_their_own_notifications() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        create_user("alice", "AL", "pass123", "member", &pool).await.unwrap();
+        notify_mentions("@JO @AL", None, 1, None, "CA", &pool).await.unwrap();
+        notify_mentions("@JO again", None, 2, None, "CA", &pool).await.unwrap();
+        let ids: Vec<u32> = sqlx::query_scalar("SELECT id FROM notifications ORDER BY id ASC").fetch_all(&pool).await.unwrap();
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/notifications/read", web::post().to(mark_all_notifications_read))
+            .route("/api/notifications/{id}/read", web::post().to(mark_notification_read))).await;
+        let as_alice = |uri: String| {
+            let request = TestRequest::post().uri(&uri).to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: "alice".to_string(), initials: "AL".to_string(), api_key_id: None });
+            request
+        };
+
+        assert_eq!(call_service(&app, as_alice(format!("/api/notifications/{}/read", ids[0]))).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(call_service(&app, as_alice(format!("/api/notifications/{}/read", ids[1]))).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, as_alice("/api/notifications/read".to_string())).await.status(), StatusCode::OK);
+
+        let unread: Vec<String> = sqlx::query_scalar("SELECT username FROM notifications WHERE read_time IS NULL").fetch_all(&pool).await.unwrap();
+        assert_eq!(unread, vec!["jordan", "jordan"]);
+    }
+}
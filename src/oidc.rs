@@ -0,0 +1,861 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web, cookie::{Cookie, SameSite, time::Duration}};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use openssl::{bn::BigNum, ec::{EcGroup, EcKey}, ecdsa::EcdsaSig, hash::MessageDigest, nid::Nid, pkey::{PKey, Public}, rsa::Rsa, sign::Verifier};
+use reqwest::Url;
+use sha2::{Sha256, Digest};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, hash_secret, generate_secret, add_column_if_missing, now};
+use crate::users::{User, UserError, AuthenticatedUser, create_external_user, get_user_by_username};
+use crate::sessions::start_session;
+use crate::totp::{TotpError, totp_enabled, create_login_challenge};
+use crate::roles::DEFAULT_ROLE;
+
+// how long a user has to finish signing in at the identity provider
+const LOGIN_STATE_LIFETIME: u32 = 60 * 10;
+// holds a hash of the login state so only the browser that started a login can finish it
+const STATE_COOKIE: &str = "oidc_state";
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+    #[error(transparent)]
+    UrlError(#[from] url::ParseError),
+    #[error(transparent)]
+    JSONError(#[from] serde_json::Error),
+    #[error(transparent)]
+    DecodeError(#[from] base64::DecodeError),
+    #[error(transparent)]
+    SslError(#[from] openssl::error::ErrorStack),
+    #[error(transparent)]
+    UserError(#[from] UserError),
+    #[error(transparent)]
+    TotpError(#[from] TotpError),
+    #[error("single sign-on is not configured")]
+    Disabled,
+    #[error("login state is invalid or expired")]
+    InvalidState,
+    #[error("identity provider returned an invalid id token - {0}")]
+    InvalidToken(String),
+    #[error("no user is linked to this identity")]
+    UnknownUser,
+    #[error("the identity provider has not verified this email address")]
+    UnverifiedEmail,
+    #[error("a user with this username already exists, sign in with its password to link single sign-on")]
+    LinkRequired,
+    #[error("this identity is already linked to another user")]
+    AlreadyLinked
+}
+
+/// Settings for the optional OpenID Connect login, read from the environment.
+///
+/// SSO is enabled when `OIDC_ISSUER_URL` is set, along with `OIDC_CLIENT_ID` and
+/// `OIDC_REDIRECT_URL` (the public url of `/api/oidc/callback`). `OIDC_CLIENT_SECRET` is
+/// optional for public clients. With `OIDC_CREATE_USERS=true` someone signing in for the first
+/// time gets a user named by the id token claim in `OIDC_USERNAME_CLAIM` (default `email`,
+/// which the provider must have verified). Existing users are never matched by that claim,
+/// they link their identity with `POST /api/me/oidc` while signed in.
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub username_claim: String,
+    pub create_users: bool
+}
+
+impl OidcConfig {
+    pub fn from_env() -> Option<OidcConfig> {
+        let issuer_url = std::env::var("OIDC_ISSUER_URL").ok()?;
+        Some(OidcConfig {
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            client_id: std::env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID environment variable is not set"),
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: std::env::var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL environment variable is not set"),
+            username_claim: std::env::var("OIDC_USERNAME_CLAIM").unwrap_or_else(|_| "email".to_string()),
+            create_users: std::env::var("OIDC_CREATE_USERS").map(|value| value == "true").unwrap_or(false)
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String
+}
+
+#[derive(Deserialize, Debug)]
+struct IdTokenHeader {
+    alg: String,
+    kid: Option<String>
+}
+
+// a provider signing key, only the members used by RS256 and ES256 are read
+#[derive(Deserialize, Debug)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct JwkSet {
+    keys: Vec<Jwk>
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>)
+}
+
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    azp: Option<String>,
+    nonce: Option<String>,
+    name: Option<String>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>
+}
+
+impl IdTokenClaims {
+    // some providers send the flag as a string
+    fn email_verified(&self) -> bool {
+        match self.other.get("email_verified") {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct OidcLogin {
+    state: String,
+    code_verifier: String,
+    nonce: String,
+    created_time: u32,
+    // set when a signed in user is linking their identity rather than signing in
+    link_username: Option<String>
+}
+
+pub async fn create_oidc_tables(pool: &SqlitePool) -> Result<(), OidcError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS oidc_logins (state TEXT PRIMARY KEY, code_verifier TEXT NOT NULL, nonce TEXT NOT NULL, created_time INTEGER, link_username TEXT)")
+        .execute(pool).await?;
+    add_column_if_missing(pool, "oidc_logins", "link_username", "TEXT").await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS oidc_identities (id INTEGER PRIMARY KEY AUTOINCREMENT, issuer TEXT NOT NULL, subject TEXT NOT NULL, username TEXT NOT NULL, UNIQUE(issuer, subject))")
+        .execute(pool).await?;
+    Ok(())
+}
+
+async fn discover(config: &OidcConfig) -> Result<ProviderMetadata, OidcError> {
+    let metadata: ProviderMetadata = reqwest::get(format!("{}/.well-known/openid-configuration", config.issuer_url))
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(metadata)
+}
+
+async fn fetch_signing_keys(metadata: &ProviderMetadata) -> Result<JwkSet, OidcError> {
+    let keys: JwkSet = reqwest::get(&metadata.jwks_uri)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(keys)
+}
+
+// the cookie is sent on the provider's redirect back to the callback, which SameSite=Strict would block
+fn state_cookie(value: String, max_age: u32) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, value)
+        .path("/api/oidc")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(max_age as i64))
+        .finish()
+}
+
+fn clear_state_cookie() -> Cookie<'static> {
+    let mut cookie = state_cookie(String::new(), 0);
+    cookie.make_removal();
+    cookie
+}
+
+// records the login state and returns the provider url to send the browser to, along with the cookie tying the login to that browser
+async fn start_login(config: &OidcConfig, link_username: Option<&str>, pool: &SqlitePool) -> Result<(Url, Cookie<'static>), OidcError> {
+    let metadata = discover(config).await?;
+
+    let state = generate_secret();
+    let nonce = generate_secret();
+    let code_verifier = generate_secret();
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    let time = now()?;
+    sqlx::query("DELETE FROM oidc_logins WHERE created_time < $1")
+        .bind(time.saturating_sub(LOGIN_STATE_LIFETIME))
+        .execute(pool).await?;
+    sqlx::query("INSERT INTO oidc_logins (state, code_verifier, nonce, created_time, link_username) values ($1, $2, $3, $4, $5)")
+        .bind(&state)
+        .bind(&code_verifier)
+        .bind(&nonce)
+        .bind(time)
+        .bind(link_username)
+        .execute(pool).await?;
+
+    let url = Url::parse_with_params(&metadata.authorization_endpoint, &[
+        ("response_type", "code"),
+        ("client_id", config.client_id.as_str()),
+        ("redirect_uri", config.redirect_url.as_str()),
+        ("scope", "openid email profile"),
+        ("state", state.as_str()),
+        ("nonce", nonce.as_str()),
+        ("code_challenge", code_challenge.as_str()),
+        ("code_challenge_method", "S256")
+    ])?;
+    Ok((url, state_cookie(hash_secret(&state), LOGIN_STATE_LIFETIME)))
+}
+
+pub async fn oidc_login(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let config = data.oidc.as_ref().ok_or(OidcError::Disabled)?;
+    let (url, cookie) = start_login(config, None, &data.db_pool).await?;
+
+    Ok(HttpResponse::Found().insert_header(("Location", url.as_str())).cookie(cookie).finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OidcLinkResponse {
+    authorization_url: String
+}
+
+// the dashboard sends the browser to the returned url, the callback then links whoever signs in there to this user
+pub async fn link_oidc_identity(
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let config = data.oidc.as_ref().ok_or(OidcError::Disabled)?;
+    let (url, cookie) = start_login(config, Some(&auth.username), &data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().cookie(cookie).json(OidcLinkResponse { authorization_url: url.to_string() }))
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    code: String,
+    state: String
+}
+
+fn invalid_token(reason: &str) -> OidcError {
+    OidcError::InvalidToken(reason.to_string())
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, OidcError> {
+    Ok(URL_SAFE_NO_PAD.decode(segment.trim_end_matches('='))?)
+}
+
+fn key_component(component: &Option<String>) -> Result<BigNum, OidcError> {
+    let component = component.as_deref().ok_or_else(|| invalid_token("incomplete signing key"))?;
+    Ok(BigNum::from_slice(&decode_segment(component)?)?)
+}
+
+impl Jwk {
+    // None when the key can't check signatures made with this algorithm
+    fn public_key(&self, alg: &str) -> Result<Option<PKey<Public>>, OidcError> {
+        if self.key_use.as_deref().is_some_and(|key_use| key_use != "sig") {
+            return Ok(None);
+        }
+        match (alg, self.kty.as_str(), self.crv.as_deref()) {
+            ("RS256", "RSA", _) => {
+                let key = Rsa::from_public_components(key_component(&self.n)?, key_component(&self.e)?)?;
+                Ok(Some(PKey::from_rsa(key)?))
+            },
+            ("ES256", "EC", Some("P-256")) => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                let (x, y) = (key_component(&self.x)?, key_component(&self.y)?);
+                let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+                Ok(Some(PKey::from_ec_key(key)?))
+            },
+            _ => Ok(None)
+        }
+    }
+}
+
+// checks the token was signed by one of the provider's published keys, the key id picks the key
+// when the token has one, otherwise every key that fits the algorithm is tried
+fn verify_signature(id_token: &str, keys: &JwkSet) -> Result<(), OidcError> {
+    let segments: Vec<&str> = id_token.split('.').collect();
+    let (header, payload, signature) = match segments[..] {
+        [header, payload, signature] => (header, payload, signature),
+        _ => return Err(invalid_token("malformed token"))
+    };
+    let header_claims: IdTokenHeader = serde_json::from_slice(&decode_segment(header)?)?;
+    let signature = decode_segment(signature)?;
+    let signature = match header_claims.alg.as_str() {
+        "RS256" => signature,
+        // JWS carries ES256 signatures as the raw r and s values, openssl wants them DER encoded
+        "ES256" if signature.len() == 64 => {
+            let r = BigNum::from_slice(&signature[..32])?;
+            let s = BigNum::from_slice(&signature[32..])?;
+            EcdsaSig::from_private_components(r, s)?.to_der()?
+        },
+        "ES256" => return Err(invalid_token("signature mismatch")),
+        alg => return Err(OidcError::InvalidToken(format!("unsupported signing algorithm {}", alg)))
+    };
+
+    let signed = format!("{}.{}", header, payload);
+    let candidates = keys.keys.iter().filter(|key| header_claims.kid.is_none() || key.kid == header_claims.kid);
+    for key in candidates {
+        if let Some(public_key) = key.public_key(&header_claims.alg)? {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+            verifier.update(signed.as_bytes())?;
+            // openssl reports some mismatches, like a signature of the wrong length, as errors
+            if verifier.verify(&signature).unwrap_or(false) {
+                return Ok(());
+            }
+        }
+    }
+    Err(invalid_token("signature mismatch"))
+}
+
+fn decode_id_token(id_token: &str, config: &OidcConfig, metadata: &ProviderMetadata, keys: &JwkSet, nonce: &str) -> Result<IdTokenClaims, OidcError> {
+    verify_signature(id_token, keys)?;
+    let payload = id_token.split('.').nth(1).ok_or_else(|| invalid_token("malformed token"))?;
+    let claims: IdTokenClaims = serde_json::from_slice(&decode_segment(payload)?)?;
+
+    // discovery is fetched from the configured issuer, but only the configured issuer is trusted to have signed in the user
+    if claims.iss != metadata.issuer || claims.iss.trim_end_matches('/') != config.issuer_url {
+        return Err(invalid_token("issuer mismatch"));
+    }
+    let audience_matches = match &claims.aud {
+        Audience::Single(aud) => aud == &config.client_id,
+        Audience::Multiple(auds) => auds.contains(&config.client_id)
+    };
+    if !audience_matches {
+        return Err(invalid_token("audience mismatch"));
+    }
+    // a token for several audiences has to name us as the party it was issued to
+    match (&claims.azp, &claims.aud) {
+        (Some(azp), _) if azp != &config.client_id => return Err(invalid_token("authorized party mismatch")),
+        (None, Audience::Multiple(auds)) if auds.len() > 1 => return Err(invalid_token("authorized party missing")),
+        _ => ()
+    }
+    if claims.exp < now()? as u64 {
+        return Err(invalid_token("token expired"));
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(invalid_token("nonce mismatch"));
+    }
+    Ok(claims)
+}
+
+async fn find_or_create_user(claims: &IdTokenClaims, config: &OidcConfig, pool: &SqlitePool) -> Result<User, OidcError> {
+    let linked: Option<String> = sqlx::query_scalar("SELECT username FROM oidc_identities WHERE issuer = $1 AND subject = $2")
+        .bind(&claims.iss)
+        .bind(&claims.sub)
+        .fetch_optional(pool).await?;
+    if let Some(username) = linked {
+        return get_user_by_username(&username, pool).await.map_err(|_| OidcError::UnknownUser);
+    }
+    if !config.create_users {
+        return Err(OidcError::UnknownUser);
+    }
+
+    let username = match config.username_claim.as_str() {
+        "sub" => claims.sub.clone(),
+        claim => match claims.other.get(claim).and_then(|value| value.as_str()) {
+            Some(value) => value.to_string(),
+            None => return Err(OidcError::InvalidToken(format!("missing {} claim", claim)))
+        }
+    };
+    if config.username_claim == "email" && !claims.email_verified() {
+        return Err(OidcError::UnverifiedEmail);
+    }
+
+    // matching claims aren't proof of owning an existing account, its owner has to link it themselves
+    let user = match get_user_by_username(&username, pool).await {
+        Ok(_) => return Err(OidcError::LinkRequired),
+        Err(UserError::NotFound) => create_external_user(&username, None, claims.name.as_deref(), DEFAULT_ROLE, pool).await?,
+        Err(e) => return Err(e.into())
+    };
+    link_identity(claims, &user.username, pool).await?;
+
+    Ok(user)
+}
+
+async fn link_identity(claims: &IdTokenClaims, username: &str, pool: &SqlitePool) -> Result<(), OidcError> {
+    let linked: Option<String> = sqlx::query_scalar("INSERT INTO oidc_identities (issuer, subject, username) values ($1, $2, $3) ON CONFLICT(issuer, subject) DO UPDATE SET username = username RETURNING username")
+        .bind(&claims.iss)
+        .bind(&claims.sub)
+        .bind(username)
+        .fetch_optional(pool).await?;
+    match linked {
+        Some(linked) if linked == username => Ok(()),
+        _ => Err(OidcError::AlreadyLinked)
+    }
+}
+
+pub async fn oidc_callback(
+    req: HttpRequest,
+    callback: web::Query<OidcCallback>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let config = data.oidc.as_ref().ok_or(OidcError::Disabled)?;
+    // a state handed to someone else's browser, for login csrf or to link the wrong identity, doesn't come with the cookie
+    if req.cookie(STATE_COOKIE).map(|cookie| cookie.value().to_string()) != Some(hash_secret(&callback.state)) {
+        return Err(OidcError::InvalidState.into());
+    }
+
+    let query = sqlx::query_as::<_, OidcLogin>("DELETE FROM oidc_logins WHERE state = $1 RETURNING *").bind(&callback.state);
+    let login: OidcLogin = match query.fetch_optional(&data.db_pool).await? {
+        Some(login) if login.created_time + LOGIN_STATE_LIFETIME >= now()? => login,
+        _ => return Err(OidcError::InvalidState.into())
+    };
+
+    let metadata = discover(config).await?;
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", callback.code.as_str()),
+        ("redirect_uri", config.redirect_url.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", login.code_verifier.as_str())
+    ];
+    if let Some(client_secret) = &config.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+    let tokens: TokenResponse = reqwest::Client::new()
+        .post(&metadata.token_endpoint)
+        .form(&form)
+        .send()
+        .await.map_err(OidcError::from)?
+        .error_for_status().map_err(OidcError::from)?
+        .json()
+        .await.map_err(OidcError::from)?;
+
+    let keys = fetch_signing_keys(&metadata).await?;
+    let claims = decode_id_token(&tokens.id_token, config, &metadata, &keys, &login.nonce)?;
+    if let Some(username) = &login.link_username {
+        link_identity(&claims, username, &data.db_pool).await?;
+        return Ok(HttpResponse::Found().insert_header(("Location", "/")).cookie(clear_state_cookie()).finish());
+    }
+    let user = find_or_create_user(&claims, config, &data.db_pool).await?;
+
+    // single sign-on stands in for the password, users with two-factor authentication still have to give a code.
+    // the challenge goes in the fragment so it never reaches the server logs
+    if totp_enabled(&user.username, &data.db_pool).await? {
+        let challenge = create_login_challenge(&user.username, &data.db_pool).await?;
+        return Ok(HttpResponse::Found()
+            .insert_header(("Location", format!("/login/#totp_challenge={}", challenge.challenge_token)))
+            .cookie(clear_state_cookie())
+            .finish());
+    }
+
+    // the dashboard uses the same session cookies as a password login
+    let session = start_session(&user.username, &data.db_pool).await?;
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", "/"))
+        .cookie(session.session_cookie())
+        .cookie(session.csrf_cookie())
+        .cookie(clear_state_cookie())
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer, dev::ServiceResponse, http::StatusCode, test};
+    use openssl::{bn::BigNumContext, pkey::Private, sign::Signer};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+    use crate::{test_pool, test_state};
+    use crate::users::create_user;
+
+    const CLIENT_ID: &str = "work-dash";
+
+    struct StubProvider {
+        issuer: String,
+        rsa_key: PKey<Private>,
+        ec_key: PKey<Private>,
+        // what the token endpoint hands back on the next exchange
+        id_token: web::Data<Mutex<String>>
+    }
+
+    fn encode_json(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+    }
+
+    impl StubProvider {
+        // serves discovery, the key set and a token endpoint from a local port
+        async fn start() -> StubProvider {
+            let rsa_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            let ec_key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+            let rsa = rsa_key.rsa().unwrap();
+            let ec = ec_key.ec_key().unwrap();
+            let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+            ec.public_key().affine_coordinates(&group, &mut x, &mut y, &mut BigNumContext::new().unwrap()).unwrap();
+            let keys = json!({ "keys": [
+                { "kty": "RSA", "kid": "rsa", "use": "sig", "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()), "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()) },
+                { "kty": "EC", "kid": "ec", "crv": "P-256", "x": URL_SAFE_NO_PAD.encode(x.to_vec_padded(32).unwrap()), "y": URL_SAFE_NO_PAD.encode(y.to_vec_padded(32).unwrap()) }
+            ]});
+
+            let id_token = web::Data::new(Mutex::new(String::new()));
+            let served_token = id_token.clone();
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let metadata = json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer)
+            });
+            let server = HttpServer::new(move || {
+                let metadata = metadata.clone();
+                let keys = keys.clone();
+                App::new()
+                    .app_data(served_token.clone())
+                    .route("/.well-known/openid-configuration", web::get().to(move || { let metadata = metadata.clone(); async move { HttpResponse::Ok().json(metadata) } }))
+                    .route("/jwks", web::get().to(move || { let keys = keys.clone(); async move { HttpResponse::Ok().json(keys) } }))
+                    .route("/token", web::post().to(|token: web::Data<Mutex<String>>| async move {
+                        HttpResponse::Ok().json(json!({ "id_token": *token.lock().unwrap() }))
+                    }))
+            }).workers(1).listen(listener).unwrap().run();
+            actix_rt::spawn(server);
+
+            StubProvider { issuer, rsa_key, ec_key, id_token }
+        }
+
+        fn claims(&self, nonce: &str) -> Value {
+            json!({
+                "iss": self.issuer,
+                "sub": "subject-1",
+                "aud": CLIENT_ID,
+                "exp": now().unwrap() + 300,
+                "nonce": nonce,
+                "name": "Sam Smith",
+                "email": "sam@example.com",
+                "email_verified": true
+            })
+        }
+
+        fn sign_rs256(&self, claims: &Value, key: &PKey<Private>) -> String {
+            let signed = format!("{}.{}", encode_json(&json!({ "alg": "RS256", "kid": "rsa" })), encode_json(claims));
+            let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+            signer.update(signed.as_bytes()).unwrap();
+            format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap()))
+        }
+
+        fn sign_es256(&self, claims: &Value) -> String {
+            let signed = format!("{}.{}", encode_json(&json!({ "alg": "ES256", "kid": "ec" })), encode_json(claims));
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.ec_key).unwrap();
+            signer.update(signed.as_bytes()).unwrap();
+            let signature = EcdsaSig::from_der(&signer.sign_to_vec().unwrap()).unwrap();
+            let mut raw = signature.r().to_vec_padded(32).unwrap();
+            raw.extend(signature.s().to_vec_padded(32).unwrap());
+            format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(raw))
+        }
+
+        fn config(&self, create_users: bool) -> OidcConfig {
+            OidcConfig {
+                issuer_url: self.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: None,
+                redirect_url: "https://dash.example.com/api/oidc/callback".to_string(),
+                username_claim: "email".to_string(),
+                create_users
+            }
+        }
+    }
+
+    fn app_state(config: OidcConfig, pool: &SqlitePool) -> web::Data<AppState> {
+        web::Data::new(AppState {
+            oidc: Some(config),
+            ..test_state(pool)
+        })
+    }
+
+    // how the browser comes back from the provider, with or without the state cookie it was given
+    enum Browser {
+        Same,
+        Other
+    }
+
+    // runs /api/oidc/login and /api/oidc/callback, making the provider return whatever token build_token signs for the login's nonce
+    async fn sign_in_as(
+        provider: &StubProvider,
+        config: OidcConfig,
+        pool: &SqlitePool,
+        link_username: Option<&str>,
+        browser: Browser,
+        build_token: impl Fn(&str) -> String
+    ) -> ServiceResponse {
+        let app = test::init_service(App::new()
+            .app_data(app_state(config.clone(), pool))
+            .route("/api/oidc/login", web::get().to(oidc_login))
+            .route("/api/oidc/callback", web::get().to(oidc_callback))).await;
+
+        let (authorization_url, cookie) = match link_username {
+            Some(username) => start_login(&config, Some(username), pool).await.unwrap(),
+            None => {
+                let response = test::call_service(&app, test::TestRequest::get().uri("/api/oidc/login").to_request()).await;
+                assert_eq!(response.status(), StatusCode::FOUND);
+                let cookie = response.response().cookies().find(|cookie| cookie.name() == STATE_COOKIE).unwrap().into_owned();
+                (Url::parse(response.headers().get("Location").unwrap().to_str().unwrap()).unwrap(), cookie)
+            }
+        };
+        let param = |name: &str| authorization_url.query_pairs().find(|(key, _)| key == name).unwrap().1.to_string();
+        *provider.id_token.lock().unwrap() = build_token(&param("nonce"));
+
+        let callback = test::TestRequest::get().uri(&format!("/api/oidc/callback?code=abc&state={}", param("state")));
+        let callback = match browser {
+            Browser::Same => callback.cookie(cookie),
+            Browser::Other => callback
+        };
+        test::call_service(&app, callback.to_request()).await
+    }
+
+    async fn sign_in(
+        provider: &StubProvider,
+        config: OidcConfig,
+        pool: &SqlitePool,
+        link_username: Option<&str>,
+        build_token: impl Fn(&str) -> String
+    ) -> StatusCode {
+        sign_in_as(provider, config, pool, link_username, Browser::Same, build_token).await.status()
+    }
+
+    async fn user_count(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn first_login_provisions_and_links_user() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::FOUND);
+        let user = get_user_by_username("sam@example.com", &pool).await.unwrap();
+        assert_eq!(user.initials, "SS");
+
+        // the second sign in finds the linked identity instead of creating another user
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| provider.sign_es256(&provider.claims(nonce))).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(user_count(&pool).await, 1);
+    }
+
+    #[actix_rt::test]
+    async fn unknown_identity_without_provisioning_is_refused() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+
+        let status = sign_in(&provider, provider.config(false), &pool, None, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(user_count(&pool).await, 0);
+    }
+
+    #[actix_rt::test]
+    async fn bad_signature_is_rejected() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+        let other_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| provider.sign_rs256(&provider.claims(nonce), &other_key)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // an unsigned token can't pick its own algorithm
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| {
+            format!("{}.{}.", encode_json(&json!({ "alg": "none" })), encode_json(&provider.claims(nonce)))
+        }).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(user_count(&pool).await, 0);
+    }
+
+    #[actix_rt::test]
+    async fn wrong_nonce_is_rejected() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |_| provider.sign_rs256(&provider.claims("replayed"), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn wrong_audience_or_issuer_is_rejected() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+        let with = |nonce: &str, name: &str, value: Value| {
+            let mut claims = provider.claims(nonce);
+            claims[name] = value;
+            provider.sign_rs256(&claims, &provider.rsa_key)
+        };
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| with(nonce, "aud", json!("someone-else"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| with(nonce, "iss", json!("https://evil.example.com"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // several audiences need azp to name us
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| with(nonce, "aud", json!([CLIENT_ID, "someone-else"]))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| {
+            let mut claims = provider.claims(nonce);
+            claims["aud"] = json!([CLIENT_ID, "someone-else"]);
+            claims["azp"] = json!("someone-else");
+            provider.sign_rs256(&claims, &provider.rsa_key)
+        }).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(user_count(&pool).await, 0);
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| {
+            let mut claims = provider.claims(nonce);
+            claims["aud"] = json!([CLIENT_ID, "someone-else"]);
+            claims["azp"] = json!(CLIENT_ID);
+            provider.sign_rs256(&claims, &provider.rsa_key)
+        }).await;
+        assert_eq!(status, StatusCode::FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn expired_token_is_rejected() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| {
+            let mut claims = provider.claims(nonce);
+            claims["exp"] = json!(now().unwrap() - 60);
+            provider.sign_rs256(&claims, &provider.rsa_key)
+        }).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn unverified_email_is_not_provisioned() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+
+        for verified in [json!(false), json!("false"), Value::Null] {
+            let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| {
+                let mut claims = provider.claims(nonce);
+                claims["email_verified"] = verified.clone();
+                provider.sign_rs256(&claims, &provider.rsa_key)
+            }).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        assert_eq!(user_count(&pool).await, 0);
+    }
+
+    #[actix_rt::test]
+    async fn existing_user_is_only_linked_on_request() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+        create_user("sam@example.com", "SAM", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let status = sign_in(&provider, provider.config(true), &pool, Some("sam@example.com"), |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::FOUND);
+        let status = sign_in(&provider, provider.config(true), &pool, None, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::FOUND);
+
+        // the identity can't then be linked to a second user
+        create_user("other", "OTH", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+        let status = sign_in(&provider, provider.config(true), &pool, Some("other"), |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn callback_needs_the_browser_that_started_the_login() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+
+        let response = sign_in_as(&provider, provider.config(true), &pool, None, Browser::Other, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(user_count(&pool).await, 0);
+
+        // a link started by one user can't be finished in someone else's browser
+        create_user("attacker", "ATT", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+        let response = sign_in_as(&provider, provider.config(true), &pool, Some("attacker"), Browser::Other, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let linked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oidc_identities").fetch_one(&pool).await.unwrap();
+        assert_eq!(linked, 0);
+
+        let response = sign_in_as(&provider, provider.config(true), &pool, None, Browser::Same, |nonce| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key)).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert!(response.response().cookies().any(|cookie| cookie.name() == STATE_COOKIE && cookie.value().is_empty()));
+    }
+
+    #[actix_rt::test]
+    async fn two_factor_users_get_a_challenge_instead_of_a_session() {
+        let provider = StubProvider::start().await;
+        let pool = test_pool().await;
+        create_user("sam@example.com", "SAM", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+        sqlx::query("INSERT INTO totp_credentials (username, secret, enabled, created_time) values ($1, $2, $3, 0)")
+            .bind("sam@example.com")
+            .bind("JBSWY3DPEHPK3PXP")
+            .bind(true)
+            .execute(&pool).await.unwrap();
+        let claims = |nonce: &str| provider.sign_rs256(&provider.claims(nonce), &provider.rsa_key);
+        assert_eq!(sign_in(&provider, provider.config(true), &pool, Some("sam@example.com"), claims).await, StatusCode::FOUND);
+
+        let response = sign_in_as(&provider, provider.config(true), &pool, None, Browser::Same, claims).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = response.headers().get("Location").unwrap().to_str().unwrap();
+        let challenge = location.strip_prefix("/login/#totp_challenge=").unwrap();
+        let challenges: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM login_challenges WHERE username = $1 AND challenge_hash = $2")
+            .bind("sam@example.com")
+            .bind(hash_secret(challenge))
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(challenges, 1);
+        assert!(!response.response().cookies().any(|cookie| cookie.name() == crate::sessions::SESSION_COOKIE));
+    }
+
+    #[actix_rt::test]
+    async fn issuer_has_to_be_the_configured_one() {
+        let provider = StubProvider::start().await;
+        let config = provider.config(true);
+        // a provider whose discovery document names some other issuer
+        let metadata = ProviderMetadata {
+            issuer: "https://other.example.com".to_string(),
+            authorization_endpoint: format!("{}/authorize", provider.issuer),
+            token_endpoint: format!("{}/token", provider.issuer),
+            jwks_uri: format!("{}/jwks", provider.issuer)
+        };
+        let keys = fetch_signing_keys(&metadata).await.unwrap();
+        let mut claims = provider.claims("nonce");
+        claims["iss"] = json!("https://other.example.com");
+        let token = provider.sign_rs256(&claims, &provider.rsa_key);
+        assert!(matches!(decode_id_token(&token, &config, &metadata, &keys, "nonce"), Err(OidcError::InvalidToken(_))));
+
+        let metadata = ProviderMetadata { issuer: provider.issuer.clone(), ..metadata };
+        let token = provider.sign_rs256(&provider.claims("nonce"), &provider.rsa_key);
+        assert!(decode_id_token(&token, &config, &metadata, &keys, "nonce").is_ok());
+    }
+}
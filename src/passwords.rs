@@ -0,0 +1,270 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::{SystemTime, SystemTimeError};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, hash_secret, generate_secret};
+use crate::audit::AuditChange;
+use crate::users::{User, AuthenticatedUser, get_user, get_user_by_username, reset_password, verify_password};
+
+const RESET_TOKEN_LIFETIME: u32 = 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum PasswordError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("password cannot be empty")]
+    EmptyPassword,
+    #[error("current password is incorrect")]
+    IncorrectPassword,
+    #[error("reset token is invalid or expired")]
+    InvalidResetToken
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct PasswordReset {
+    id: u32,
+    username: String,
+    token_hash: String,
+    created_time: u32,
+    expires_time: u32,
+    used: bool
+}
+
+pub async fn create_password_reset_table(pool: &SqlitePool) -> Result<(), PasswordError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS password_resets (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT NOT NULL, token_hash TEXT UNIQUE NOT NULL, created_time INTEGER, expires_time INTEGER, used INTEGER)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChangePassword {
+    old_password: String,
+    new_password: String
+}
+
+pub async fn change_own_password(
+    req: HttpRequest,
+    change: web::Json<ChangePassword>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if change.new_password.is_empty() {
+        return Err(PasswordError::EmptyPassword.into());
+    }
+
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1").bind(&auth.username);
+    let user: User = query.fetch_one(&data.db_pool).await?;
+    if !verify_password(&user, &change.old_password)? {
+        return Err(PasswordError::IncorrectPassword.into());
+    }
+
+    reset_password(&user.username, &change.new_password, &data.db_pool).await?;
+    AuditChange::new("user", user.id).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetResponse {
+    username: String,
+    reset_token: String,
+    expires_time: u32
+}
+
+pub async fn create_password_reset(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let user = get_user(*id, &data.db_pool).await?;
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32;
+    let reset_token = generate_secret();
+    let expires_time = time + RESET_TOKEN_LIFETIME;
+
+    sqlx::query("INSERT INTO password_resets (username, token_hash, created_time, expires_time, used) values ($1, $2, $3, $4, $5)")
+        .bind(&user.username)
+        .bind(hash_secret(&reset_token))
+        .bind(time)
+        .bind(expires_time)
+        .bind(false)
+        .execute(&data.db_pool).await?;
+    AuditChange::new("user", user.id).record(&req);
+
+    Ok(HttpResponse::Ok().json(PasswordResetResponse { username: user.username, reset_token, expires_time }))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompletePasswordReset {
+    reset_token: String,
+    new_password: String
+}
+
+pub async fn complete_password_reset(
+    req: HttpRequest,
+    reset: web::Json<CompletePasswordReset>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    if reset.new_password.is_empty() {
+        return Err(PasswordError::EmptyPassword.into());
+    }
+
+    let query = sqlx::query_as::<_, PasswordReset>("SELECT * FROM password_resets WHERE token_hash = $1").bind(hash_secret(&reset.reset_token));
+    let record: PasswordReset = match query.fetch_optional(&data.db_pool).await? {
+        Some(record) => record,
+        None => return Err(PasswordError::InvalidResetToken.into())
+    };
+
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32;
+    if time > record.expires_time {
+        return Err(PasswordError::InvalidResetToken.into());
+    }
+
+    // reset tokens are single use, only the request that flips used gets to set the password
+    let result = sqlx::query("UPDATE password_resets SET used = $1 WHERE id = $2 AND used = $3")
+        .bind(true)
+        .bind(record.id)
+        .bind(false)
+        .execute(&data.db_pool).await?;
+    if record.used || result.rows_affected() == 0 {
+        return Err(PasswordError::InvalidResetToken.into());
+    }
+
+    reset_password(&record.username, &reset.new_password, &data.db_pool).await?;
+    let user = get_user_by_username(&record.username, &data.db_pool).await?;
+    AuditChange::new("user", user.id).actor(&user.username).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::{StatusCode, header::AUTHORIZATION};
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use crate::{test_pool, test_state};
+    use crate::refresh_tokens::issue_refresh_token;
+    use crate::sessions::start_session;
+    use crate::users::{bearer_auth_validator, create_user, issue_access_token};
+
+    async fn whoami(auth: AuthenticatedUser) -> HttpResponse {
+        HttpResponse::Ok().body(auth.username)
+    }
+
+    macro_rules! app {
+        ($pool:expr) => {
+            init_service(App::new()
+                .app_data(web::Data::new(test_state($pool)))
+                .route("/api/password/reset", web::post().to(complete_password_reset))
+                .service(web::scope("/api")
+                    .wrap(HttpAuthentication::with_fn(bearer_auth_validator))
+                    .route("/me", web::get().to(whoami))
+                    .route("/me/password", web::post().to(change_own_password))
+                    .route("/users/{id}/password-reset", web::post().to(create_password_reset)))).await
+        };
+    }
+
+    async fn access_token(username: &str, pool: &SqlitePool) -> String {
+        let user = get_user_by_username(username, pool).await.unwrap();
+        issue_access_token(&user, &test_state(pool).jwt_keys).unwrap()
+    }
+
+    fn bearer(request: TestRequest, token: &str) -> TestRequest {
+        request.insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+    }
+
+    fn reset(token: &str, password: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/api/password/reset")
+            .set_json(serde_json::json!({ "reset_token": token, "new_password": password }))
+    }
+
+    async fn password_is(username: &str, password: &str, pool: &SqlitePool) -> bool {
+        verify_password(&get_user_by_username(username, pool).await.unwrap(), password).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn changing_your_password_needs_the_old_one() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let app = app!(&pool);
+        let token = access_token("jordan", &pool).await;
+
+        let change = |old: &str, new: &str| bearer(TestRequest::post(), &token)
+            .uri("/api/me/password")
+            .set_json(serde_json::json!({ "old_password": old, "new_password": new }))
+            .to_request();
+        assert_eq!(call_service(&app, change("wrong", "new-password")).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, change("pass123", "")).await.status(), StatusCode::BAD_REQUEST);
+        assert!(password_is("jordan", "pass123", &pool).await);
+
+        assert_eq!(call_service(&app, change("pass123", "new-password")).await.status(), StatusCode::OK);
+        assert!(password_is("jordan", "new-password", &pool).await);
+    }
+
+    #[actix_rt::test]
+    async fn reset_tokens_work_once_and_expire() {
+        let pool = test_pool().await;
+        create_user("carol", "CA", "pass123", "admin", &pool).await.unwrap();
+        let jordan = create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let app = app!(&pool);
+        let admin_token = access_token("carol", &pool).await;
+        let create_reset = || bearer(TestRequest::post(), &admin_token).uri(&format!("/api/users/{}/password-reset", jordan.id)).to_request();
+
+        let first: serde_json::Value = call_and_read_body_json(&app, create_reset()).await;
+        let second: serde_json::Value = call_and_read_body_json(&app, create_reset()).await;
+        let first = first["reset_token"].as_str().unwrap();
+        let second = second["reset_token"].as_str().unwrap();
+
+        assert_eq!(call_service(&app, reset("not-a-token", "reset-password").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, reset(first, "reset-password").to_request()).await.status(), StatusCode::OK);
+        assert!(password_is("jordan", "reset-password", &pool).await);
+        assert_eq!(call_service(&app, reset(first, "another-password").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        // the new password also discards the other token handed out before it
+        assert_eq!(call_service(&app, reset(second, "another-password").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(password_is("jordan", "reset-password", &pool).await);
+
+        let expired: serde_json::Value = call_and_read_body_json(&app, create_reset()).await;
+        sqlx::query("UPDATE password_resets SET expires_time = expires_time - $1")
+            .bind(RESET_TOKEN_LIFETIME + 1)
+            .execute(&pool).await.unwrap();
+        assert_eq!(call_service(&app, reset(expired["reset_token"].as_str().unwrap(), "late-password").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(password_is("jordan", "reset-password", &pool).await);
+    }
+
+    #[actix_rt::test]
+    async fn a_new_password_ends_existing_logins() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let app = app!(&pool);
+        let token = access_token("jordan", &pool).await;
+        issue_refresh_token("jordan", None, &pool).await.unwrap();
+        let session = start_session("jordan", &pool).await.unwrap();
+        let with_session = || TestRequest::get().uri("/api/me").cookie(session.session_cookie()).to_request();
+
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), &token).uri("/api/me").to_request()).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, with_session()).await.status(), StatusCode::OK);
+
+        let change = bearer(TestRequest::post(), &token)
+            .uri("/api/me/password")
+            .set_json(serde_json::json!({ "old_password": "pass123", "new_password": "new-password" }))
+            .to_request();
+        assert_eq!(call_service(&app, change).await.status(), StatusCode::OK);
+
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), &token).uri("/api/me").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, with_session()).await.status(), StatusCode::UNAUTHORIZED);
+        let live: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM refresh_tokens WHERE username = $1 AND revoked = $2")
+            .bind("jordan")
+            .bind(false)
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(live, 0);
+
+        // a token issued in the same second as the change is still accepted
+        let token = access_token("jordan", &pool).await;
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), &token).uri("/api/me").to_request()).await.status(), StatusCode::OK);
+    }
+}
@@ -1,5 +1,5 @@
 use actix_web::Result;
-use actix_web::{Responder, HttpResponse, web};
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
 use sqlx::{SqlitePool};
 use std::time::{SystemTime, Duration, SystemTimeError};
 use std::num::TryFromIntError;
@@ -11,6 +11,7 @@ use thiserror::Error;
 use crate::AppState;
 
 use crate::AppError;
+use crate::audit::AuditChange;
 
 #[derive(Error, Debug)]
 pub enum PingError {
@@ -46,6 +47,7 @@ pub struct NewPing {
     address: String,
 }
 pub async fn create_ping(
+    req: HttpRequest,
     ping: web::Json<NewPing>,
     data: web::Data<AppState>,
 )  -> Result<impl Responder, AppError> {
@@ -56,6 +58,7 @@ pub async fn create_ping(
         .bind(-1);
 
     let row: Ping = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("ping", row.id).after(&row).record(&req);
 
     Ok(HttpResponse::Ok().json(row))
 }
@@ -122,10 +125,10 @@ pub async fn update_ping(
 pub async fn resolve_address(address: &str) -> Result<IpAddr, PingError> {
     let parse_ip: Result<Ipv4Addr, AddrParseError> = address.parse();
     match parse_ip {
-        Ok(ip) => { return Ok(std::net::IpAddr::V4(ip)) },
+        Ok(ip) => { Ok(std::net::IpAddr::V4(ip)) },
         Err(_) => {
-            let ips = lookup_host(&address)?;
-            return Ok(ips[0])
+            let ips = lookup_host(address)?;
+            Ok(ips[0])
         }
     }
 }
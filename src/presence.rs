@@ -0,0 +1,241 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, add_column_if_missing, now};
+use crate::audit::AuditChange;
+use crate::users::AuthenticatedUser;
+
+pub const UNKNOWN_STATUS: &str = "unknown";
+pub const STATUSES: &[&str] = &["unknown", "free", "away", "busy"];
+// a status that runs out can only fall back to one of these
+const REVERT_STATUSES: &[&str] = &["unknown", "free"];
+pub const MANUAL_SOURCE: &str = "manual";
+
+#[derive(Error, Debug)]
+pub enum PresenceError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("unknown status {0}")]
+    InvalidStatus(String),
+    #[error("expiry must be in the future")]
+    InvalidExpiry
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct Presence {
+    id: u32,
+    username: String,
+    status: String,
+    message: Option<String>,
+    set_time: u32,
+    expires_time: Option<u32>,
+    revert_to: String,
+    // manual for statuses set through the api, otherwise the integration that set it
+    source: String
+}
+
+// every user is listed, users who have never set a status show as unknown
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct UserPresence {
+    username: String,
+    initials: String,
+    status: String,
+    message: Option<String>,
+    set_time: Option<u32>,
+    expires_time: Option<u32>,
+    source: Option<String>
+}
+
+pub async fn create_presence_table(pool: &SqlitePool) -> Result<(), PresenceError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS presence (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT UNIQUE NOT NULL, status TEXT NOT NULL, message TEXT, set_time INTEGER, expires_time INTEGER, revert_to TEXT NOT NULL, source TEXT NOT NULL DEFAULT 'manual')")
+        .execute(pool).await?;
+    add_column_if_missing(pool, "presence", "source", "TEXT NOT NULL DEFAULT 'manual'").await?;
+    Ok(())
+}
+
+pub fn is_valid_status(status: &str) -> bool {
+    STATUSES.contains(&status)
+}
+
+// statuses past their expiry fall back to the status chosen when they were set, returns how many did
+pub async fn expire_presence(pool: &SqlitePool) -> Result<u64, PresenceError> {
+    let time = now()?;
+    let result = sqlx::query("UPDATE presence SET status = revert_to, message = NULL, set_time = expires_time, expires_time = NULL WHERE expires_time <= $1")
+        .bind(time)
+        .execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+/// Sets a status on behalf of an integration such as Microsoft Graph.
+///
+/// A status someone set by hand with an expiry is left alone until it runs out, anything else is replaced.
+/// The set time only moves when the status actually changes. Returns true when the status was updated.
+pub async fn set_synced_presence(username: &str, status: &str, source: &str, pool: &SqlitePool) -> Result<bool, PresenceError> {
+    if !is_valid_status(status) {
+        return Err(PresenceError::InvalidStatus(status.to_string()));
+    }
+    let result = sqlx::query("INSERT INTO presence (username, status, message, set_time, expires_time, revert_to, source) values ($1, $2, NULL, $3, NULL, $4, $5) ON CONFLICT(username) DO UPDATE SET status = $2, message = NULL, set_time = $3, expires_time = NULL, revert_to = $4, source = $5 WHERE (presence.source != $6 OR presence.expires_time IS NULL) AND (presence.status != $2 OR presence.source != $5)")
+        .bind(username)
+        .bind(status)
+        .bind(now()?)
+        .bind(UNKNOWN_STATUS)
+        .bind(source)
+        .bind(MANUAL_SOURCE)
+        .execute(pool).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// hands a status set by an integration back to its fallback once the integration no longer has one to report
+pub async fn clear_synced_presence(username: &str, source: &str, pool: &SqlitePool) -> Result<bool, PresenceError> {
+    let result = sqlx::query("UPDATE presence SET status = revert_to, message = NULL, set_time = $1 WHERE username = $2 AND source = $3 AND status != revert_to")
+        .bind(now()?)
+        .bind(username)
+        .bind(source)
+        .execute(pool).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_presence(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    expire_presence(&data.db_pool).await?;
+    let query = sqlx::query_as::<_, UserPresence>("SELECT users.username, users.initials, COALESCE(presence.status, $1) AS status, presence.message, presence.set_time, presence.expires_time, presence.source FROM users LEFT JOIN presence ON presence.username = users.username ORDER BY users.username ASC")
+        .bind(UNKNOWN_STATUS);
+    let rows: Vec<UserPresence> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+fn default_revert_status() -> String {
+    UNKNOWN_STATUS.to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SetPresence {
+    status: String,
+    message: Option<String>,
+    expires_time: Option<u32>,
+    #[serde(default = "default_revert_status")]
+    revert_to: String
+}
+
+pub async fn set_presence(
+    req: HttpRequest,
+    presence: web::Json<SetPresence>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if !is_valid_status(&presence.status) {
+        return Err(PresenceError::InvalidStatus(presence.status.clone()).into());
+    }
+    if !REVERT_STATUSES.contains(&presence.revert_to.as_str()) {
+        return Err(PresenceError::InvalidStatus(presence.revert_to.clone()).into());
+    }
+    let time = now()?;
+    if presence.expires_time.is_some_and(|expires_time| expires_time <= time) {
+        return Err(PresenceError::InvalidExpiry.into());
+    }
+
+    let query = sqlx::query_as::<_, Presence>("SELECT * FROM presence WHERE username = $1").bind(&auth.username);
+    let before: Option<Presence> = query.fetch_optional(&data.db_pool).await?;
+    let query = sqlx::query_as::<_, Presence>("INSERT INTO presence (username, status, message, set_time, expires_time, revert_to, source) values ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT(username) DO UPDATE SET status = $2, message = $3, set_time = $4, expires_time = $5, revert_to = $6, source = $7 RETURNING *")
+        .bind(&auth.username)
+        .bind(&presence.status)
+        .bind(&presence.message)
+        .bind(time)
+        .bind(presence.expires_time)
+        .bind(&presence.revert_to)
+        .bind(MANUAL_SOURCE);
+    let row: Presence = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("presence", &auth.username).before(&before).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpMessage};
+    use actix_web::test::{TestRequest, call_and_read_body_json, init_service};
+    use crate::{test_pool, test_state};
+
+    async fn presence(username: &str, pool: &SqlitePool) -> Presence {
+        sqlx::query_as("SELECT * FROM presence WHERE username = $1").bind(username).fetch_one(pool).await.unwrap()
+    }
+
+    // runs every expiry that has been set, as if its time had come
+    async fn run_out(pool: &SqlitePool) -> u64 {
+        sqlx::query("UPDATE presence SET expires_time = $1 WHERE expires_time IS NOT NULL")
+            .bind(now().unwrap())
+            .execute(pool).await.unwrap();
+        expire_presence(pool).await.unwrap()
+    }
+
+    macro_rules! set_status {
+        ($app:expr, $body:expr) => {{
+            let request = TestRequest::put().uri("/api/presence").set_json($body).to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: "jordan".to_string(), initials: "JO".to_string(), api_key_id: None });
+            let row: Presence = call_and_read_body_json($app, request).await;
+            row
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn statuses_revert_once_they_expire() {
+        let pool = test_pool().await;
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/presence", web::put().to(set_presence))).await;
+        let expires_time = now().unwrap() + 600;
+
+        set_status!(&app, serde_json::json!({ "status": "busy", "message": "in a meeting", "expires_time": expires_time, "revert_to": "free" }));
+        assert_eq!(expire_presence(&pool).await.unwrap(), 0);
+        assert_eq!(presence("jordan", &pool).await.status, "busy");
+
+        assert_eq!(run_out(&pool).await, 1);
+        let reverted = presence("jordan", &pool).await;
+        assert_eq!((reverted.status.as_str(), reverted.message, reverted.expires_time), ("free", None, None));
+        // the fallback counts as set from the moment the old status ran out
+        assert!(reverted.set_time <= now().unwrap() && reverted.set_time >= expires_time - 600);
+
+        // without a fallback the status goes back to unknown, and one without an expiry stays put
+        set_status!(&app, serde_json::json!({ "status": "away", "expires_time": expires_time }));
+        assert_eq!(run_out(&pool).await, 1);
+        assert_eq!(presence("jordan", &pool).await.status, UNKNOWN_STATUS);
+        set_status!(&app, serde_json::json!({ "status": "away" }));
+        assert_eq!(run_out(&pool).await, 0);
+        assert_eq!(presence("jordan", &pool).await.status, "away");
+    }
+
+    #[actix_rt::test]
+    async fn a_status_set_by_hand_wins_until_it_expires() {
+        let pool = test_pool().await;
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/presence", web::put().to(set_presence))).await;
+
+        assert!(set_synced_presence("jordan", "busy", "graph", &pool).await.unwrap());
+        // the same status again is not a change
+        assert!(!set_synced_presence("jordan", "busy", "graph", &pool).await.unwrap());
+
+        let row = set_status!(&app, serde_json::json!({ "status": "away", "expires_time": now().unwrap() + 600, "revert_to": "free" }));
+        assert_eq!(row.source, MANUAL_SOURCE);
+        assert!(!set_synced_presence("jordan", "busy", "graph", &pool).await.unwrap());
+        assert!(!clear_synced_presence("jordan", "graph", &pool).await.unwrap());
+        assert_eq!(presence("jordan", &pool).await.status, "away");
+
+        run_out(&pool).await;
+        assert_eq!(presence("jordan", &pool).await.status, "free");
+        assert!(set_synced_presence("jordan", "busy", "graph", &pool).await.unwrap());
+        let synced = presence("jordan", &pool).await;
+        assert_eq!((synced.status.as_str(), synced.source.as_str()), ("busy", "graph"));
+
+        // a status set by hand without an expiry is replaced by the next sync
+        set_status!(&app, serde_json::json!({ "status": "away" }));
+        assert!(set_synced_presence("jordan", "busy", "graph", &pool).await.unwrap());
+        assert!(clear_synced_presence("jordan", "graph", &pool).await.unwrap());
+        assert_eq!(presence("jordan", &pool).await.status, UNKNOWN_STATUS);
+    }
+}
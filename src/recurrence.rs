@@ -0,0 +1,335 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use std::str::FromStr;
+use thiserror::Error;
+
+// a daily rule started years ago still fits well inside this many periods
+const MAX_PERIODS: i64 = 100_000;
+
+#[derive(Error, Debug)]
+pub enum RecurrenceError {
+    #[error("invalid recurrence rule {0}")]
+    InvalidRule(String)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Until {
+    Utc(NaiveDateTime),
+    Floating(NaiveDateTime)
+}
+
+/// A recurrence rule in the iCalendar RRULE format, e.g. `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE`.
+///
+/// FREQ, INTERVAL, COUNT, UNTIL, BYDAY, BYMONTHDAY and BYMONTH are supported. BYDAY ordinals
+/// like `-1FR` pick a weekday within the month for monthly and yearly rules. Rules using
+/// other parts are rejected rather than expanded incorrectly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecurrenceRule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<Until>,
+    pub by_day: Vec<(Option<i32>, Weekday)>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>
+}
+
+fn invalid(rule: &str) -> RecurrenceError {
+    RecurrenceError::InvalidRule(rule.to_string())
+}
+
+fn parse_weekday(value: &str) -> Option<Weekday> {
+    match value {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None
+    }
+}
+
+// BYDAY entries are a weekday with an optional ordinal in front, e.g. MO, 2TU or -1FR
+fn parse_by_day(value: &str) -> Option<(Option<i32>, Weekday)> {
+    let split = value.len().checked_sub(2)?;
+    let (ordinal, weekday) = value.split_at(split);
+    let ordinal = match ordinal {
+        "" => None,
+        ordinal => Some(ordinal.parse::<i32>().ok().filter(|ordinal| *ordinal != 0 && ordinal.abs() <= 5)?)
+    };
+    Some((ordinal, parse_weekday(weekday)?))
+}
+
+fn parse_until(value: &str) -> Option<Until> {
+    match value.strip_suffix('Z') {
+        Some(value) => NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok().map(Until::Utc),
+        None => NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()
+            // a date on its own includes the whole day
+            .or_else(|| NaiveDate::parse_from_str(value, "%Y%m%d").ok().and_then(|date| date.and_hms_opt(23, 59, 59)))
+            .map(Until::Floating)
+    }
+}
+
+fn parse_list<T>(value: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    value.split(',').map(|item| parse(item.trim())).collect()
+}
+
+impl FromStr for RecurrenceRule {
+    type Err = RecurrenceError;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let mut frequency = None;
+        let mut parsed = RecurrenceRule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new()
+        };
+
+        let parts = rule.trim().trim_start_matches("RRULE:");
+        for part in parts.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part.split_once('=').ok_or_else(|| invalid(rule))?;
+            let value = value.to_uppercase();
+            match key.to_uppercase().as_str() {
+                "FREQ" => frequency = Some(match value.as_str() {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    "YEARLY" => Frequency::Yearly,
+                    _ => return Err(invalid(rule))
+                }),
+                "INTERVAL" => parsed.interval = value.parse().ok().filter(|interval| *interval > 0).ok_or_else(|| invalid(rule))?,
+                "COUNT" => parsed.count = Some(value.parse().map_err(|_| invalid(rule))?),
+                "UNTIL" => parsed.until = Some(parse_until(&value).ok_or_else(|| invalid(rule))?),
+                "BYDAY" => parsed.by_day = parse_list(&value, parse_by_day).ok_or_else(|| invalid(rule))?,
+                "BYMONTHDAY" => parsed.by_month_day = parse_list(&value, |day| day.parse::<i32>().ok().filter(|day| *day != 0 && day.abs() <= 31)).ok_or_else(|| invalid(rule))?,
+                "BYMONTH" => parsed.by_month = parse_list(&value, |month| month.parse::<u32>().ok().filter(|month| (1..=12).contains(month))).ok_or_else(|| invalid(rule))?,
+                // weeks always start on monday here, which is what calendar apps send anyway
+                "WKST" => {},
+                _ => return Err(invalid(rule))
+            }
+        }
+
+        parsed.frequency = frequency.ok_or_else(|| invalid(rule))?;
+        Ok(parsed)
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    match (NaiveDate::from_ymd_opt(next_year, next_month, 1), NaiveDate::from_ymd_opt(year, month, 1)) {
+        (Some(next), Some(first)) => (next - first).num_days() as u32,
+        _ => 0
+    }
+}
+
+impl RecurrenceRule {
+    fn matches_month(&self, date: NaiveDate) -> bool {
+        self.by_month.is_empty() || self.by_month.contains(&date.month())
+    }
+
+    // days of one month picked by BYMONTHDAY and BYDAY, when both are given a day has to match both
+    fn month_days(&self, year: i32, month: u32, start: NaiveDate) -> Vec<NaiveDate> {
+        let length = days_in_month(year, month) as i32;
+        let from_month_day: Vec<i32> = self.by_month_day.iter()
+            .map(|day| if *day > 0 { *day } else { length + day + 1 })
+            .filter(|day| (1..=length).contains(day))
+            .collect();
+
+        let mut from_weekday: Vec<i32> = Vec::new();
+        for (ordinal, weekday) in &self.by_day {
+            let matching: Vec<i32> = (1..=length)
+                .filter(|day| NaiveDate::from_ymd_opt(year, month, *day as u32).is_some_and(|date| date.weekday() == *weekday))
+                .collect();
+            match ordinal {
+                None => from_weekday.extend(matching),
+                Some(ordinal) if *ordinal > 0 => from_weekday.extend(matching.get(*ordinal as usize - 1)),
+                Some(ordinal) => from_weekday.extend(matching.len().checked_sub(ordinal.unsigned_abs() as usize).and_then(|index| matching.get(index)))
+            }
+        }
+
+        let mut days: Vec<i32> = match (self.by_month_day.is_empty(), self.by_day.is_empty()) {
+            (true, true) => vec![start.day() as i32].into_iter().filter(|day| *day <= length).collect(),
+            (false, true) => from_month_day,
+            (true, false) => from_weekday,
+            (false, false) => from_month_day.into_iter().filter(|day| from_weekday.contains(day)).collect()
+        };
+        days.sort();
+        days.dedup();
+        days.into_iter().filter_map(|day| NaiveDate::from_ymd_opt(year, month, day as u32)).collect()
+    }
+
+    // the first day of the given period and the candidate dates inside it
+    fn period_dates(&self, start: NaiveDate, period: i64) -> Option<(NaiveDate, Vec<NaiveDate>)> {
+        let offset = period * self.interval as i64;
+        match self.frequency {
+            Frequency::Daily => {
+                let date = start.checked_add_signed(Duration::days(offset))?;
+                let weekday_matches = self.by_day.is_empty() || self.by_day.iter().any(|(_, weekday)| *weekday == date.weekday());
+                let day_matches = self.by_month_day.is_empty() || self.month_days(date.year(), date.month(), start).contains(&date);
+                let dates = if weekday_matches && day_matches && self.matches_month(date) { vec![date] } else { Vec::new() };
+                Some((date, dates))
+            },
+            Frequency::Weekly => {
+                let week_start = start.checked_sub_signed(Duration::days(start.weekday().num_days_from_monday() as i64))?
+                    .checked_add_signed(Duration::weeks(offset))?;
+                let mut weekdays: Vec<Weekday> = self.by_day.iter().map(|(_, weekday)| *weekday).collect();
+                if weekdays.is_empty() {
+                    weekdays.push(start.weekday());
+                }
+                let mut dates: Vec<NaiveDate> = weekdays.iter()
+                    .filter_map(|weekday| week_start.checked_add_signed(Duration::days(weekday.num_days_from_monday() as i64)))
+                    .filter(|date| self.matches_month(*date))
+                    .collect();
+                dates.sort();
+                dates.dedup();
+                Some((week_start, dates))
+            },
+            Frequency::Monthly => {
+                let months = start.year() as i64 * 12 + start.month0() as i64 + offset;
+                let (year, month) = (i32::try_from(months.div_euclid(12)).ok()?, months.rem_euclid(12) as u32 + 1);
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let dates = if self.matches_month(first) { self.month_days(year, month, start) } else { Vec::new() };
+                Some((first, dates))
+            },
+            Frequency::Yearly => {
+                let year = start.year().checked_add(i32::try_from(offset).ok()?)?;
+                let months = if self.by_month.is_empty() { vec![start.month()] } else { self.by_month.clone() };
+                let mut dates: Vec<NaiveDate> = months.iter().flat_map(|month| self.month_days(year, *month, start)).collect();
+                dates.sort();
+                Some((NaiveDate::from_ymd_opt(year, 1, 1)?, dates))
+            }
+        }
+    }
+
+    /// Every occurrence from `start` up to and including `end`, as local times in the same zone as `start`.
+    ///
+    /// `to_utc` converts a local time so an UNTIL given in UTC can be compared. COUNT is counted from
+    /// `start`, so callers only interested in a later window filter the result themselves.
+    pub fn occurrences(&self, start: NaiveDateTime, end: NaiveDateTime, to_utc: impl Fn(NaiveDateTime) -> NaiveDateTime) -> Vec<NaiveDateTime> {
+        let mut found = Vec::new();
+        let mut count = 0;
+        let time: NaiveTime = start.time();
+
+        for period in 0..MAX_PERIODS {
+            let (period_start, dates) = match self.period_dates(start.date(), period) {
+                Some(period) => period,
+                None => break
+            };
+            if period_start.and_time(time) > end && period_start > start.date() {
+                break;
+            }
+            for date in dates {
+                let occurrence = date.and_time(time);
+                if occurrence < start {
+                    continue;
+                }
+                let past_until = match self.until {
+                    Some(Until::Utc(until)) => to_utc(occurrence) > until,
+                    Some(Until::Floating(until)) => occurrence > until,
+                    None => false
+                };
+                if past_until || occurrence > end || self.count.is_some_and(|limit| count >= limit) {
+                    return found;
+                }
+                count += 1;
+                found.push(occurrence);
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    fn dates(rule: &str, start: NaiveDateTime, end: NaiveDateTime) -> Vec<NaiveDate> {
+        let rule: RecurrenceRule = rule.parse().unwrap();
+        rule.occurrences(start, end, |local| local).into_iter().map(|occurrence| occurrence.date()).collect()
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn by_day_ordinals_pick_a_weekday_in_the_month() {
+        let last_friday = dates("FREQ=MONTHLY;BYDAY=-1FR", at(2024, 1, 26, 10), at(2024, 4, 30, 0));
+        assert_eq!(last_friday, vec![date(2024, 1, 26), date(2024, 2, 23), date(2024, 3, 29), date(2024, 4, 26)]);
+
+        let second_tuesday = dates("FREQ=MONTHLY;BYDAY=2TU", at(2024, 1, 1, 10), at(2024, 3, 31, 0));
+        assert_eq!(second_tuesday, vec![date(2024, 1, 9), date(2024, 2, 13), date(2024, 3, 12)]);
+
+        // thanksgiving, the fourth thursday of november
+        let yearly = dates("FREQ=YEARLY;BYMONTH=11;BYDAY=4TH", at(2023, 1, 1, 10), at(2025, 12, 31, 0));
+        assert_eq!(yearly, vec![date(2023, 11, 23), date(2024, 11, 28), date(2025, 11, 27)]);
+
+        assert!("FREQ=MONTHLY;BYDAY=0FR".parse::<RecurrenceRule>().is_err());
+        assert!("FREQ=MONTHLY;BYDAY=6FR".parse::<RecurrenceRule>().is_err());
+    }
+
+    #[test]
+    fn negative_month_days_count_from_the_end() {
+        let leap_year = dates("FREQ=MONTHLY;BYMONTHDAY=-1", at(2024, 1, 31, 9), at(2024, 4, 30, 23));
+        assert_eq!(leap_year, vec![date(2024, 1, 31), date(2024, 2, 29), date(2024, 3, 31), date(2024, 4, 30)]);
+
+        let common_year = dates("FREQ=MONTHLY;BYMONTHDAY=-1", at(2023, 1, 31, 9), at(2023, 3, 31, 23));
+        assert_eq!(common_year, vec![date(2023, 1, 31), date(2023, 2, 28), date(2023, 3, 31)]);
+
+        let yearly = dates("FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1", at(2023, 2, 28, 9), at(2025, 12, 31, 0));
+        assert_eq!(yearly, vec![date(2023, 2, 28), date(2024, 2, 29), date(2025, 2, 28)]);
+
+        // without BYMONTHDAY the start day is kept and months too short for it are skipped
+        let thirty_first = dates("FREQ=MONTHLY", at(2024, 1, 31, 9), at(2024, 5, 31, 23));
+        assert_eq!(thirty_first, vec![date(2024, 1, 31), date(2024, 3, 31), date(2024, 5, 31)]);
+    }
+
+    #[test]
+    fn count_is_counted_from_the_start() {
+        let count = dates("FREQ=DAILY;COUNT=5", at(2024, 1, 1, 9), at(2024, 12, 31, 0));
+        assert_eq!(count.len(), 5);
+        assert_eq!(count.last(), Some(&date(2024, 1, 5)));
+
+        let weekly = dates("FREQ=WEEKLY;COUNT=4;BYDAY=MO,WE", at(2024, 1, 1, 9), at(2024, 12, 31, 0));
+        assert_eq!(weekly, vec![date(2024, 1, 1), date(2024, 1, 3), date(2024, 1, 8), date(2024, 1, 10)]);
+    }
+
+    #[test]
+    fn until_in_utc_is_compared_in_utc() {
+        // a zone five hours behind utc, so 09:00 local is 14:00 utc
+        let to_utc = |local: NaiveDateTime| local + Duration::hours(5);
+        let occurrences = |rule: &str| rule.parse::<RecurrenceRule>().unwrap().occurrences(at(2024, 1, 1, 9), at(2024, 12, 31, 0), to_utc).len();
+
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240103T140000Z"), 3);
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240103T135959Z"), 2);
+        // a floating until is compared with the local time
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240103T090000"), 3);
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240103T085959"), 2);
+        // and a date on its own includes the whole day
+        assert_eq!(occurrences("FREQ=DAILY;UNTIL=20240103"), 3);
+    }
+
+    #[test]
+    fn unsupported_rules_are_rejected() {
+        for rule in ["FREQ=HOURLY", "FREQ=DAILY;BYSETPOS=1", "FREQ=DAILY;INTERVAL=0", "INTERVAL=2", "FREQ=DAILY;UNTIL=tomorrow"] {
+            assert!(rule.parse::<RecurrenceRule>().is_err(), "{} should be rejected", rule);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::{SystemTime, SystemTimeError};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, hash_secret, generate_secret};
+use crate::audit::AuditChange;
+use crate::users::{User, LoginResponse, issue_access_token};
+
+// refresh tokens slide forward every time they are exchanged
+const REFRESH_TOKEN_LIFETIME: u32 = 60 * 60 * 24 * 30;
+
+#[derive(Error, Debug)]
+pub enum RefreshTokenError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("refresh token is invalid or expired")]
+    Invalid,
+    #[error("refresh token was reused, all tokens in the family have been revoked")]
+    Reused
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct RefreshToken {
+    id: u32,
+    family: String,
+    username: String,
+    token_hash: String,
+    created_time: u32,
+    expires_time: u32,
+    used: bool,
+    revoked: bool
+}
+
+pub async fn create_refresh_token_table(pool: &SqlitePool) -> Result<(), RefreshTokenError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS refresh_tokens (id INTEGER PRIMARY KEY AUTOINCREMENT, family TEXT NOT NULL, username TEXT NOT NULL, token_hash TEXT UNIQUE NOT NULL, created_time INTEGER, expires_time INTEGER, used INTEGER, revoked INTEGER)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+// issues a new refresh token, starting a new family when one is not given
+pub async fn issue_refresh_token(username: &str, family: Option<&str>, pool: &SqlitePool) -> Result<String, RefreshTokenError> {
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32;
+    let token = generate_secret();
+    let family = match family {
+        Some(family) => family.to_string(),
+        None => generate_secret()
+    };
+
+    sqlx::query("INSERT INTO refresh_tokens (family, username, token_hash, created_time, expires_time, used, revoked) values ($1, $2, $3, $4, $5, $6, $7)")
+        .bind(family)
+        .bind(username)
+        .bind(hash_secret(&token))
+        .bind(time)
+        .bind(time + REFRESH_TOKEN_LIFETIME)
+        .bind(false)
+        .bind(false)
+        .execute(pool).await?;
+
+    Ok(token)
+}
+
+pub async fn revoke_refresh_token_family(family: &str, pool: &SqlitePool) -> Result<(), RefreshTokenError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked = $1 WHERE family = $2")
+        .bind(true)
+        .bind(family)
+        .execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String
+}
+
+pub async fn refresh_access_token(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    request: web::Json<RefreshRequest>,
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, RefreshToken>("SELECT * FROM refresh_tokens WHERE token_hash = $1").bind(hash_secret(&request.refresh_token));
+    let record: RefreshToken = match query.fetch_optional(&data.db_pool).await? {
+        Some(record) => record,
+        None => return Err(RefreshTokenError::Invalid.into())
+    };
+
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32;
+    if record.revoked || time > record.expires_time {
+        return Err(RefreshTokenError::Invalid.into());
+    }
+
+    // marking the token used only succeeds once, so a second exchange means the token leaked
+    let result = sqlx::query("UPDATE refresh_tokens SET used = $1 WHERE id = $2 AND used = $3")
+        .bind(true)
+        .bind(record.id)
+        .bind(false)
+        .execute(&data.db_pool).await?;
+    if record.used || result.rows_affected() == 0 {
+        revoke_refresh_token_family(&record.family, &data.db_pool).await?;
+        return Err(RefreshTokenError::Reused.into());
+    }
+
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1").bind(&record.username);
+    let user: User = match query.fetch_optional(&data.db_pool).await? {
+        Some(user) => user,
+        None => {
+            revoke_refresh_token_family(&record.family, &data.db_pool).await?;
+            return Err(RefreshTokenError::Invalid.into());
+        }
+    };
+
+    let token = issue_access_token(&user, &data.jwt_keys)?;
+    let refresh_token = issue_refresh_token(&user.username, Some(&record.family), &data.db_pool).await?;
+    AuditChange::new("user", user.id).actor(&user.username).record(&req);
+
+    Ok(HttpResponse::Ok().json(LoginResponse { token, refresh_token }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service};
+    use crate::{test_pool, test_state};
+    use crate::users::create_user;
+
+    fn refresh(token: &str) -> TestRequest {
+        TestRequest::post()
+            .uri("/api/refresh")
+            .set_json(serde_json::json!({ "refresh_token": token }))
+    }
+
+    #[actix_rt::test]
+    async fn reusing_a_refresh_token_revokes_its_family() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/refresh", web::post().to(refresh_access_token))).await;
+        let first = issue_refresh_token("jordan", None, &pool).await.unwrap();
+        let other_login = issue_refresh_token("jordan", None, &pool).await.unwrap();
+
+        let second: LoginResponse = call_and_read_body_json(&app, refresh(&first).to_request()).await;
+        assert!(!second.token.is_empty());
+        assert_ne!(second.refresh_token, first);
+
+        // the stolen first token is replayed, after which the rotated one is refused too
+        assert_eq!(call_service(&app, refresh(&first).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, refresh(&second.refresh_token).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        // other logins have their own family and keep working
+        assert_eq!(call_service(&app, refresh(&other_login).to_request()).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, refresh("not-a-token").to_request()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn expired_refresh_tokens_are_refused() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/refresh", web::post().to(refresh_access_token))).await;
+        let token = issue_refresh_token("jordan", None, &pool).await.unwrap();
+        sqlx::query("UPDATE refresh_tokens SET expires_time = expires_time - $1")
+            .bind(REFRESH_TOKEN_LIFETIME + 1)
+            .execute(&pool).await.unwrap();
+
+        assert_eq!(call_service(&app, refresh(&token).to_request()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,153 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::audit::AuditChange;
+use crate::users::AuthenticatedUser;
+use crate::reminders::{ReminderError, get_reminder};
+use crate::notifications::{NotificationError, notify_mentions};
+
+pub const COMMENT_KIND: &str = "comment";
+pub const ACKNOWLEDGEMENT_KIND: &str = "acknowledgement";
+const DEFAULT_ACKNOWLEDGEMENT: &str = "done for today";
+
+#[derive(Error, Debug)]
+pub enum ReminderCommentError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    ReminderError(#[from] ReminderError),
+    #[error(transparent)]
+    NotificationError(#[from] NotificationError),
+    #[error("comments cannot be empty")]
+    EmptyComment
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct ReminderComment {
+    id: u32,
+    reminder_id: u32,
+    username: String,
+    initials: String,
+    created_time: u32,
+    // a plain comment, or an acknowledgement that the reminder has been dealt with for now
+    kind: String,
+    comment: String
+}
+
+pub async fn create_reminder_comment_table(pool: &SqlitePool) -> Result<(), ReminderCommentError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS reminder_comments (id INTEGER PRIMARY KEY AUTOINCREMENT, reminder_id INTEGER NOT NULL, username TEXT NOT NULL, initials TEXT NOT NULL, created_time INTEGER, kind TEXT NOT NULL, comment TEXT NOT NULL)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+async fn add_comment(reminder_id: u32, kind: &str, comment: &str, auth: &AuthenticatedUser, pool: &SqlitePool) -> Result<ReminderComment, ReminderCommentError> {
+    get_reminder(reminder_id, pool).await?;
+    let query = sqlx::query_as::<_, ReminderComment>("INSERT INTO reminder_comments (reminder_id, username, initials, created_time, kind, comment) values ($1, $2, $3, $4, $5, $6) RETURNING *")
+        .bind(reminder_id)
+        .bind(&auth.username)
+        .bind(&auth.initials)
+        .bind(now()?)
+        .bind(kind)
+        .bind(comment);
+    let row: ReminderComment = query.fetch_one(pool).await?;
+    notify_mentions(&row.comment, None, reminder_id, Some(row.id), &auth.initials, pool).await?;
+    Ok(row)
+}
+
+pub async fn get_reminder_comments(id: web::Path<u32>, data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    get_reminder(*id, &data.db_pool).await?;
+    let query = sqlx::query_as::<_, ReminderComment>("SELECT * FROM reminder_comments WHERE reminder_id = $1 ORDER BY created_time ASC, id ASC").bind(*id);
+    let rows: Vec<ReminderComment> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewReminderComment {
+    comment: String
+}
+
+pub async fn create_reminder_comment(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    comment: web::Json<NewReminderComment>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let text = comment.comment.trim();
+    if text.is_empty() {
+        return Err(ReminderCommentError::EmptyComment.into());
+    }
+    let row = add_comment(*id, COMMENT_KIND, text, &auth, &data.db_pool).await?;
+    AuditChange::new("reminder_comment", row.id).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewAcknowledgement {
+    comment: Option<String>
+}
+
+// the body is optional, an acknowledgement without a comment reads as "done for today"
+pub async fn acknowledge_reminder(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    acknowledgement: Option<web::Json<NewAcknowledgement>>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let text = acknowledgement.as_ref()
+        .and_then(|acknowledgement| acknowledgement.comment.as_deref())
+        .map(str::trim)
+        .filter(|comment| !comment.is_empty())
+        .unwrap_or(DEFAULT_ACKNOWLEDGEMENT);
+    let row = add_comment(*id, ACKNOWLEDGEMENT_KIND, text, &auth, &data.db_pool).await?;
+    AuditChange::new("reminder_comment", row.id).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpMessage};
+    use actix_web::test::{TestRequest, call_and_read_body_json, init_service};
+    use crate::{test_pool, test_state};
+    use crate::users::create_user;
+
+    #[actix_rt::test]
+    async fn acknowledgements_default_to_done_for_today() {
+        let pool = test_pool().await;
+        create_user("alice", "AL", "pass123", "member", &pool).await.unwrap();
+        let id: u32 = sqlx::query_scalar("INSERT INTO reminders (created_time, active, reminder, user_initials) values (0, 1, 'water the plants', 'AL') RETURNING id")
+            .fetch_one(&pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/reminders/{id}/acknowledge", web::post().to(acknowledge_reminder))).await;
+        let acknowledge = |body: Option<serde_json::Value>| {
+            let request = TestRequest::post().uri(&format!("/api/reminders/{}/acknowledge", id));
+            let request = match body {
+                Some(body) => request.set_json(body),
+                None => request
+            }.to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: "jordan".to_string(), initials: "JO".to_string(), api_key_id: None });
+            request
+        };
+
+        for body in [None, Some(serde_json::json!({})), Some(serde_json::json!({ "comment": "   " }))] {
+            let row: ReminderComment = call_and_read_body_json(&app, acknowledge(body)).await;
+            assert_eq!((row.kind.as_str(), row.comment.as_str()), (ACKNOWLEDGEMENT_KIND, DEFAULT_ACKNOWLEDGEMENT));
+        }
+
+        let row: ReminderComment = call_and_read_body_json(&app, acknowledge(Some(serde_json::json!({ "comment": " @al watered " })))).await;
+        assert_eq!(row.comment, "@al watered");
+        let notified: Vec<String> = sqlx::query_scalar("SELECT username FROM notifications").fetch_all(&pool).await.unwrap();
+        assert_eq!(notified, vec!["alice"]);
+    }
+}
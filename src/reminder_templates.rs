@@ -0,0 +1,402 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use chrono::{DateTime, Duration, Local, TimeZone, Utc};
+use sqlx::{SqlitePool};
+use std::str::FromStr;
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, now};
+use crate::audit::AuditChange;
+use crate::users::AuthenticatedUser;
+use crate::recurrence::RecurrenceRule;
+use crate::reminders::{ReminderError, record_history};
+use crate::notifications::{NotificationError, notify_mentions};
+
+// how far ahead an RRULE is searched for its next occurrence, enough for yearly rules
+const RULE_SEARCH_DAYS: i64 = 400;
+
+#[derive(Error, Debug)]
+pub enum ReminderTemplateError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    ReminderError(#[from] ReminderError),
+    #[error(transparent)]
+    NotificationError(#[from] NotificationError),
+    #[error("invalid schedule {0}")]
+    InvalidSchedule(String),
+    #[error("reminder template not found")]
+    NotFound
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct ReminderTemplate {
+    id: u32,
+    created_time: u32,
+    reminder: String,
+    user_initials: String,
+    // a cron expression or an RRULE, evaluated in the server's time zone
+    schedule: String,
+    // RRULE occurrences take their time of day from here, neither kind runs before it
+    starts_at: u32,
+    // seconds after a reminder is generated that it becomes due and that it expires
+    due_after: Option<u32>,
+    expires_after: Option<u32>,
+    paused: bool,
+    last_run: Option<u32>,
+    // empty once the schedule has no occurrences left
+    next_run: Option<u32>
+}
+
+pub async fn create_reminder_template_table(pool: &SqlitePool) -> Result<(), ReminderTemplateError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS reminder_templates (id INTEGER PRIMARY KEY AUTOINCREMENT, created_time INTEGER, reminder TEXT NOT NULL, user_initials TEXT NOT NULL, schedule TEXT NOT NULL, starts_at INTEGER NOT NULL, due_after INTEGER, expires_after INTEGER, paused INTEGER, last_run INTEGER, next_run INTEGER)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+// the five field form counts weekdays from 0 or 7 for Sunday while the cron crate counts from 1,
+// so numeric days are written out as names and anything else is left for the cron crate to check
+fn cron_weekdays(field: &str) -> String {
+    field.split(',')
+        .map(|item| numeric_weekdays(item).unwrap_or_else(|| item.to_string()))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn numeric_weekdays(item: &str) -> Option<String> {
+    const NAMES: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    let (range, step) = match item.split_once('/') {
+        Some((range, step)) => (range, step.parse::<usize>().ok().filter(|step| *step > 0)?),
+        None => (item, 1)
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?),
+        // a single day with a step runs from that day to the end of the week
+        None => range.parse::<usize>().ok().map(|day| (day, if step > 1 { 7 } else { day }))?
+    };
+    if start > end || end > 7 {
+        return None;
+    }
+    let mut days: Vec<&str> = Vec::new();
+    for day in (start..=end).step_by(step).map(|day| NAMES[day]) {
+        if !days.contains(&day) {
+            days.push(day);
+        }
+    }
+    Some(days.join(","))
+}
+
+enum Schedule {
+    Cron(cron::Schedule),
+    Rule(RecurrenceRule)
+}
+
+impl FromStr for Schedule {
+    type Err = ReminderTemplateError;
+
+    // RRULEs start with FREQ or RRULE:, anything else is treated as cron
+    fn from_str(schedule: &str) -> Result<Self, Self::Err> {
+        let invalid = || ReminderTemplateError::InvalidSchedule(schedule.to_string());
+        let trimmed = schedule.trim();
+        let upper = trimmed.to_uppercase();
+        if upper.starts_with("FREQ=") || upper.starts_with("RRULE:") {
+            return trimmed.parse::<RecurrenceRule>().map(Schedule::Rule).map_err(|_| invalid());
+        }
+        // the cron crate wants a seconds field, the usual five field form runs on the minute
+        let fields: Vec<&str> = trimmed.split_whitespace().collect();
+        let expression = match fields.len() {
+            5 => format!("0 {} {}", fields[..4].join(" "), cron_weekdays(fields[4])),
+            _ => trimmed.to_string()
+        };
+        cron::Schedule::from_str(&expression).map(Schedule::Cron).map_err(|_| invalid())
+    }
+}
+
+impl Schedule {
+    // the first occurrence strictly after `after` and no earlier than `starts_at`
+    fn next_run(&self, starts_at: u32, after: u32) -> Option<u32> {
+        let start = Local.timestamp_opt(starts_at as i64, 0).single()?;
+        let after = Local.timestamp_opt(after as i64, 0).single()?.max(start - Duration::seconds(1));
+        let next: DateTime<Local> = match self {
+            Schedule::Cron(schedule) => schedule.after(&after).next()?,
+            Schedule::Rule(rule) => {
+                let end = (after + Duration::days(RULE_SEARCH_DAYS)).naive_local();
+                let to_utc = |local| Local.from_local_datetime(&local).earliest().map(|time| time.naive_utc()).unwrap_or(local);
+                rule.occurrences(start.naive_local(), end, to_utc)
+                    .into_iter()
+                    .filter_map(|occurrence| Local.from_local_datetime(&occurrence).earliest())
+                    .find(|occurrence| *occurrence > after)?
+            }
+        };
+        u32::try_from(next.with_timezone(&Utc).timestamp()).ok()
+    }
+}
+
+/// Turns every unpaused template whose next run has come into a reminder, then moves the template on to
+/// its next occurrence. Runs missed while the server was down produce a single reminder, not one each.
+pub async fn generate_scheduled_reminders(pool: &SqlitePool) -> Result<u32, ReminderTemplateError> {
+    let time = now()?;
+    let query = sqlx::query_as::<_, ReminderTemplate>("SELECT * FROM reminder_templates WHERE paused = 0 AND next_run <= $1").bind(time);
+    let templates: Vec<ReminderTemplate> = query.fetch_all(pool).await?;
+    let mut generated = 0;
+
+    for template in templates {
+        let next_run = match template.schedule.parse::<Schedule>() {
+            Ok(schedule) => schedule.next_run(template.starts_at, time),
+            Err(e) => {
+                println!("reminder template {} has an invalid schedule - {}", template.id, e);
+                None
+            }
+        };
+        let run = template.next_run.unwrap_or(time);
+
+        let mut transaction = pool.begin().await?;
+        let reminder_id: u32 = sqlx::query_scalar("INSERT INTO reminders (created_time, active, reminder, user_initials, show_from, due_at, expires_at, template_id) values ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id")
+            .bind(time)
+            .bind(true)
+            .bind(&template.reminder)
+            .bind(&template.user_initials)
+            .bind(run)
+            .bind(template.due_after.map(|due_after| run + due_after))
+            .bind(template.expires_after.map(|expires_after| run + expires_after))
+            .bind(template.id)
+            .fetch_one(&mut transaction).await?;
+        record_history(reminder_id, "created", None, &mut transaction).await?;
+        sqlx::query("UPDATE reminder_templates SET last_run = $1, next_run = $2 WHERE id = $3")
+            .bind(run)
+            .bind(next_run)
+            .bind(template.id)
+            .execute(&mut transaction).await?;
+        transaction.commit().await?;
+        notify_mentions(&template.reminder, None, reminder_id, None, &template.user_initials, pool).await?;
+        generated += 1;
+    }
+    Ok(generated)
+}
+
+pub async fn get_reminder_templates(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, ReminderTemplate>("SELECT * FROM reminder_templates ORDER BY next_run ASC");
+    let rows: Vec<ReminderTemplate> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewReminderTemplate {
+    reminder: String,
+    schedule: String,
+    starts_at: Option<u32>,
+    due_after: Option<u32>,
+    expires_after: Option<u32>
+}
+
+pub async fn create_reminder_template(
+    req: HttpRequest,
+    template: web::Json<NewReminderTemplate>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let time = now()?;
+    let starts_at = template.starts_at.unwrap_or(time);
+    let schedule: Schedule = template.schedule.parse()?;
+
+    let query = sqlx::query_as::<_, ReminderTemplate>("INSERT INTO reminder_templates (created_time, reminder, user_initials, schedule, starts_at, due_after, expires_after, paused, next_run) values ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *")
+        .bind(time)
+        .bind(&template.reminder)
+        .bind(&auth.initials)
+        .bind(template.schedule.trim())
+        .bind(starts_at)
+        .bind(template.due_after)
+        .bind(template.expires_after)
+        .bind(false)
+        .bind(schedule.next_run(starts_at, time));
+    let row: ReminderTemplate = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("reminder_template", row.id).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+async fn get_owned_template(id: u32, auth: &AuthenticatedUser, pool: &SqlitePool) -> Result<Result<ReminderTemplate, HttpResponse>, ReminderTemplateError> {
+    let query = sqlx::query_as::<_, ReminderTemplate>("SELECT * FROM reminder_templates WHERE id = $1").bind(id);
+    let template: ReminderTemplate = match query.fetch_optional(pool).await? {
+        Some(template) => template,
+        None => return Err(ReminderTemplateError::NotFound)
+    };
+    if template.user_initials != auth.initials {
+        return Ok(Err(HttpResponse::Forbidden().body(format!("{} is unable to change reminder schedules for {}", auth.initials, template.user_initials))));
+    }
+    Ok(Ok(template))
+}
+
+// fields left out are unchanged, a missing due_after or expires_after cannot be cleared this way
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateReminderTemplate {
+    reminder: Option<String>,
+    schedule: Option<String>,
+    starts_at: Option<u32>,
+    due_after: Option<u32>,
+    expires_after: Option<u32>,
+    paused: Option<bool>
+}
+
+pub async fn update_reminder_template(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    update: web::Json<UpdateReminderTemplate>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let template = match get_owned_template(*id, &auth, &data.db_pool).await? {
+        Ok(template) => template,
+        Err(response) => return Ok(response)
+    };
+
+    let schedule_text = update.schedule.as_deref().map(str::trim).unwrap_or(&template.schedule);
+    let schedule: Schedule = schedule_text.parse()?;
+    let starts_at = update.starts_at.unwrap_or(template.starts_at);
+    // resuming a paused template picks up from now rather than generating the runs it missed
+    let next_run = schedule.next_run(starts_at, now()?);
+
+    let query = sqlx::query_as::<_, ReminderTemplate>("UPDATE reminder_templates SET reminder = $1, schedule = $2, starts_at = $3, due_after = $4, expires_after = $5, paused = $6, next_run = $7 WHERE id = $8 RETURNING *")
+        .bind(update.reminder.as_ref().unwrap_or(&template.reminder))
+        .bind(schedule_text)
+        .bind(starts_at)
+        .bind(update.due_after.or(template.due_after))
+        .bind(update.expires_after.or(template.expires_after))
+        .bind(update.paused.unwrap_or(template.paused))
+        .bind(next_run)
+        .bind(template.id);
+    let row: ReminderTemplate = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("reminder_template", row.id).before(&template).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+// reminders already generated from the template stay, they just lose the link back to it
+pub async fn delete_reminder_template(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let template = match get_owned_template(*id, &auth, &data.db_pool).await? {
+        Ok(template) => template,
+        Err(response) => return Ok(response)
+    };
+
+    let mut transaction = data.db_pool.begin().await?;
+    sqlx::query("UPDATE reminders SET template_id = NULL WHERE template_id = $1")
+        .bind(template.id)
+        .execute(&mut transaction).await?;
+    sqlx::query("DELETE FROM reminder_templates WHERE id = $1")
+        .bind(template.id)
+        .execute(&mut transaction).await?;
+    transaction.commit().await?;
+    AuditChange::new("reminder_template", template.id).before(&template).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_pool;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> u32 {
+        Local.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap().timestamp() as u32
+    }
+
+    #[test]
+    fn cron_schedules_run_on_the_next_match() {
+        let weekdays: Schedule = "30 9 * * Mon-Fri".parse().unwrap();
+        // friday after the run moves on to monday
+        assert_eq!(weekdays.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 5, 10, 0)), Some(local(2024, 1, 8, 9, 30)));
+        // a run exactly at `after` has already happened
+        assert_eq!(weekdays.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 2, 9, 30)), Some(local(2024, 1, 3, 9, 30)));
+        // nothing runs before starts_at, but a run at starts_at itself counts
+        assert_eq!(weekdays.next_run(local(2024, 1, 3, 9, 30), local(2024, 1, 1, 0, 0)), Some(local(2024, 1, 3, 9, 30)));
+
+        // numeric weekdays count from Sunday as 0, with 7 as Sunday too
+        let fridays: Schedule = "0 9 * * 5".parse().unwrap();
+        assert_eq!(fridays.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 1, 0, 0)), Some(local(2024, 1, 5, 9, 0)));
+        let sundays: Schedule = "* * * * 0".parse().unwrap();
+        assert_eq!(sundays.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 1, 0, 0)), Some(local(2024, 1, 7, 0, 0)));
+        let weekends: Schedule = "0 9 * * 6-7".parse().unwrap();
+        assert_eq!(weekends.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 6, 10, 0)), Some(local(2024, 1, 7, 9, 0)));
+        let weekdays: Schedule = "0 9 * * 1-5,0/7".parse().unwrap();
+        assert_eq!(weekdays.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 5, 10, 0)), Some(local(2024, 1, 7, 9, 0)));
+        assert_eq!(cron_weekdays("1-5"), "Mon,Tue,Wed,Thu,Fri");
+        assert_eq!(cron_weekdays("*/2"), "*/2");
+        assert_eq!(cron_weekdays("0-7/3"), "Sun,Wed,Sat");
+        assert!("0 9 * * 8".parse::<Schedule>().is_err());
+
+        let with_seconds: Schedule = "0 0 12 1 * *".parse().unwrap();
+        assert_eq!(with_seconds.next_run(local(2024, 1, 1, 0, 0), local(2024, 1, 15, 0, 0)), Some(local(2024, 2, 1, 12, 0)));
+    }
+
+    #[test]
+    fn rrule_schedules_take_their_time_from_starts_at() {
+        let twice_weekly: Schedule = "FREQ=WEEKLY;BYDAY=TU,TH".parse().unwrap();
+        let starts_at = local(2024, 1, 2, 14, 0);
+        assert_eq!(twice_weekly.next_run(starts_at, local(2024, 1, 1, 0, 0)), Some(starts_at));
+        assert_eq!(twice_weekly.next_run(starts_at, starts_at), Some(local(2024, 1, 4, 14, 0)));
+        assert_eq!(twice_weekly.next_run(starts_at, local(2024, 1, 5, 9, 0)), Some(local(2024, 1, 9, 14, 0)));
+
+        let yearly: Schedule = "RRULE:FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1".parse().unwrap();
+        assert_eq!(yearly.next_run(local(2023, 2, 28, 9, 0), local(2023, 3, 1, 0, 0)), Some(local(2024, 2, 29, 9, 0)));
+
+        // a finished rule has no next run
+        let limited: Schedule = "FREQ=DAILY;COUNT=2".parse().unwrap();
+        assert_eq!(limited.next_run(local(2024, 1, 1, 9, 0), local(2024, 1, 1, 9, 0)), Some(local(2024, 1, 2, 9, 0)));
+        assert_eq!(limited.next_run(local(2024, 1, 1, 9, 0), local(2024, 1, 2, 9, 0)), None);
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for schedule in ["every day", "61 * * * *", "FREQ=FORTNIGHTLY", "RRULE:FREQ=DAILY;BYSETPOS=1"] {
+            assert!(matches!(schedule.parse::<Schedule>(), Err(ReminderTemplateError::InvalidSchedule(_))), "{} should be rejected", schedule);
+        }
+    }
+
+    async fn add_template(schedule: &str, starts_at: u32, next_run: u32, paused: bool, pool: &SqlitePool) -> u32 {
+        sqlx::query_scalar("INSERT INTO reminder_templates (created_time, reminder, user_initials, schedule, starts_at, due_after, paused, next_run) values ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id")
+            .bind(starts_at)
+            .bind("water the plants")
+            .bind("JG")
+            .bind(schedule)
+            .bind(starts_at)
+            .bind(3600)
+            .bind(paused)
+            .bind(next_run)
+            .fetch_one(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn missed_runs_generate_a_single_reminder() {
+        let pool = test_pool().await;
+        let time = now().unwrap();
+        let day = 24 * 60 * 60;
+        let starts_at = time - 10 * day;
+        // three daily runs were missed while the server was down
+        let missed = starts_at + 7 * day;
+        let id = add_template("FREQ=DAILY", starts_at, missed, false, &pool).await;
+        add_template("FREQ=DAILY", starts_at, missed, true, &pool).await;
+
+        assert_eq!(generate_scheduled_reminders(&pool).await.unwrap(), 1);
+        let reminders: Vec<(u32, Option<u32>)> = sqlx::query_as("SELECT show_from, due_at FROM reminders WHERE template_id = $1")
+            .bind(id)
+            .fetch_all(&pool).await.unwrap();
+        assert_eq!(reminders, vec![(missed, Some(missed + 3600))]);
+
+        let (last_run, next_run): (Option<u32>, Option<u32>) = sqlx::query_as("SELECT last_run, next_run FROM reminder_templates WHERE id = $1")
+            .bind(id)
+            .fetch_one(&pool).await.unwrap();
+        assert_eq!(last_run, Some(missed));
+        assert!(next_run.is_some_and(|next_run| next_run > time && next_run <= time + day));
+
+        // nothing is due again until the next run
+        assert_eq!(generate_scheduled_reminders(&pool).await.unwrap(), 0);
+    }
+}
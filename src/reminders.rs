@@ -1,20 +1,45 @@
-use actix_web::{Responder, HttpResponse, web};
-use actix_web_httpauth::extractors::bearer::BearerAuth;
-use sqlx::{SqlitePool, Row};
-use std::time::{SystemTime, SystemTimeError};
+use actix_web::{Responder, HttpMessage, HttpRequest, HttpResponse, web};
+use sqlx::{Executor, Sqlite, SqlitePool};
+use std::time::SystemTimeError;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
-use crate::AppState;
+use crate::{AppState, add_column_if_missing, now};
 use crate::AppError;
-use crate::users::UserToken;
-use crate::users::parse_token;
+use crate::audit::AuditChange;
+use crate::users::AuthenticatedUser;
+use crate::scopes::GrantedScopes;
+use crate::notifications::{NotificationError, notify_mentions};
+
+pub const PRIORITIES: &[&str] = &["low", "normal", "high"];
+pub const DEFAULT_PRIORITY: &str = "normal";
+// admins can change and disable reminders that are neither theirs nor assigned to them
+const ADMIN_SCOPE: &str = "reminders:admin";
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+const ACKNOWLEDGED_TIME: &str = "(SELECT MAX(created_time) FROM reminder_comments WHERE reminder_id = reminders.id AND kind = 'acknowledgement') AS acknowledged_time";
+// every filter is optional, $1 to $5 are status, author, from, to and the full text query
+const SEARCH_FILTER: &str = "($1 IS NULL OR active = $1) AND ($2 IS NULL OR UPPER(user_initials) = UPPER($2)) AND ($3 IS NULL OR created_time >= $3) AND ($4 IS NULL OR created_time < $4) AND ($5 IS NULL OR id IN (SELECT rowid FROM reminders_fts WHERE reminders_fts MATCH $5))";
 
 #[derive(Error, Debug)]
 pub enum ReminderError {
     #[error(transparent)]
     TimeError(#[from] SystemTimeError),
     #[error(transparent)]
-    DatabaseError(#[from] sqlx::Error)
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    NotificationError(#[from] NotificationError),
+    #[error("a reminder has to be shown before it expires and be due before it expires")]
+    InvalidSchedule,
+    #[error("unknown priority {0}")]
+    InvalidPriority(String),
+    #[error("no user has the initials {0}")]
+    UnknownInitials(String),
+    #[error("an expired reminder cannot be re-enabled")]
+    Expired,
+    #[error("unknown status {0}")]
+    InvalidStatus(String),
+    #[error("reminder not found")]
+    NotFound
 }
 
 #[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
@@ -23,74 +48,339 @@ pub struct Reminder {
     created_time: u32,
     active: bool,
     reminder: String,
-    user_initials: String
+    user_initials: String,
+    priority: String,
+    // initials of the user the reminder was handed to, the author keeps it otherwise
+    assigned_to: Option<String>,
+    // hidden from the active list until this time
+    show_from: Option<u32>,
+    due_at: Option<u32>,
+    // deactivated by the reminder scheduler once this time passes
+    expires_at: Option<u32>,
+    // the reminder template that generated this reminder, if any
+    template_id: Option<u32>,
+    // latest acknowledgement in reminder_comments, only filled in by the reminder lists
+    #[sqlx(default)]
+    acknowledged_time: Option<u32>,
+    // not stored, worked out from due_at whenever a reminder is returned
+    #[sqlx(default)]
+    overdue: bool
+}
+
+// a snapshot of the changeable parts of a reminder taken after each change, changed_by is empty for the schedulers
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct ReminderHistory {
+    id: u32,
+    reminder_id: u32,
+    changed_time: u32,
+    changed_by: Option<String>,
+    action: String,
+    reminder: String,
+    active: bool,
+    priority: String,
+    assigned_to: Option<String>
+}
+
+impl Reminder {
+    fn flag_overdue(mut self, time: u32) -> Reminder {
+        self.overdue = self.active && self.due_at.is_some_and(|due_at| due_at <= time);
+        self
+    }
 }
 
 pub async fn create_reminder_table(pool: &SqlitePool) -> Result<(), ReminderError> {
-    sqlx::query("CREATE TABLE IF NOT EXISTS reminders (id INTEGER PRIMARY KEY AUTOINCREMENT, created_time INTEGER, active INTEGER, reminder TEXT NOT NULL, user_initials TEXT NOT NULL)")
+    sqlx::query("CREATE TABLE IF NOT EXISTS reminders (id INTEGER PRIMARY KEY AUTOINCREMENT, created_time INTEGER, active INTEGER, reminder TEXT NOT NULL, user_initials TEXT NOT NULL, show_from INTEGER, due_at INTEGER, expires_at INTEGER, template_id INTEGER, priority TEXT NOT NULL DEFAULT 'normal', assigned_to TEXT)")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS reminder_history (id INTEGER PRIMARY KEY AUTOINCREMENT, reminder_id INTEGER NOT NULL, changed_time INTEGER, changed_by TEXT, action TEXT NOT NULL, reminder TEXT NOT NULL, active INTEGER, priority TEXT NOT NULL, assigned_to TEXT)")
+        .execute(pool).await?;
+    add_column_if_missing(pool, "reminders", "show_from", "INTEGER").await?;
+    add_column_if_missing(pool, "reminders", "due_at", "INTEGER").await?;
+    add_column_if_missing(pool, "reminders", "expires_at", "INTEGER").await?;
+    add_column_if_missing(pool, "reminders", "template_id", "INTEGER").await?;
+    add_column_if_missing(pool, "reminders", "priority", "TEXT NOT NULL DEFAULT 'normal'").await?;
+    add_column_if_missing(pool, "reminders", "assigned_to", "TEXT").await?;
+
+    // the search index only holds the reminder text, triggers keep it in step with the reminders table
+    let indexed: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sqlite_master WHERE name = 'reminders_fts'")
+        .fetch_one(pool).await?;
+    sqlx::query("CREATE VIRTUAL TABLE IF NOT EXISTS reminders_fts USING fts5(reminder, content='reminders', content_rowid='id')")
+        .execute(pool).await?;
+    sqlx::query("CREATE TRIGGER IF NOT EXISTS reminders_fts_insert AFTER INSERT ON reminders BEGIN INSERT INTO reminders_fts (rowid, reminder) VALUES (new.id, new.reminder); END")
+        .execute(pool).await?;
+    sqlx::query("CREATE TRIGGER IF NOT EXISTS reminders_fts_delete AFTER DELETE ON reminders BEGIN INSERT INTO reminders_fts (reminders_fts, rowid, reminder) VALUES ('delete', old.id, old.reminder); END")
         .execute(pool).await?;
+    sqlx::query("CREATE TRIGGER IF NOT EXISTS reminders_fts_update AFTER UPDATE OF reminder ON reminders BEGIN INSERT INTO reminders_fts (reminders_fts, rowid, reminder) VALUES ('delete', old.id, old.reminder); INSERT INTO reminders_fts (rowid, reminder) VALUES (new.id, new.reminder); END")
+        .execute(pool).await?;
+    if indexed == 0 {
+        sqlx::query("INSERT INTO reminders_fts (reminders_fts) VALUES ('rebuild')")
+            .execute(pool).await?;
+    }
+    Ok(())
+}
+
+fn is_valid_priority(priority: &str) -> bool {
+    PRIORITIES.contains(&priority)
+}
+
+async fn ensure_initials_exist(initials: &str, pool: &SqlitePool) -> Result<(), ReminderError> {
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE initials = $1")
+        .bind(initials)
+        .fetch_one(pool).await?;
+    if users == 0 {
+        return Err(ReminderError::UnknownInitials(initials.to_string()));
+    }
     Ok(())
 }
 
+/// Copies the current state of a reminder into its history. Takes an executor so the schedulers can
+/// record history in the same transaction as the change itself.
+pub async fn record_history<'e, E: Executor<'e, Database = Sqlite>>(reminder_id: u32, action: &str, changed_by: Option<&str>, executor: E) -> Result<(), ReminderError> {
+    sqlx::query("INSERT INTO reminder_history (reminder_id, changed_time, changed_by, action, reminder, active, priority, assigned_to) SELECT id, $1, $2, $3, reminder, active, priority, assigned_to FROM reminders WHERE id = $4")
+        .bind(now()?)
+        .bind(changed_by)
+        .bind(action)
+        .bind(reminder_id)
+        .execute(executor).await?;
+    Ok(())
+}
+
+// run by the reminder scheduler, returns how many reminders were deactivated
+pub async fn expire_reminders(pool: &SqlitePool) -> Result<u64, ReminderError> {
+    let time = now()?;
+    let mut transaction = pool.begin().await?;
+    let query = sqlx::query_as::<_, Reminder>("UPDATE reminders SET active = $1 WHERE active = $2 AND expires_at <= $3 RETURNING *")
+        .bind(false)
+        .bind(true)
+        .bind(time);
+    let expired: Vec<Reminder> = query.fetch_all(&mut transaction).await?;
+    for reminder in &expired {
+        record_history(reminder.id, "expired", None, &mut transaction).await?;
+    }
+    transaction.commit().await?;
+    Ok(expired.len() as u64)
+}
+
+fn default_priority() -> String {
+    DEFAULT_PRIORITY.to_string()
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct NewReminder {
-    reminder: String
+    reminder: String,
+    show_from: Option<u32>,
+    due_at: Option<u32>,
+    expires_at: Option<u32>,
+    #[serde(default = "default_priority")]
+    priority: String,
+    assigned_to: Option<String>
 }
 
 pub async fn create_reminder(
+    req: HttpRequest,
     reminder: web::Json<NewReminder>,
     data: web::Data<AppState>, 
-    auth: BearerAuth
+    auth: AuthenticatedUser
 )  -> Result<impl Responder, AppError> {
-    let token: Option<UserToken> = parse_token(auth.token(), data.jwt_key.clone());
-    let valid_token = match token {
-        Some(token) => { token },
-        None => return Ok(HttpResponse::Forbidden().body("Unable to validate User Identity"))
-    };
+    if let Some(expires_at) = reminder.expires_at {
+        if reminder.show_from.is_some_and(|show_from| show_from >= expires_at) || reminder.due_at.is_some_and(|due_at| due_at > expires_at) {
+            return Err(ReminderError::InvalidSchedule.into());
+        }
+    }
+    if !is_valid_priority(&reminder.priority) {
+        return Err(ReminderError::InvalidPriority(reminder.priority.clone()).into());
+    }
+    if let Some(assigned_to) = &reminder.assigned_to {
+        ensure_initials_exist(assigned_to, &data.db_pool).await?;
+    }
 
-    let query = sqlx::query_as::<_, Reminder>("INSERT INTO reminders (created_time, active, reminder, user_initials) values ($1, $2, $3, $4) RETURNING *")
-        .bind(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32)
+    let time = now()?;
+    let query = sqlx::query_as::<_, Reminder>("INSERT INTO reminders (created_time, active, reminder, user_initials, show_from, due_at, expires_at, priority, assigned_to) values ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *")
+        .bind(time)
         .bind(true)
         .bind(reminder.reminder.clone())
-        .bind(valid_token.initials);
+        .bind(auth.initials)
+        .bind(reminder.show_from)
+        .bind(reminder.due_at)
+        .bind(reminder.expires_at)
+        .bind(&reminder.priority)
+        .bind(&reminder.assigned_to);
     
-    let row: Reminder = query.fetch_one(&data.db_pool).await?;
+    let row: Reminder = query.fetch_one(&data.db_pool).await?.flag_overdue(time);
+    record_history(row.id, "created", Some(&auth.username), &data.db_pool).await?;
+    notify_mentions(&row.reminder, None, row.id, None, &row.user_initials, &data.db_pool).await?;
+    AuditChange::new("reminder", row.id).after(&row).record(&req);
 
     Ok(HttpResponse::Ok().json(row))
 }
 
+// reminders waiting for their show_from time or past their expiry are left out even before the scheduler runs
 pub async fn get_active_reminders(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
-    let query = sqlx::query("SELECT * FROM reminders WHERE active = 1  ORDER BY created_time ASC");
-    let rows = query.fetch_all(&data.db_pool).await?;
-    let reminders: Vec<Reminder> = rows.iter().map(|row| {
-        Reminder {
-            id: row.get("id"),
-            created_time: row.get("created_time"),
-            active: row.get("active"),
-            reminder: row.get("reminder"),
-            user_initials: row.get("user_initials")
-        }
-    }).collect();
+    let time = now()?;
+    let sql = format!("SELECT reminders.*, {} FROM reminders WHERE active = 1 AND (show_from IS NULL OR show_from <= $1) AND (expires_at IS NULL OR expires_at > $1) ORDER BY CASE priority WHEN 'high' THEN 0 WHEN 'normal' THEN 1 ELSE 2 END, created_time ASC", ACKNOWLEDGED_TIME);
+    let query = sqlx::query_as::<_, Reminder>(&sql)
+        .bind(time);
+    let rows: Vec<Reminder> = query.fetch_all(&data.db_pool).await?;
+    let reminders: Vec<Reminder> = rows.into_iter().map(|row| row.flag_overdue(time)).collect();
 
     Ok(HttpResponse::Ok().json(reminders))
 }
 
-pub async fn get_all_reminders(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
-    let query = sqlx::query("SELECT * FROM reminders  ORDER BY created_time ASC");
-    let rows = query.fetch_all(&data.db_pool).await?;
-    let reminders: Vec<Reminder> = rows.iter().map(|row| {
-        Reminder {
-            id: row.get("id"),
-            created_time: row.get("created_time"),
-            active: row.get("active"),
-            reminder: row.get("reminder"),
-            user_initials: row.get("user_initials")
-        }
-    }).collect();
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReminderSearch {
+    // active or inactive, both are returned when left out
+    status: Option<String>,
+    author: Option<String>,
+    // created_time range, from is inclusive and to is exclusive
+    from: Option<u32>,
+    to: Option<u32>,
+    q: Option<String>,
+    // the next_cursor of the previous page
+    cursor: Option<u32>,
+    limit: Option<u32>
+}
 
-    Ok(HttpResponse::Ok().json(reminders))
+#[derive(Serialize, Debug)]
+pub struct ReminderPage {
+    reminders: Vec<Reminder>,
+    // matching reminders across every page
+    total: i64,
+    next_cursor: Option<u32>
+}
+
+// search text is matched word by word as prefixes, quoting each word keeps fts5 syntax in the text from being parsed
+fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text.split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    match terms.is_empty() {
+        true => None,
+        false => Some(terms.join(" "))
+    }
+}
+
+// pages run oldest first, the cursor is the id of the last reminder on the previous page
+pub async fn get_all_reminders(search: web::Query<ReminderSearch>, data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let time = now()?;
+    let active = match search.status.as_deref() {
+        None | Some("all") => None,
+        Some("active") => Some(true),
+        Some("inactive") => Some(false),
+        Some(status) => return Err(ReminderError::InvalidStatus(status.to_string()).into())
+    };
+    let text = search.q.as_deref().and_then(fts_query);
+    let limit = search.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let total_sql = format!("SELECT COUNT(*) FROM reminders WHERE {}", SEARCH_FILTER);
+    let total: i64 = sqlx::query_scalar(&total_sql)
+        .bind(active)
+        .bind(&search.author)
+        .bind(search.from)
+        .bind(search.to)
+        .bind(&text)
+        .fetch_one(&data.db_pool).await?;
+
+    // one extra row is fetched to tell whether there is another page
+    let page_sql = format!("SELECT reminders.*, {} FROM reminders WHERE {} AND id > $6 ORDER BY id ASC LIMIT $7", ACKNOWLEDGED_TIME, SEARCH_FILTER);
+    let mut rows: Vec<Reminder> = sqlx::query_as::<_, Reminder>(&page_sql)
+        .bind(active)
+        .bind(&search.author)
+        .bind(search.from)
+        .bind(search.to)
+        .bind(&text)
+        .bind(search.cursor.unwrap_or(0))
+        .bind(limit + 1)
+        .fetch_all(&data.db_pool).await?;
+    let next_cursor = match rows.len() > limit as usize {
+        true => {
+            rows.truncate(limit as usize);
+            rows.last().map(|row| row.id)
+        },
+        false => None
+    };
+    let reminders: Vec<Reminder> = rows.into_iter().map(|row| row.flag_overdue(time)).collect();
+
+    Ok(HttpResponse::Ok().json(ReminderPage { reminders, total, next_cursor }))
 }
 
+pub async fn get_reminder_history(id: web::Path<u32>, data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    get_reminder(*id, &data.db_pool).await?;
+    let query = sqlx::query_as::<_, ReminderHistory>("SELECT * FROM reminder_history WHERE reminder_id = $1 ORDER BY id ASC").bind(*id);
+    let rows: Vec<ReminderHistory> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+pub async fn get_reminder(id: u32, pool: &SqlitePool) -> Result<Reminder, ReminderError> {
+    let query = sqlx::query_as::<_, Reminder>("SELECT * FROM reminders WHERE id = $1").bind(id);
+    query.fetch_optional(pool).await?.ok_or(ReminderError::NotFound)
+}
+
+// the author and whoever the reminder is assigned to can change it, admins can change any reminder
+fn can_change(reminder: &Reminder, auth: &AuthenticatedUser, req: &HttpRequest) -> bool {
+    reminder.user_initials == auth.initials
+        || reminder.assigned_to.as_deref() == Some(auth.initials.as_str())
+        || req.extensions().get::<GrantedScopes>().is_some_and(|scopes| scopes.contains(ADMIN_SCOPE))
+}
+
+// fields left out are unchanged, an empty assigned_to hands the reminder back to its author
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateReminder {
+    reminder: Option<String>,
+    priority: Option<String>,
+    assigned_to: Option<String>,
+    active: Option<bool>
+}
+
+pub async fn update_reminder(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    update: web::Json<UpdateReminder>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let row = get_reminder(*id, &data.db_pool).await?;
+    if !can_change(&row, &auth, &req) {
+        return Ok(HttpResponse::Forbidden().body(format!("{} is unable to change reminders for {}", auth.initials, row.user_initials)));
+    }
+
+    let time = now()?;
+    if let Some(priority) = &update.priority {
+        if !is_valid_priority(priority) {
+            return Err(ReminderError::InvalidPriority(priority.clone()).into());
+        }
+    }
+    let assigned_to = match update.assigned_to.as_deref() {
+        Some("") => None,
+        Some(initials) => {
+            ensure_initials_exist(initials, &data.db_pool).await?;
+            Some(initials)
+        },
+        None => row.assigned_to.as_deref()
+    };
+    let active = update.active.unwrap_or(row.active);
+    if active && !row.active && row.expires_at.is_some_and(|expires_at| expires_at <= time) {
+        return Err(ReminderError::Expired.into());
+    }
+
+    let query = sqlx::query_as::<_, Reminder>("UPDATE reminders SET reminder = $1, priority = $2, assigned_to = $3, active = $4 WHERE id = $5 RETURNING *")
+        .bind(update.reminder.as_ref().unwrap_or(&row.reminder))
+        .bind(update.priority.as_ref().unwrap_or(&row.priority))
+        .bind(assigned_to)
+        .bind(active)
+        .bind(row.id);
+    let updated: Reminder = query.fetch_one(&data.db_pool).await?.flag_overdue(time);
+
+    let action = match (row.active, updated.active) {
+        (true, false) => "disabled",
+        (false, true) => "enabled",
+        _ => "edited"
+    };
+    record_history(updated.id, action, Some(&auth.username), &data.db_pool).await?;
+    notify_mentions(&updated.reminder, Some(&row.reminder), updated.id, None, &auth.initials, &data.db_pool).await?;
+    AuditChange::new("reminder", row.id).before(&row).after(&updated).record(&req);
+
+    Ok(HttpResponse::Ok().json(updated))
+}
 
 // pub async fn disable_reminder(id: u32, _user_initials: &str, pool: &SqlitePool) -> Result<(), ReminderError> {
 
@@ -104,28 +394,251 @@ pub struct DisableReminder {
 }
 
 pub async fn disable_reminder(
+    req: HttpRequest,
     disable: web::Json<DisableReminder>,
     data: web::Data<AppState>, 
-    auth: BearerAuth
+    auth: AuthenticatedUser
 )  -> Result<impl Responder, AppError> {
-    let token: Option<UserToken> = parse_token(auth.token(), data.jwt_key.clone());
-    let valid_token = match token {
-        Some(token) => { token },
-        None => return Ok(HttpResponse::Forbidden().body("Unable to validate User Identity"))
-    };
+    let row = get_reminder(disable.id, &data.db_pool).await?;
 
-    let query = sqlx::query_as::<_, Reminder>("SELECT * FROM reminders WHERE id = $1").bind(disable.id);
-    let row: Reminder = query.fetch_one(&data.db_pool).await?;
-
-    if valid_token.initials == row.user_initials {
-        sqlx::query("UPDATE reminders SET active = $1 WHERE id = $2")
+    if can_change(&row, &auth, &req) {
+        let query = sqlx::query_as::<_, Reminder>("UPDATE reminders SET active = $1 WHERE id = $2 RETURNING *")
             .bind(false)
-            .bind(disable.id)
-            .execute(&data.db_pool).await?;
+            .bind(disable.id);
+        let updated: Reminder = query.fetch_one(&data.db_pool).await?;
+        if row.active {
+            record_history(updated.id, "disabled", Some(&auth.username), &data.db_pool).await?;
+        }
+        AuditChange::new("reminder", row.id).before(&row).after(&updated).record(&req);
     } else {
-        return Ok(HttpResponse::Forbidden().body(format!("{} is unable to delete reminders for {}", valid_token.initials, row.user_initials)));
+        return Ok(HttpResponse::Forbidden().body(format!("{} is unable to delete reminders for {}", auth.initials, row.user_initials)));
     }
 
 
     Ok(HttpResponse::Ok().body("success"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service};
+    use serde_json::Value;
+    use crate::{test_pool, test_state};
+    use crate::users::create_user;
+
+    async fn add_reminder(reminder: &str, user_initials: &str, active: bool, pool: &SqlitePool) -> u32 {
+        sqlx::query_scalar("INSERT INTO reminders (created_time, active, reminder, user_initials) values ($1, $2, $3, $4) RETURNING id")
+            .bind(1_700_000_000)
+            .bind(active)
+            .bind(reminder)
+            .bind(user_initials)
+            .fetch_one(pool).await.unwrap()
+    }
+
+    async fn search(pool: &SqlitePool, query: &str) -> Value {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(pool)))
+            .route("/api/reminders", web::get().to(get_all_reminders))).await;
+        let request = TestRequest::get().uri(&format!("/api/reminders?{}", query)).to_request();
+        call_and_read_body_json(&app, request).await
+    }
+
+    fn ids(page: &Value) -> Vec<u64> {
+        page["reminders"].as_array().unwrap().iter().map(|reminder| reminder["id"].as_u64().unwrap()).collect()
+    }
+
+    #[test]
+    fn search_text_is_quoted_word_by_word() {
+        assert_eq!(fts_query(""), None);
+        assert_eq!(fts_query("   "), None);
+        assert_eq!(fts_query("water  plants"), Some("\"water\"* \"plants\"*".to_string()));
+        assert_eq!(fts_query("say \"hi\""), Some("\"say\"* \"\"\"hi\"\"\"*".to_string()));
+        assert_eq!(fts_query("NOT (a OR"), Some("\"NOT\"* \"(a\"* \"OR\"*".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn search_matches_prefixes_and_filters() {
+        let pool = test_pool().await;
+        add_reminder("water the plants", "JG", true, &pool).await;
+        add_reminder("sprint planning on friday", "ab", true, &pool).await;
+        add_reminder("order more coffee", "JG", false, &pool).await;
+
+        assert_eq!(ids(&search(&pool, "q=plan").await), vec![1, 2]);
+        assert_eq!(ids(&search(&pool, "q=plan&author=jg").await), vec![1]);
+        assert_eq!(ids(&search(&pool, "status=inactive").await), vec![3]);
+        // fts5 operators in the text are searched for rather than parsed
+        let page = search(&pool, "q=%22coffee%20NOT%20(").await;
+        assert_eq!((ids(&page), page["total"].as_i64()), (vec![], Some(0)));
+
+        sqlx::query("UPDATE reminders SET reminder = $1 WHERE id = $2").bind("order more tea").bind(3).execute(&pool).await.unwrap();
+        assert_eq!(ids(&search(&pool, "q=coffee").await), Vec::<u64>::new());
+        assert_eq!(ids(&search(&pool, "q=tea").await), vec![3]);
+    }
+
+    #[actix_rt::test]
+    async fn pages_follow_the_cursor() {
+        let pool = test_pool().await;
+        for number in 1..=5 {
+            add_reminder(&format!("reminder {}", number), "JG", true, &pool).await;
+        }
+
+        let first = search(&pool, "limit=2").await;
+        assert_eq!((ids(&first), first["total"].as_i64(), first["next_cursor"].as_u64()), (vec![1, 2], Some(5), Some(2)));
+        let second = search(&pool, "limit=2&cursor=2").await;
+        assert_eq!((ids(&second), second["total"].as_i64(), second["next_cursor"].as_u64()), (vec![3, 4], Some(5), Some(4)));
+        let last = search(&pool, "limit=2&cursor=4").await;
+        assert_eq!((ids(&last), last["next_cursor"].as_u64()), (vec![5], None));
+
+        // a page that ends exactly on the last reminder has no next cursor
+        let exact = search(&pool, "limit=5").await;
+        assert_eq!((ids(&exact).len(), exact["next_cursor"].as_u64()), (5, None));
+        let past_the_end = search(&pool, "cursor=5").await;
+        assert_eq!((ids(&past_the_end), past_the_end["total"].as_i64()), (vec![], Some(5)));
+
+        // limits outside 1 to 200 are clamped
+        assert_eq!(ids(&search(&pool, "limit=0").await), vec![1]);
+        assert_eq!(ids(&search(&pool, "limit=1000").await).len(), 5);
+    }
+
+    async fn add_scheduled_reminder(reminder: &str, show_from: Option<u32>, due_at: Option<u32>, expires_at: Option<u32>, pool: &SqlitePool) -> u32 {
+        sqlx::query_scalar("INSERT INTO reminders (created_time, active, reminder, user_initials, show_from, due_at, expires_at) values ($1, $2, $3, $4, $5, $6, $7) RETURNING id")
+            .bind(1_700_000_000)
+            .bind(true)
+            .bind(reminder)
+            .bind("JG")
+            .bind(show_from)
+            .bind(due_at)
+            .bind(expires_at)
+            .fetch_one(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn active_reminders_respect_their_window() {
+        let pool = test_pool().await;
+        let time = now().unwrap();
+        let always = add_scheduled_reminder("always", None, None, None, &pool).await;
+        let shown = add_scheduled_reminder("shown", Some(time - 60), Some(time + 3600), Some(time + 7200), &pool).await;
+        add_scheduled_reminder("later", Some(time + 3600), None, None, &pool).await;
+        // past its expiry but the scheduler has not run yet
+        add_scheduled_reminder("expired", None, None, Some(time - 60), &pool).await;
+        let overdue = add_scheduled_reminder("overdue", None, Some(time - 60), None, &pool).await;
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/reminders/active", web::get().to(get_active_reminders))).await;
+        let reminders: Value = call_and_read_body_json(&app, TestRequest::get().uri("/api/reminders/active").to_request()).await;
+        let listed: Vec<(u64, bool)> = reminders.as_array().unwrap().iter()
+            .map(|reminder| (reminder["id"].as_u64().unwrap(), reminder["overdue"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(listed, vec![(always as u64, false), (shown as u64, false), (overdue as u64, true)]);
+    }
+
+    #[actix_rt::test]
+    async fn reminders_must_show_and_fall_due_before_they_expire() {
+        let pool = test_pool().await;
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/reminders", web::post().to(create_reminder))).await;
+        let create = |body: Value| {
+            let request = TestRequest::post().uri("/api/reminders").set_json(body).to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: "jordan".to_string(), initials: "JG".to_string(), api_key_id: None });
+            request
+        };
+
+        let invalid = [
+            serde_json::json!({ "reminder": "a", "show_from": 2000, "expires_at": 2000 }),
+            serde_json::json!({ "reminder": "a", "due_at": 2001, "expires_at": 2000 })
+        ];
+        for body in invalid {
+            assert_eq!(call_service(&app, create(body)).await.status(), StatusCode::BAD_REQUEST);
+        }
+        let body = serde_json::json!({ "reminder": "a", "show_from": 1000, "due_at": 2000, "expires_at": 2000 });
+        let reminder: Value = call_and_read_body_json(&app, create(body)).await;
+        // already past its due time when created
+        assert_eq!(reminder["overdue"], true);
+    }
+
+    #[actix_rt::test]
+    async fn expired_reminders_are_deactivated_with_a_history_row() {
+        let pool = test_pool().await;
+        let time = now().unwrap();
+        let expired = add_scheduled_reminder("expired", None, None, Some(time - 60), &pool).await;
+        let current = add_scheduled_reminder("current", None, None, Some(time + 60), &pool).await;
+        let disabled = add_scheduled_reminder("disabled", None, None, Some(time - 60), &pool).await;
+        sqlx::query("UPDATE reminders SET active = $1 WHERE id = $2").bind(false).bind(disabled).execute(&pool).await.unwrap();
+
+        assert_eq!(expire_reminders(&pool).await.unwrap(), 1);
+        assert!(!get_reminder(expired, &pool).await.unwrap().active);
+        assert!(get_reminder(current, &pool).await.unwrap().active);
+        let history: Vec<ReminderHistory> = sqlx::query_as("SELECT * FROM reminder_history").fetch_all(&pool).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].reminder_id, history[0].action.as_str(), history[0].changed_by.as_deref(), history[0].active), (expired, "expired", None, false));
+
+        // nothing is left to expire the second time round
+        assert_eq!(expire_reminders(&pool).await.unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn edits_only_change_what_is_sent_and_keep_history() {
+        let pool = test_pool().await;
+        create_user("jordan", "JG", "pass123", "member", &pool).await.unwrap();
+        create_user("alice", "AB", "pass123", "member", &pool).await.unwrap();
+        create_user("carol", "CA", "pass123", "admin", &pool).await.unwrap();
+        let id = add_reminder("water the plants", "JG", true, &pool).await;
+        let time = now().unwrap();
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/reminders/{id}", web::patch().to(update_reminder))
+            .route("/api/reminders/{id}/history", web::get().to(get_reminder_history))).await;
+        let patch = |username: &str, initials: &str, scopes: &[&str], body: Value| {
+            let request = TestRequest::patch().uri(&format!("/api/reminders/{}", id)).set_json(body).to_request();
+            request.extensions_mut().insert(AuthenticatedUser { username: username.to_string(), initials: initials.to_string(), api_key_id: None });
+            request.extensions_mut().insert(GrantedScopes(scopes.iter().map(|scope| scope.to_string()).collect()));
+            request
+        };
+
+        let updated: Value = call_and_read_body_json(&app, patch("jordan", "JG", &[], serde_json::json!({ "priority": "high" }))).await;
+        assert_eq!((updated["reminder"].as_str(), updated["priority"].as_str(), updated["active"].as_bool()), (Some("water the plants"), Some("high"), Some(true)));
+
+        assert_eq!(call_service(&app, patch("jordan", "JG", &[], serde_json::json!({ "assigned_to": "ZZ" }))).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(call_service(&app, patch("jordan", "JG", &[], serde_json::json!({ "priority": "urgent" }))).await.status(), StatusCode::BAD_REQUEST);
+        let updated: Value = call_and_read_body_json(&app, patch("jordan", "JG", &[], serde_json::json!({ "assigned_to": "AB" }))).await;
+        assert_eq!((updated["assigned_to"].as_str(), updated["priority"].as_str()), (Some("AB"), Some("high")));
+
+        // the assignee can change it too, others need reminders:admin
+        let updated: Value = call_and_read_body_json(&app, patch("alice", "AB", &[], serde_json::json!({ "reminder": "water the ferns" }))).await;
+        assert_eq!((updated["reminder"].as_str(), updated["assigned_to"].as_str()), (Some("water the ferns"), Some("AB")));
+        assert_eq!(call_service(&app, patch("carol", "CA", &["reminders:write"], serde_json::json!({ "active": false }))).await.status(), StatusCode::FORBIDDEN);
+        let updated: Value = call_and_read_body_json(&app, patch("carol", "CA", &[ADMIN_SCOPE], serde_json::json!({ "active": false, "assigned_to": "" }))).await;
+        assert_eq!((updated["active"].as_bool(), updated["assigned_to"].as_str()), (Some(false), None));
+
+        let updated: Value = call_and_read_body_json(&app, patch("jordan", "JG", &[], serde_json::json!({ "active": true }))).await;
+        assert_eq!(updated["active"], true);
+
+        // once a reminder has expired it stays off
+        sqlx::query("UPDATE reminders SET active = $1, expires_at = $2 WHERE id = $3")
+            .bind(false)
+            .bind(time - 60)
+            .bind(id)
+            .execute(&pool).await.unwrap();
+        assert_eq!(call_service(&app, patch("jordan", "JG", &[], serde_json::json!({ "active": true }))).await.status(), StatusCode::CONFLICT);
+        assert_eq!(call_service(&app, patch("jordan", "JG", &[], serde_json::json!({ "reminder": "still off" }))).await.status(), StatusCode::OK);
+
+        let request = TestRequest::get().uri(&format!("/api/reminders/{}/history", id)).to_request();
+        let history: Value = call_and_read_body_json(&app, request).await;
+        let history: Vec<(&str, Option<&str>, &str, bool)> = history.as_array().unwrap().iter()
+            .map(|row| (row["action"].as_str().unwrap(), row["changed_by"].as_str(), row["reminder"].as_str().unwrap(), row["active"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(history, vec![
+            ("edited", Some("jordan"), "water the plants", true),
+            ("edited", Some("jordan"), "water the plants", true),
+            ("edited", Some("alice"), "water the ferns", true),
+            ("disabled", Some("carol"), "water the ferns", false),
+            ("enabled", Some("jordan"), "water the ferns", true),
+            ("edited", Some("jordan"), "still off", false)
+        ]);
+    }
+}
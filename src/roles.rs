@@ -0,0 +1,206 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use sqlx::{SqlitePool};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError};
+use crate::audit::AuditChange;
+use crate::scopes::{SCOPES, is_valid_scope};
+use crate::users::{User, UserError, OTHER_ADMIN_REMAINS, get_user};
+
+pub const ADMIN_ROLE: &str = "admin";
+pub const DEFAULT_ROLE: &str = "member";
+pub const ROLES: &[&str] = &["viewer", "member", "admin", "device"];
+
+// permissions use the same names as api key scopes, each role starts with these until an admin changes them
+const DEFAULT_PERMISSIONS: &[(&str, &[&str])] = &[
+    ("viewer", &["account:write", "reminders:read", "presence:read", "calendar:read", "temperatures:read", "rss:read", "ping:read"]),
+    ("member", &["account:write", "reminders:read", "reminders:write", "presence:read", "presence:write", "calendar:read", "calendar:write", "temperatures:read", "rss:read", "rss:write", "ping:read"]),
+    ("admin", SCOPES),
+    ("device", &["temperatures:read", "temperatures:write"]),
+];
+
+// admins always keep the permission to manage roles so they cannot lock themselves out
+const PROTECTED_ADMIN_PERMISSION: &str = "users:admin";
+
+#[derive(Error, Debug)]
+pub enum RoleError {
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    UserError(#[from] UserError),
+    #[error("unknown role {0}")]
+    UnknownRole(String),
+    #[error("unknown permission {0}")]
+    UnknownPermission(String),
+    #[error("the admin role cannot lose the users:admin permission")]
+    ProtectedPermission
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Role {
+    name: String,
+    permissions: Vec<String>
+}
+
+pub fn is_valid_role(role: &str) -> bool {
+    ROLES.contains(&role)
+}
+
+pub async fn create_role_permission_table(pool: &SqlitePool) -> Result<(), RoleError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS role_permissions (id INTEGER PRIMARY KEY AUTOINCREMENT, role TEXT NOT NULL, permission TEXT NOT NULL, UNIQUE(role, permission))")
+        .execute(pool).await?;
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS seeded_permissions (permission TEXT PRIMARY KEY)")
+        .execute(pool).await?;
+
+    // databases from before seeding was tracked per permission already have every permission a role still holds
+    let tracked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM seeded_permissions")
+        .fetch_one(pool).await?;
+    if tracked == 0 {
+        sqlx::query("INSERT OR IGNORE INTO seeded_permissions (permission) SELECT DISTINCT permission FROM role_permissions")
+            .execute(pool).await?;
+    }
+
+    // each permission is only seeded once so permissions an admin has removed stay removed,
+    // while permissions added in later versions still reach the roles that should have them
+    for permission in SCOPES {
+        let result = sqlx::query("INSERT OR IGNORE INTO seeded_permissions (permission) values ($1)")
+            .bind(permission)
+            .execute(pool).await?;
+        if result.rows_affected() == 0 {
+            continue;
+        }
+        for (role, defaults) in DEFAULT_PERMISSIONS {
+            if defaults.contains(permission) {
+                sqlx::query("INSERT OR IGNORE INTO role_permissions (role, permission) values ($1, $2)")
+                    .bind(role)
+                    .bind(permission)
+                    .execute(pool).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn role_permissions(role: &str, pool: &SqlitePool) -> Result<Vec<String>, RoleError> {
+    let permissions: Vec<String> = sqlx::query_scalar("SELECT permission FROM role_permissions WHERE role = $1 ORDER BY permission ASC")
+        .bind(role)
+        .fetch_all(pool).await?;
+    Ok(permissions)
+}
+
+// refuses to take the admin role from the last admin, whether the change comes from the api, the admin cli or a directory login
+pub async fn set_role(username: &str, role: &str, pool: &SqlitePool) -> Result<User, RoleError> {
+    if !is_valid_role(role) {
+        return Err(RoleError::UnknownRole(role.to_string()));
+    }
+    let mut transaction = pool.begin().await?;
+    let exists: Option<u32> = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+        .bind(username)
+        .fetch_optional(&mut transaction).await?;
+    if exists.is_none() {
+        return Err(UserError::NotFound.into());
+    }
+    let sql = format!("UPDATE users SET role = $1 WHERE username = $2 AND ($1 = $3 OR {}) RETURNING *", OTHER_ADMIN_REMAINS);
+    let query = sqlx::query_as::<_, User>(&sql)
+        .bind(role)
+        .bind(username)
+        .bind(ADMIN_ROLE);
+    let user = query.fetch_optional(&mut transaction).await?;
+    transaction.commit().await?;
+    match user {
+        Some(user) => Ok(user),
+        None => Err(UserError::LastAdmin.into())
+    }
+}
+
+pub async fn get_roles(
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let mut roles = Vec::with_capacity(ROLES.len());
+    for role in ROLES {
+        roles.push(Role { name: role.to_string(), permissions: role_permissions(role, &data.db_pool).await? });
+    }
+
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateRole {
+    permissions: Vec<String>
+}
+
+pub async fn update_role(
+    req: HttpRequest,
+    name: web::Path<String>,
+    update: web::Json<UpdateRole>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    if !is_valid_role(&name) {
+        return Err(RoleError::UnknownRole(name.clone()).into());
+    }
+    if let Some(permission) = update.permissions.iter().find(|permission| !is_valid_scope(permission)) {
+        return Err(RoleError::UnknownPermission(permission.clone()).into());
+    }
+    if name.as_str() == ADMIN_ROLE && !update.permissions.iter().any(|permission| permission == PROTECTED_ADMIN_PERMISSION) {
+        return Err(RoleError::ProtectedPermission.into());
+    }
+
+    let before = Role { name: name.clone(), permissions: role_permissions(&name, &data.db_pool).await? };
+    let mut transaction = data.db_pool.begin().await?;
+    sqlx::query("DELETE FROM role_permissions WHERE role = $1")
+        .bind(name.as_str())
+        .execute(&mut transaction).await?;
+    for permission in &update.permissions {
+        sqlx::query("INSERT OR IGNORE INTO role_permissions (role, permission) values ($1, $2)")
+            .bind(name.as_str())
+            .bind(permission)
+            .execute(&mut transaction).await?;
+    }
+    transaction.commit().await?;
+
+    let after = Role { name: name.into_inner(), permissions: role_permissions(&before.name, &data.db_pool).await? };
+    AuditChange::new("role", &after.name).before(&before).after(&after).record(&req);
+
+    Ok(HttpResponse::Ok().json(after))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RoleAssignment {
+    role: String
+}
+
+pub async fn assign_role(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    assignment: web::Json<RoleAssignment>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let user = get_user(*id, &data.db_pool).await?;
+    let row = set_role(&user.username, &assignment.role, &data.db_pool).await?;
+    AuditChange::new("user", row.id).before(&user).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::users::create_user;
+    use crate::test_pool;
+
+    #[actix_rt::test]
+    async fn set_role_keeps_an_admin() {
+        let pool = test_pool().await;
+        create_user("jordan", "JG", "pass123", ADMIN_ROLE, &pool).await.unwrap();
+
+        let result = set_role("jordan", DEFAULT_ROLE, &pool).await;
+        assert!(matches!(result, Err(RoleError::UserError(UserError::LastAdmin))));
+        assert!(matches!(set_role("nobody", DEFAULT_ROLE, &pool).await, Err(RoleError::UserError(UserError::NotFound))));
+
+        create_user("carol", "CD", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+        assert_eq!(set_role("carol", ADMIN_ROLE, &pool).await.unwrap().role, ADMIN_ROLE);
+        assert_eq!(set_role("jordan", DEFAULT_ROLE, &pool).await.unwrap().role, DEFAULT_ROLE);
+        assert!(set_role("carol", "viewer", &pool).await.is_err());
+    }
+}
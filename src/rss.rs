@@ -1,5 +1,4 @@
-use actix_web::http::Error;
-use actix_web::{Responder, HttpResponse, web};
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
 use rss::{Channel, Item};
 use sqlx::{SqlitePool, Row};
 use std::time::{SystemTimeError};
@@ -10,6 +9,7 @@ use thiserror::Error;
 use crate::AppState;
 
 use crate::AppError;
+use crate::audit::AuditChange;
 
 #[derive(Error, Debug)]
 pub enum RSSError {
@@ -66,15 +66,17 @@ pub struct NewRSSFeed {
 }
 
 pub async fn create_rss_feed(
+    req: HttpRequest,
     feed: web::Json<NewRSSFeed>,
     data: web::Data<AppState>
 )   -> Result<impl Responder, AppError> {
     let query = sqlx::query_as::<_, RssFeed>("INSERT INTO rss_feeds (label, url, important) values ($1, $2, $3) RETURNING *")
         .bind(feed.label.clone())
         .bind(feed.url.clone())
-        .bind(feed.important.clone());
+        .bind(feed.important);
 
     let row: RssFeed = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("rss_feed", row.id).after(&row).record(&req);
 
     Ok(HttpResponse::Ok().json(row))
 }
@@ -113,6 +115,7 @@ pub struct DismissFeedItem {
 }
 
 pub async fn dismiss_feed_item(
+    req: HttpRequest,
     disable: web::Json<DismissFeedItem>,
     data: web::Data<AppState>
 )  -> Result<impl Responder, AppError> {
@@ -120,10 +123,11 @@ pub async fn dismiss_feed_item(
     let query = sqlx::query_as::<_, RssFeedItem>("SELECT * FROM rss_feed_items WHERE id = $1").bind(disable.id);
     let row: RssFeedItem = query.fetch_one(&data.db_pool).await?;
 
-    sqlx::query("UPDATE rss_feed_items SET dismissed = $1 WHERE id = $2")
+    let query = sqlx::query_as::<_, RssFeedItem>("UPDATE rss_feed_items SET dismissed = $1 WHERE id = $2 RETURNING *")
         .bind(true)
-        .bind(disable.id)
-        .execute(&data.db_pool).await?;
+        .bind(disable.id);
+    let updated: RssFeedItem = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("rss_feed_item", row.id).before(&row).after(&updated).record(&req);
 
 
     Ok(HttpResponse::Ok().body("success"))
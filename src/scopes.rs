@@ -0,0 +1,154 @@
+use actix_web::{
+    Error, HttpMessage, HttpResponse,
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use crate::AppErrorResponse;
+
+pub const SCOPES: &[&str] = &[
+    "account:write",
+    "reminders:read",
+    "reminders:write",
+    "reminders:admin",
+    "presence:read",
+    "presence:write",
+    "calendar:read",
+    "calendar:write",
+    "calendar:admin",
+    "temperatures:read",
+    "temperatures:write",
+    "temperatures:admin",
+    "rss:read",
+    "rss:write",
+    "rss:admin",
+    "ping:read",
+    "ping:admin",
+    "users:admin",
+    "apikeys:admin",
+];
+
+pub fn is_valid_scope(scope: &str) -> bool {
+    SCOPES.contains(&scope)
+}
+
+// scopes stored with an api key are saved as a space separated list like an oauth scope claim
+pub fn parse_scopes(scopes: &str) -> Vec<String> {
+    scopes.split_whitespace().map(|scope| scope.to_string()).collect()
+}
+
+/// Scopes granted to the current request, inserted into the request extensions by `bearer_auth_validator`.
+/// For logged in users these are the permissions of their role, for api keys the key's scopes.
+#[derive(Clone, Debug)]
+pub struct GrantedScopes(pub Vec<String>);
+
+impl GrantedScopes {
+    pub fn contains(&self, scope: &str) -> bool {
+        self.0.iter().any(|granted| granted == scope)
+    }
+}
+
+/// Route middleware that rejects the request with a 403 unless the given scope was granted.
+pub struct RequireScope(pub &'static str);
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware { service, scope: self.0 }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    service: S,
+    scope: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let allowed = match req.extensions().get::<GrantedScopes>() {
+            Some(scopes) => scopes.contains(self.scope),
+            None => false
+        };
+
+        if !allowed {
+            let response = HttpResponse::Forbidden()
+                .json(AppErrorResponse { error: format!("missing required scope {}", self.scope) });
+            let res = req.into_response(response).map_into_right_body();
+            return Box::pin(async { Ok(res) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, web};
+    use actix_web::http::{StatusCode, header::AUTHORIZATION};
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use crate::{test_pool, test_state};
+    use crate::api_keys::create_api_key;
+    use crate::users::{bearer_auth_validator, create_user, get_user_by_username, issue_access_token};
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn routes_need_their_scope() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "admin", &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .service(web::scope("/api")
+                .wrap(HttpAuthentication::with_fn(bearer_auth_validator))
+                .route("/apikeys", web::post().to(create_api_key))
+                .route("/reminders", web::get().to(ok).wrap(RequireScope("reminders:read")))
+                .route("/reminders", web::post().to(ok).wrap(RequireScope("reminders:write"))))).await;
+        let bearer = |request: TestRequest, token: &str| request.insert_header((AUTHORIZATION, format!("Bearer {}", token)));
+        let user = get_user_by_username("jordan", &pool).await.unwrap();
+        let login_token = issue_access_token(&user, &test_state(&pool).jwt_keys).unwrap();
+
+        let request = bearer(TestRequest::post(), &login_token)
+            .uri("/api/apikeys")
+            .set_json(serde_json::json!({ "name": "wall display", "scopes": ["reminders:read"] }))
+            .to_request();
+        let key: serde_json::Value = call_and_read_body_json(&app, request).await;
+        let key = key["token"].as_str().unwrap();
+
+        assert_eq!(call_service(&app, bearer(TestRequest::get(), key).uri("/api/reminders").to_request()).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, bearer(TestRequest::post(), key).uri("/api/reminders").to_request()).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, bearer(TestRequest::post(), &login_token).uri("/api/reminders").to_request()).await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn scopes_are_split_on_whitespace() {
+        assert_eq!(parse_scopes(" reminders:read  presence:read "), vec!["reminders:read", "presence:read"]);
+        assert!(parse_scopes("").is_empty());
+        assert!(is_valid_scope("reminders:admin"));
+        assert!(!is_valid_scope("reminders:*"));
+    }
+}
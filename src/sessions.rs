@@ -0,0 +1,268 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web, cookie::{Cookie, SameSite, time::Duration}, http::Method};
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use crate::{AppState, AppError, add_column_if_missing, hash_secret, generate_secret, now};
+use crate::audit::AuditChange;
+
+pub const SESSION_COOKIE: &str = "session";
+// the names axios uses by default, so the dashboard sends the header without any extra code
+pub const CSRF_COOKIE: &str = "XSRF-TOKEN";
+pub const CSRF_HEADER: &str = "X-XSRF-TOKEN";
+// sessions end after a day without use, and a month after logging in however often they are used
+const SESSION_LIFETIME: u32 = 60 * 60 * 24;
+const SESSION_MAX_LIFETIME: u32 = 60 * 60 * 24 * 30;
+// a session is only extended once this much of its idle lifetime has passed, not on every request
+const SESSION_RENEW_AFTER: u32 = 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("missing or invalid csrf token")]
+    InvalidCsrfToken
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub id: u32,
+    pub username: String,
+    session_hash: String,
+    csrf_hash: String,
+    pub created_time: u32,
+    expires_time: u32,
+    revoked: bool,
+    pub token_generation: u32
+}
+
+pub async fn create_session_table(pool: &SqlitePool) -> Result<(), SessionError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS sessions (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT NOT NULL, session_hash TEXT UNIQUE NOT NULL, csrf_hash TEXT NOT NULL, created_time INTEGER, expires_time INTEGER, revoked INTEGER, token_generation INTEGER NOT NULL DEFAULT 0)")
+        .execute(pool).await?;
+    add_column_if_missing(pool, "sessions", "token_generation", "INTEGER NOT NULL DEFAULT 0").await?;
+    Ok(())
+}
+
+/// A freshly started browser session, the tokens only exist in the cookies handed to the browser.
+pub struct NewSession {
+    session_token: String,
+    csrf_token: String
+}
+
+impl NewSession {
+    // the session cookie can never be read by scripts on the page
+    pub fn session_cookie(&self) -> Cookie<'static> {
+        Cookie::build(SESSION_COOKIE, self.session_token.clone())
+            .path("/api")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(Duration::seconds(SESSION_MAX_LIFETIME as i64))
+            .finish()
+    }
+
+    // the csrf cookie has to be readable so the page can copy it into the request header
+    pub fn csrf_cookie(&self) -> Cookie<'static> {
+        Cookie::build(CSRF_COOKIE, self.csrf_token.clone())
+            .path("/")
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(Duration::seconds(SESSION_MAX_LIFETIME as i64))
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionResponse {
+    csrf_token: String
+}
+
+pub async fn start_session(username: &str, pool: &SqlitePool) -> Result<NewSession, SessionError> {
+    let time = now()?;
+    sqlx::query("DELETE FROM sessions WHERE expires_time < $1")
+        .bind(time)
+        .execute(pool).await?;
+
+    let session = NewSession { session_token: generate_secret(), csrf_token: generate_secret() };
+    // the session remembers the user's token generation so a password change ends it
+    sqlx::query("INSERT INTO sessions (username, session_hash, csrf_hash, created_time, expires_time, revoked, token_generation) SELECT $1, $2, $3, $4, $5, $6, token_generation FROM users WHERE username = $1")
+        .bind(username)
+        .bind(hash_secret(&session.session_token))
+        .bind(hash_secret(&session.csrf_token))
+        .bind(time)
+        .bind(time + SESSION_LIFETIME)
+        .bind(false)
+        .execute(pool).await?;
+
+    Ok(session)
+}
+
+// the response for /api/login and /api/login/totp when the browser asked for a session instead of tokens
+pub fn session_login_response(session: &NewSession) -> HttpResponse {
+    HttpResponse::Ok()
+        .cookie(session.session_cookie())
+        .cookie(session.csrf_cookie())
+        .json(SessionResponse { csrf_token: session.csrf_token.clone() })
+}
+
+async fn find_session(req: &HttpRequest, pool: &SqlitePool) -> Result<Option<Session>, SessionError> {
+    let cookie = match req.cookie(SESSION_COOKIE) {
+        Some(cookie) => cookie,
+        None => return Ok(None)
+    };
+    let query = sqlx::query_as::<_, Session>("SELECT * FROM sessions WHERE session_hash = $1").bind(hash_secret(cookie.value()));
+    match query.fetch_optional(pool).await? {
+        Some(session) if !session.revoked && session.expires_time >= now()? => Ok(Some(session)),
+        _ => Ok(None)
+    }
+}
+
+/// Looks up the session named by the request's session cookie.
+///
+/// Mutating requests must also send the csrf token in the `X-XSRF-TOKEN` header, matching both the
+/// csrf cookie and the token issued with the session. Another site can make the browser send the
+/// cookies but cannot read them to fill in the header. Each use pushes the session's expiry back by
+/// `SESSION_LIFETIME`, up to `SESSION_MAX_LIFETIME` after it started.
+pub async fn validate_session(req: &HttpRequest, pool: &SqlitePool) -> Result<Option<Session>, SessionError> {
+    let session = match find_session(req, pool).await? {
+        Some(session) => session,
+        None => return Ok(None)
+    };
+
+    if matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE) {
+        let header = req.headers().get(CSRF_HEADER).and_then(|header| header.to_str().ok());
+        let cookie = req.cookie(CSRF_COOKIE);
+        match (header, cookie) {
+            (Some(header), Some(cookie)) if header == cookie.value() && hash_secret(header) == session.csrf_hash => {},
+            _ => return Err(SessionError::InvalidCsrfToken)
+        }
+    }
+
+    // the cookies outlive any session, so keeping the session going only needs the row extended
+    let time = now()?;
+    let expires_time = (time + SESSION_LIFETIME).min(session.created_time + SESSION_MAX_LIFETIME);
+    if expires_time >= session.expires_time + SESSION_RENEW_AFTER {
+        sqlx::query("UPDATE sessions SET expires_time = $1 WHERE id = $2")
+            .bind(expires_time)
+            .bind(session.id)
+            .execute(pool).await?;
+    }
+
+    Ok(Some(session))
+}
+
+pub async fn logout(
+    req: HttpRequest,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    if let Some(session) = find_session(&req, &data.db_pool).await? {
+        sqlx::query("UPDATE sessions SET revoked = $1 WHERE id = $2")
+            .bind(true)
+            .bind(session.id)
+            .execute(&data.db_pool).await?;
+        AuditChange::new("session", session.id).record(&req);
+    }
+
+    // removal cookies need the same path as the originals
+    let removed = NewSession { session_token: String::new(), csrf_token: String::new() };
+    let (mut session_cookie, mut csrf_cookie) = (removed.session_cookie(), removed.csrf_cookie());
+    session_cookie.make_removal();
+    csrf_cookie.make_removal();
+
+    Ok(HttpResponse::Ok().cookie(session_cookie).cookie(csrf_cookie).body("success"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use crate::{test_pool, test_state};
+    use crate::users::{AuthenticatedUser, bearer_auth_validator, create_user};
+
+    async fn whoami(auth: AuthenticatedUser) -> HttpResponse {
+        HttpResponse::Ok().body(auth.username)
+    }
+
+    async fn expires_time(pool: &SqlitePool) -> u32 {
+        sqlx::query_scalar("SELECT expires_time FROM sessions").fetch_one(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn changes_need_the_csrf_header() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let session = start_session("jordan", &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .service(web::scope("/api")
+                .wrap(HttpAuthentication::with_fn(bearer_auth_validator))
+                .route("/me", web::get().to(whoami))
+                .route("/me", web::post().to(whoami)))).await;
+        let request = |method: Method, header: Option<&str>| {
+            let request = TestRequest::default()
+                .method(method)
+                .uri("/api/me")
+                .cookie(session.session_cookie())
+                .cookie(session.csrf_cookie());
+            match header {
+                Some(header) => request.insert_header((CSRF_HEADER, header)),
+                None => request
+            }.to_request()
+        };
+
+        assert_eq!(call_service(&app, request(Method::GET, None)).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, request(Method::POST, None)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, request(Method::POST, Some("not-the-token"))).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call_service(&app, request(Method::POST, Some(&session.csrf_token))).await.status(), StatusCode::OK);
+
+        // the header has to match the token issued with this session, not just the cookie sent alongside it
+        let other = NewSession { session_token: session.session_token.clone(), csrf_token: "forged".to_string() };
+        let forged = TestRequest::post()
+            .uri("/api/me")
+            .cookie(other.session_cookie())
+            .cookie(other.csrf_cookie())
+            .insert_header((CSRF_HEADER, "forged"))
+            .to_request();
+        assert_eq!(call_service(&app, forged).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_are_extended_while_in_use() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "member", &pool).await.unwrap();
+        let session = start_session("jordan", &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .service(web::scope("/api")
+                .wrap(HttpAuthentication::with_fn(bearer_auth_validator))
+                .route("/me", web::get().to(whoami)))).await;
+        let request = || TestRequest::get().uri("/api/me").cookie(session.session_cookie()).to_request();
+
+        // a fresh session is not rewritten on every request
+        let started = expires_time(&pool).await;
+        assert_eq!(call_service(&app, request()).await.status(), StatusCode::OK);
+        assert_eq!(expires_time(&pool).await, started);
+
+        sqlx::query("UPDATE sessions SET created_time = created_time - $1, expires_time = expires_time - $1")
+            .bind(SESSION_LIFETIME - 60)
+            .execute(&pool).await.unwrap();
+        assert_eq!(call_service(&app, request()).await.status(), StatusCode::OK);
+        assert!(expires_time(&pool).await >= started);
+
+        // however much it is used, a session ends a month after it started
+        sqlx::query("UPDATE sessions SET created_time = created_time - $1, expires_time = expires_time - $2")
+            .bind(SESSION_MAX_LIFETIME)
+            .bind(SESSION_LIFETIME - 60)
+            .execute(&pool).await.unwrap();
+        let before = expires_time(&pool).await;
+        assert_eq!(call_service(&app, request()).await.status(), StatusCode::OK);
+        assert_eq!(expires_time(&pool).await, before);
+        sqlx::query("UPDATE sessions SET expires_time = expires_time - 120").execute(&pool).await.unwrap();
+        assert_eq!(call_service(&app, request()).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,53 +1,146 @@
-use actix_web::{Responder, HttpResponse, web};
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
 use sqlx::{SqlitePool, Row};
-use std::time::{SystemTime, SystemTimeError};
+use std::time::SystemTimeError;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
-use crate::AppState;
+use crate::{AppState, add_column_if_missing, now};
 
 use crate::AppError;
+use crate::audit::{AuditChange, audited_within, skip_audit};
+use crate::alerts::{AlertError, evaluate_temperature_alerts};
+
+// readings are kept as they are for this long, then folded into hourly rollups
+const RAW_RETENTION: u32 = 7 * 86400;
+// hourly rollups are kept for this long, then folded into daily rollups which are kept forever
+const HOURLY_RETENTION: u32 = 90 * 86400;
+const HOUR: u32 = 3600;
+const DAY: u32 = 86400;
+const DEFAULT_HISTORY_RANGE: u32 = DAY;
+const MIN_RESOLUTION: u32 = 60;
+// without a resolution the range is split into about this many buckets
+const DEFAULT_BUCKETS: u32 = 200;
+pub const UNITS: &[&str] = &["C", "F"];
+const DEFAULT_UNIT: &str = "C";
+// probes used to be created with this in place of a reading
+const LEGACY_NO_READING: i32 = 255;
 
 #[derive(Error, Debug)]
 pub enum TemperatureError {
     #[error(transparent)]
     TimeError(#[from] SystemTimeError),
     #[error(transparent)]
-    DatabaseError(#[from] sqlx::Error)
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    AlertError(#[from] AlertError),
+
+    #[error("temperature probe not found")]
+    NotFound,
+    #[error("from has to be before to and the resolution at least a minute")]
+    InvalidRange,
+    #[error("unknown unit {0}")]
+    InvalidUnit(String),
+    #[error("the unit of a probe with readings or thresholds can't be changed")]
+    UnitInUse
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
 pub struct Temperature {
     id: u32,
     label: String,
-    last_set_time: u32,
-    temp: i32
+    location: Option<String>,
+    // the unit the probe reports in, readings are stored as they arrive plus the calibration offset
+    unit: String,
+    calibration_offset: i32,
+    // both empty until the probe sends its first reading
+    last_set_time: Option<u32>,
+    temp: Option<i32>,
+    // severity of the probe's open alert, only filled in by get_temperatures
+    #[sqlx(default)]
+    alert: Option<String>
+}
+
+// one bucket of a probe's history, rollups store the average rather than a running total
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct TemperatureBucket {
+    time: u32,
+    min: i32,
+    max: i32,
+    avg: f64,
+    count: u32
+}
+
+#[derive(Serialize, Debug)]
+pub struct TemperatureHistory {
+    id: u32,
+    from: u32,
+    to: u32,
+    resolution: u32,
+    buckets: Vec<TemperatureBucket>
 }
 
 pub async fn create_temperature_table(pool: &SqlitePool) -> Result<(), TemperatureError> {
-    sqlx::query("CREATE TABLE IF NOT EXISTS temperatures (id INTEGER PRIMARY KEY AUTOINCREMENT, label TEXT NOT NULL, last_set_time INTEGER, temp INTEGER)")
+    sqlx::query("CREATE TABLE IF NOT EXISTS temperatures (id INTEGER PRIMARY KEY AUTOINCREMENT, label TEXT NOT NULL, last_set_time INTEGER, temp INTEGER, location TEXT, unit TEXT NOT NULL DEFAULT 'C', calibration_offset INTEGER NOT NULL DEFAULT 0)")
+        .execute(pool).await?;
+    add_column_if_missing(pool, "temperatures", "location", "TEXT").await?;
+    add_column_if_missing(pool, "temperatures", "unit", "TEXT NOT NULL DEFAULT 'C'").await?;
+    add_column_if_missing(pool, "temperatures", "calibration_offset", "INTEGER NOT NULL DEFAULT 0").await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS temperature_readings (id INTEGER PRIMARY KEY AUTOINCREMENT, temperature_id INTEGER NOT NULL, reading_time INTEGER NOT NULL, temp INTEGER NOT NULL)")
+        .execute(pool).await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS temperature_readings_time ON temperature_readings (temperature_id, reading_time)")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS temperature_rollups (temperature_id INTEGER NOT NULL, resolution TEXT NOT NULL, bucket_time INTEGER NOT NULL, min INTEGER NOT NULL, max INTEGER NOT NULL, avg REAL NOT NULL, count INTEGER NOT NULL, PRIMARY KEY (temperature_id, resolution, bucket_time))")
+        .execute(pool).await?;
+    // a probe that has ever stored a reading really did read 255, only the placeholder from creation is cleared
+    sqlx::query("UPDATE temperatures SET temp = NULL, last_set_time = NULL WHERE temp = $1 AND NOT EXISTS (SELECT 1 FROM temperature_readings WHERE temperature_id = temperatures.id) AND NOT EXISTS (SELECT 1 FROM temperature_rollups WHERE temperature_id = temperatures.id)")
+        .bind(LEGACY_NO_READING)
         .execute(pool).await?;
     Ok(())
 }
 
-pub async fn create_temperature_probe(label: &str, pool: &SqlitePool) -> Result<(), TemperatureError> {
-    sqlx::query("INSERT INTO temperatures (label, last_set_time, temp) values ($1, $2, $3)")
+fn is_valid_unit(unit: &str) -> bool {
+    UNITS.contains(&unit)
+}
+
+pub async fn create_temperature_probe(label: &str, location: Option<&str>, unit: &str, calibration_offset: i32, pool: &SqlitePool) -> Result<Temperature, TemperatureError> {
+    if !is_valid_unit(unit) {
+        return Err(TemperatureError::InvalidUnit(unit.to_string()));
+    }
+    let probe = sqlx::query_as::<_, Temperature>("INSERT INTO temperatures (label, location, unit, calibration_offset) values ($1, $2, $3, $4) RETURNING *")
         .bind(label)
-        .bind(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32)
-        .bind(255)
-        .execute(pool).await?;
+        .bind(location)
+        .bind(unit)
+        .bind(calibration_offset)
+        .fetch_one(pool).await?;
 
-    Ok(())
+    Ok(probe)
+}
+
+async fn get_probe(id: u32, pool: &SqlitePool) -> Result<Temperature, TemperatureError> {
+    let query = sqlx::query_as::<_, Temperature>("SELECT * FROM temperatures WHERE id = $1").bind(id);
+    query.fetch_optional(pool).await?.ok_or(TemperatureError::NotFound)
+}
+
+// readings, rollups and thresholds are all stored in the probe's unit
+async fn has_unit_data(id: u32, pool: &SqlitePool) -> Result<bool, TemperatureError> {
+    let found: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM temperature_readings WHERE temperature_id = $1) OR EXISTS (SELECT 1 FROM temperature_rollups WHERE temperature_id = $1) OR EXISTS (SELECT 1 FROM temperature_thresholds WHERE temperature_id = $1)")
+        .bind(id)
+        .fetch_one(pool).await?;
+    Ok(found)
 }
 
 pub async fn get_temperatures(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
-    let query = sqlx::query("SELECT * FROM temperatures");
+    let query = sqlx::query("SELECT temperatures.*, (SELECT severity FROM temperature_alerts WHERE temperature_id = temperatures.id AND closed_time IS NULL) AS alert FROM temperatures");
     let rows = query.fetch_all(&data.db_pool).await?;
     let temperatures: Vec<Temperature> = rows.iter().map(|row| {
         Temperature {
             id: row.get("id"),
             label: row.get("label"),
+            location: row.get("location"),
+            unit: row.get("unit"),
+            calibration_offset: row.get("calibration_offset"),
             last_set_time: row.get("last_set_time"),
-            temp: row.get("temp")
+            temp: row.get("temp"),
+            alert: row.get("alert")
         }
     }).collect();
 
@@ -61,14 +154,402 @@ pub struct UpdateTemp {
 }
 
 pub async fn update_temperature(
+    req: HttpRequest,
     update: web::Json<UpdateTemp>,
     data: web::Data<AppState>
 ) -> Result<impl Responder, AppError> {
-    sqlx::query("UPDATE temperatures SET temp = $1, last_set_time = $2 WHERE id = $3")
+    let query = sqlx::query_as::<_, Temperature>("UPDATE temperatures SET temp = $1 + calibration_offset, last_set_time = $2 WHERE id = $3 RETURNING *")
         .bind(update.temp)
-        .bind(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32)
-        .bind(update.id)
-        .execute(&data.db_pool).await?;
+        .bind(now()?)
+        .bind(update.id);
+    let after: Option<Temperature> = query.fetch_optional(&data.db_pool).await?;
+    if let Some(after) = &after {
+        sqlx::query("INSERT INTO temperature_readings (temperature_id, reading_time, temp) values ($1, $2, $3)")
+            .bind(after.id)
+            .bind(after.last_set_time)
+            .bind(after.temp)
+            .execute(&data.db_pool).await?;
+        evaluate_temperature_alerts(after.id, &data.db_pool).await?;
+    }
+    // probes report every few minutes and their readings are kept in temperature_readings,
+    // so the log only keeps one entry per probe an hour to show which device is sending them
+    match &after {
+        Some(after) if !audited_within("temperature", after.id, HOUR, &data.db_pool).await? => {
+            AuditChange::new("temperature", after.id).after(after).record(&req);
+        },
+        _ => skip_audit(&req)
+    }
 
     Ok(HttpResponse::Ok().body("success"))
-}
\ No newline at end of file
+}
+
+/// Folds readings older than a week into hourly rollups and hourly rollups older than 90 days into daily
+/// ones, removing what was folded. Rollups cover whole UTC hours and days, so a bucket only ever comes
+/// from one table and readings that arrive late are merged into the rollup already there.
+pub async fn rollup_temperature_readings(pool: &SqlitePool) -> Result<(), TemperatureError> {
+    let time = now()?;
+    let raw_cutoff = time.saturating_sub(RAW_RETENTION) / HOUR * HOUR;
+    let hourly_cutoff = time.saturating_sub(HOURLY_RETENTION) / DAY * DAY;
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query("INSERT INTO temperature_rollups (temperature_id, resolution, bucket_time, min, max, avg, count) SELECT temperature_id, 'hour', reading_time / $1 * $1, MIN(temp), MAX(temp), AVG(temp), COUNT(*) FROM temperature_readings WHERE reading_time < $2 GROUP BY temperature_id, reading_time / $1 ON CONFLICT (temperature_id, resolution, bucket_time) DO UPDATE SET min = MIN(min, excluded.min), max = MAX(max, excluded.max), avg = (avg * count + excluded.avg * excluded.count) / (count + excluded.count), count = count + excluded.count")
+        .bind(HOUR)
+        .bind(raw_cutoff)
+        .execute(&mut transaction).await?;
+    sqlx::query("DELETE FROM temperature_readings WHERE reading_time < $1")
+        .bind(raw_cutoff)
+        .execute(&mut transaction).await?;
+
+    sqlx::query("INSERT INTO temperature_rollups (temperature_id, resolution, bucket_time, min, max, avg, count) SELECT temperature_id, 'day', bucket_time / $1 * $1, MIN(min), MAX(max), SUM(avg * count) / SUM(count), SUM(count) FROM temperature_rollups WHERE resolution = 'hour' AND bucket_time < $2 GROUP BY temperature_id, bucket_time / $1 ON CONFLICT (temperature_id, resolution, bucket_time) DO UPDATE SET min = MIN(min, excluded.min), max = MAX(max, excluded.max), avg = (avg * count + excluded.avg * excluded.count) / (count + excluded.count), count = count + excluded.count")
+        .bind(DAY)
+        .bind(hourly_cutoff)
+        .execute(&mut transaction).await?;
+    sqlx::query("DELETE FROM temperature_rollups WHERE resolution = 'hour' AND bucket_time < $1")
+        .bind(hourly_cutoff)
+        .execute(&mut transaction).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryQuery {
+    // unix times, both included, the last day when left out
+    from: Option<u32>,
+    to: Option<u32>,
+    // bucket size in seconds, rolled up history can't be split finer than its rollups
+    resolution: Option<u32>
+}
+
+pub async fn get_temperature_history(
+    id: web::Path<u32>,
+    query: web::Query<HistoryQuery>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let probe: Option<u32> = sqlx::query_scalar("SELECT id FROM temperatures WHERE id = $1")
+        .bind(*id)
+        .fetch_optional(&data.db_pool).await?;
+    let id = probe.ok_or(TemperatureError::NotFound)?;
+
+    let to = query.to.unwrap_or(now()?);
+    let from = query.from.unwrap_or(to.saturating_sub(DEFAULT_HISTORY_RANGE));
+    let resolution = query.resolution.unwrap_or(((to.saturating_sub(from)) / DEFAULT_BUCKETS).max(MIN_RESOLUTION));
+    if from >= to || resolution < MIN_RESOLUTION {
+        return Err(TemperatureError::InvalidRange.into());
+    }
+
+    let buckets: Vec<TemperatureBucket> = sqlx::query_as::<_, TemperatureBucket>("WITH samples AS (SELECT reading_time AS time, temp AS min, temp AS max, temp AS total, 1 AS count FROM temperature_readings WHERE temperature_id = $1 AND reading_time >= $2 AND reading_time <= $3 UNION ALL SELECT bucket_time, min, max, avg * count, count FROM temperature_rollups WHERE temperature_id = $1 AND bucket_time >= $2 AND bucket_time <= $3) SELECT time / $4 * $4 AS time, MIN(min) AS min, MAX(max) AS max, SUM(total) * 1.0 / SUM(count) AS avg, SUM(count) AS count FROM samples GROUP BY time / $4 ORDER BY time ASC")
+        .bind(id)
+        .bind(from)
+        .bind(to)
+        .bind(resolution)
+        .fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(TemperatureHistory { id, from, to, resolution, buckets }))
+}
+
+pub async fn get_probes(data: web::Data<AppState>) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, Temperature>("SELECT * FROM temperatures ORDER BY label ASC");
+    let rows: Vec<Temperature> = query.fetch_all(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+fn default_unit() -> String {
+    DEFAULT_UNIT.to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewProbe {
+    label: String,
+    location: Option<String>,
+    #[serde(default = "default_unit")]
+    unit: String,
+    // added to every reading the probe sends, in the probe's unit
+    #[serde(default)]
+    calibration_offset: i32
+}
+
+pub async fn create_probe(
+    req: HttpRequest,
+    probe: web::Json<NewProbe>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let row = create_temperature_probe(&probe.label, probe.location.as_deref(), &probe.unit, probe.calibration_offset, &data.db_pool).await?;
+    AuditChange::new("temperature_probe", row.id).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+// fields left out are unchanged, an empty location clears it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateProbe {
+    label: Option<String>,
+    location: Option<String>,
+    unit: Option<String>,
+    calibration_offset: Option<i32>
+}
+
+// a new calibration offset only applies to readings sent after the change, the unit can only
+// change before the probe has anything stored in the old one
+pub async fn update_probe(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    update: web::Json<UpdateProbe>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let probe = get_probe(*id, &data.db_pool).await?;
+    if let Some(unit) = &update.unit {
+        if !is_valid_unit(unit) {
+            return Err(TemperatureError::InvalidUnit(unit.clone()).into());
+        }
+        if *unit != probe.unit && has_unit_data(probe.id, &data.db_pool).await? {
+            return Err(TemperatureError::UnitInUse.into());
+        }
+    }
+    let location = match update.location.as_deref() {
+        Some("") => None,
+        Some(location) => Some(location),
+        None => probe.location.as_deref()
+    };
+
+    let query = sqlx::query_as::<_, Temperature>("UPDATE temperatures SET label = $1, location = $2, unit = $3, calibration_offset = $4 WHERE id = $5 RETURNING *")
+        .bind(update.label.as_ref().unwrap_or(&probe.label))
+        .bind(location)
+        .bind(update.unit.as_ref().unwrap_or(&probe.unit))
+        .bind(update.calibration_offset.unwrap_or(probe.calibration_offset))
+        .bind(probe.id);
+    let row: Temperature = query.fetch_one(&data.db_pool).await?;
+    AuditChange::new("temperature_probe", row.id).before(&probe).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+// the probe's readings, rollups, thresholds and alerts go with it
+pub async fn delete_probe(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let probe = get_probe(*id, &data.db_pool).await?;
+
+    let mut transaction = data.db_pool.begin().await?;
+    for table in ["temperature_readings", "temperature_rollups", "temperature_thresholds", "temperature_alerts"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE temperature_id = $1", table))
+            .bind(probe.id)
+            .execute(&mut transaction).await?;
+    }
+    sqlx::query("DELETE FROM temperatures WHERE id = $1")
+        .bind(probe.id)
+        .execute(&mut transaction).await?;
+    transaction.commit().await?;
+    AuditChange::new("temperature_probe", probe.id).before(&probe).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service};
+    use crate::{test_pool, test_state};
+    use crate::audit::AuditLog;
+
+    async fn add_reading(id: u32, reading_time: u32, temp: i32, pool: &SqlitePool) {
+        sqlx::query("INSERT INTO temperature_readings (temperature_id, reading_time, temp) values ($1, $2, $3)")
+            .bind(id)
+            .bind(reading_time)
+            .bind(temp)
+            .execute(pool).await.unwrap();
+    }
+
+    async fn add_hourly_rollup(id: u32, bucket_time: u32, min: i32, max: i32, avg: f64, count: u32, pool: &SqlitePool) {
+        sqlx::query("INSERT INTO temperature_rollups (temperature_id, resolution, bucket_time, min, max, avg, count) values ($1, 'hour', $2, $3, $4, $5, $6)")
+            .bind(id)
+            .bind(bucket_time)
+            .bind(min)
+            .bind(max)
+            .bind(avg)
+            .bind(count)
+            .execute(pool).await.unwrap();
+    }
+
+    async fn rollups(id: u32, resolution: &str, pool: &SqlitePool) -> Vec<(u32, i32, i32, f64, u32)> {
+        sqlx::query_as("SELECT bucket_time, min, max, avg, count FROM temperature_rollups WHERE temperature_id = $1 AND resolution = $2 ORDER BY bucket_time ASC")
+            .bind(id)
+            .bind(resolution)
+            .fetch_all(pool).await.unwrap()
+    }
+
+    async fn reading_times(id: u32, pool: &SqlitePool) -> Vec<u32> {
+        sqlx::query_scalar("SELECT reading_time FROM temperature_readings WHERE temperature_id = $1 ORDER BY reading_time ASC")
+            .bind(id)
+            .fetch_all(pool).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn old_readings_fold_into_hourly_rollups() {
+        let pool = test_pool().await;
+        let probe = create_temperature_probe("server room", None, "C", 0, &pool).await.unwrap();
+        let time = now().unwrap();
+        let hour = (time - 10 * DAY) / HOUR * HOUR;
+        add_reading(probe.id, hour + 60, 20, &pool).await;
+        add_reading(probe.id, hour + 120, 22, &pool).await;
+        add_reading(probe.id, hour + HOUR - 1, 24, &pool).await;
+        add_reading(probe.id, hour + HOUR, 30, &pool).await;
+        // readings from the last week are left alone
+        add_reading(probe.id, time - HOUR, 21, &pool).await;
+
+        rollup_temperature_readings(&pool).await.unwrap();
+        assert_eq!(rollups(probe.id, "hour", &pool).await, vec![(hour, 20, 24, 22.0, 3), (hour + HOUR, 30, 30, 30.0, 1)]);
+        assert_eq!(reading_times(probe.id, &pool).await, vec![time - HOUR]);
+
+        // a reading that turns up after its hour was rolled up is merged into that rollup
+        add_reading(probe.id, hour + 1800, 18, &pool).await;
+        rollup_temperature_readings(&pool).await.unwrap();
+        assert_eq!(rollups(probe.id, "hour", &pool).await, vec![(hour, 18, 24, 21.0, 4), (hour + HOUR, 30, 30, 30.0, 1)]);
+        assert_eq!(reading_times(probe.id, &pool).await, vec![time - HOUR]);
+    }
+
+    #[actix_rt::test]
+    async fn old_hourly_rollups_fold_into_daily_rollups() {
+        let pool = test_pool().await;
+        let probe = create_temperature_probe("server room", None, "C", 0, &pool).await.unwrap();
+        let other = create_temperature_probe("office", None, "C", 0, &pool).await.unwrap();
+        let time = now().unwrap();
+        let day = (time - 100 * DAY) / DAY * DAY;
+        add_hourly_rollup(probe.id, day, 10, 20, 15.0, 2, &pool).await;
+        add_hourly_rollup(probe.id, day + HOUR, 12, 30, 21.0, 4, &pool).await;
+        add_hourly_rollup(probe.id, day + DAY, 5, 5, 5.0, 1, &pool).await;
+        add_hourly_rollup(other.id, day, 40, 40, 40.0, 1, &pool).await;
+        // hourly rollups from the last 90 days stay hourly
+        let recent = (time - 30 * DAY) / HOUR * HOUR;
+        add_hourly_rollup(probe.id, recent, 19, 19, 19.0, 1, &pool).await;
+
+        rollup_temperature_readings(&pool).await.unwrap();
+        // the average is weighted by how many readings each hour held
+        assert_eq!(rollups(probe.id, "day", &pool).await, vec![(day, 10, 30, 19.0, 6), (day + DAY, 5, 5, 5.0, 1)]);
+        assert_eq!(rollups(other.id, "day", &pool).await, vec![(day, 40, 40, 40.0, 1)]);
+        assert_eq!(rollups(probe.id, "hour", &pool).await, vec![(recent, 19, 19, 19.0, 1)]);
+
+        // a very late reading passes through an hourly rollup and is merged into the existing day
+        add_reading(probe.id, day + 2 * HOUR, 26, &pool).await;
+        rollup_temperature_readings(&pool).await.unwrap();
+        assert_eq!(rollups(probe.id, "day", &pool).await, vec![(day, 10, 30, 20.0, 7), (day + DAY, 5, 5, 5.0, 1)]);
+        assert_eq!(rollups(probe.id, "hour", &pool).await, vec![(recent, 19, 19, 19.0, 1)]);
+        assert!(reading_times(probe.id, &pool).await.is_empty());
+    }
+
+    async fn change_unit(id: u32, unit: &str, pool: &SqlitePool) -> StatusCode {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(pool)))
+            .route("/api/temperatures/probes/{id}", web::put().to(update_probe))).await;
+        let request = TestRequest::put().uri(&format!("/api/temperatures/probes/{}", id)).set_json(serde_json::json!({ "unit": unit })).to_request();
+        call_service(&app, request).await.status()
+    }
+
+    #[actix_rt::test]
+    async fn unit_changes_only_before_anything_is_stored() {
+        let pool = test_pool().await;
+        create_temperature_probe("new", None, "C", 0, &pool).await.unwrap();
+        create_temperature_probe("reading", None, "C", 0, &pool).await.unwrap();
+        create_temperature_probe("rolled up", None, "C", 0, &pool).await.unwrap();
+        create_temperature_probe("thresholds", None, "C", 0, &pool).await.unwrap();
+        add_reading(2, now().unwrap(), 21, &pool).await;
+        add_hourly_rollup(3, 0, 20, 22, 21.0, 2, &pool).await;
+        sqlx::query("INSERT INTO temperature_thresholds (temperature_id, warning_high, hysteresis, consecutive_samples) values (4, 26, 1, 3)")
+            .execute(&pool).await.unwrap();
+
+        assert_eq!(change_unit(1, "F", &pool).await, StatusCode::OK);
+        assert_eq!(get_probe(1, &pool).await.unwrap().unit, "F");
+        for id in [2, 3, 4] {
+            assert_eq!(change_unit(id, "F", &pool).await, StatusCode::CONFLICT);
+            assert_eq!(get_probe(id, &pool).await.unwrap().unit, "C");
+            // sending the unit the probe already has is not a change
+            assert_eq!(change_unit(id, "C", &pool).await, StatusCode::OK);
+        }
+        assert_eq!(change_unit(1, "K", &pool).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn only_placeholder_readings_are_cleared_on_startup() {
+        let pool = test_pool().await;
+        create_temperature_probe("legacy", None, "C", 0, &pool).await.unwrap();
+        create_temperature_probe("hot", None, "F", 0, &pool).await.unwrap();
+        create_temperature_probe("rolled up", None, "F", 0, &pool).await.unwrap();
+        sqlx::query("UPDATE temperatures SET temp = $1, last_set_time = $2")
+            .bind(LEGACY_NO_READING)
+            .bind(now().unwrap())
+            .execute(&pool).await.unwrap();
+        add_reading(2, now().unwrap(), LEGACY_NO_READING, &pool).await;
+        add_hourly_rollup(3, 0, 250, LEGACY_NO_READING, 252.5, 2, &pool).await;
+
+        create_temperature_table(&pool).await.unwrap();
+        assert_eq!(get_probe(1, &pool).await.unwrap().temp, None);
+        assert_eq!(get_probe(2, &pool).await.unwrap().temp, Some(LEGACY_NO_READING));
+        assert_eq!(get_probe(3, &pool).await.unwrap().temp, Some(LEGACY_NO_READING));
+    }
+
+    #[actix_rt::test]
+    async fn history_merges_readings_and_rollups() {
+        let pool = test_pool().await;
+        let probe = create_temperature_probe("server room", None, "C", 0, &pool).await.unwrap();
+        let base = (now().unwrap() - 10 * DAY) / DAY * DAY;
+        add_hourly_rollup(probe.id, base, 10, 20, 15.0, 2, &pool).await;
+        add_reading(probe.id, base + 1800, 30, &pool).await;
+        add_reading(probe.id, base + HOUR + 60, 22, &pool).await;
+        // outside the range asked for
+        add_reading(probe.id, base + 2 * HOUR, 40, &pool).await;
+
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/temperatures/{id}/history", web::get().to(get_temperature_history))).await;
+        let history = |id: u32, from: u32, to: u32, resolution: u32| TestRequest::get()
+            .uri(&format!("/api/temperatures/{}/history?from={}&to={}&resolution={}", id, from, to, resolution))
+            .to_request();
+        let buckets = |history: serde_json::Value| -> Vec<TemperatureBucket> { serde_json::from_value(history["buckets"].clone()).unwrap() };
+        let summary = |buckets: Vec<TemperatureBucket>| -> Vec<(u32, i32, i32, f64, u32)> {
+            buckets.into_iter().map(|bucket| (bucket.time, bucket.min, bucket.max, bucket.avg, bucket.count)).collect()
+        };
+
+        let hourly = call_and_read_body_json(&app, history(probe.id, base, base + 2 * HOUR - 1, HOUR)).await;
+        assert_eq!(summary(buckets(hourly)), vec![(base, 10, 30, 20.0, 3), (base + HOUR, 22, 22, 22.0, 1)]);
+        // the rollup's average counts for as many readings as it holds
+        let whole = call_and_read_body_json(&app, history(probe.id, base, base + 2 * HOUR - 1, 2 * HOUR)).await;
+        assert_eq!(summary(buckets(whole)), vec![(base, 10, 30, 20.5, 4)]);
+
+        assert_eq!(call_service(&app, history(probe.id, base, base + HOUR, 30)).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(call_service(&app, history(probe.id, base + HOUR, base, HOUR)).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(call_service(&app, history(probe.id + 1, base, base + HOUR, HOUR)).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn readings_are_audited_once_an_hour_per_probe() {
+        let pool = test_pool().await;
+        create_temperature_probe("server room", None, "C", 0, &pool).await.unwrap();
+        create_temperature_probe("office", None, "C", 0, &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .wrap(AuditLog)
+            .route("/api/temperatures", web::post().to(update_temperature))).await;
+        let reading = |id: u32| TestRequest::post().uri("/api/temperatures").set_json(serde_json::json!({ "id": id, "temp": 21 })).to_request();
+        let entries = |id: u32| sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM audit_log WHERE entity_type = 'temperature' AND entity_id = $1")
+            .bind(id.to_string())
+            .fetch_one(&pool);
+
+        for _ in 0..3 {
+            assert_eq!(call_service(&app, reading(1)).await.status(), StatusCode::OK);
+        }
+        call_service(&app, reading(2)).await;
+        call_service(&app, reading(3)).await;
+        assert_eq!((entries(1).await.unwrap(), entries(2).await.unwrap(), entries(3).await.unwrap()), (1, 1, 0));
+
+        sqlx::query("UPDATE audit_log SET created_time = created_time - $1")
+            .bind(HOUR + 1)
+            .execute(&pool).await.unwrap();
+        call_service(&app, reading(1)).await;
+        call_service(&app, reading(1)).await;
+        assert_eq!(entries(1).await.unwrap(), 2);
+    }
+}
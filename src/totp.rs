@@ -0,0 +1,451 @@
+use actix_web::{Responder, HttpRequest, HttpResponse, web};
+use qrcode::{QrCode, render::svg};
+use rand::RngCore;
+use sqlx::{SqlitePool};
+use std::time::SystemTimeError;
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use totp_rs::{Algorithm, Secret, SecretParseError, TotpUrlError, TOTP};
+use crate::{AppState, AppError, hash_secret, generate_secret, now};
+use crate::audit::AuditChange;
+use crate::users::{UserError, AuthenticatedUser, LoginResponse, get_user, get_user_by_username, issue_access_token};
+use crate::refresh_tokens::issue_refresh_token;
+use crate::sessions::{start_session, session_login_response};
+use crate::login_throttle::{check_login_allowed, record_login_failure, clear_login_failures};
+
+const TOTP_ISSUER: &str = "Work Dashboard";
+const TOTP_STEP: u64 = 30;
+// steps either side of the current one that are still accepted, to allow for clock drift
+const TOTP_SKEW: u64 = 1;
+const CHALLENGE_LIFETIME: u32 = 60 * 5;
+const CHALLENGE_ATTEMPTS: u32 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum TotpError {
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    UserError(#[from] UserError),
+    #[error(transparent)]
+    SecretError(#[from] SecretParseError),
+    #[error(transparent)]
+    UrlError(#[from] TotpUrlError),
+    #[error(transparent)]
+    QrError(#[from] qrcode::types::QrError),
+    #[error("two-factor authentication is already enabled")]
+    AlreadyEnabled,
+    #[error("two-factor authentication is not set up")]
+    NotEnrolled,
+    #[error("code is invalid")]
+    InvalidCode,
+    #[error("login challenge is invalid or expired")]
+    InvalidChallenge
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct TotpCredential {
+    id: u32,
+    username: String,
+    secret: String,
+    enabled: bool,
+    created_time: u32,
+    last_used_step: Option<i64>
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug)]
+pub struct LoginChallenge {
+    id: u32,
+    username: String,
+    challenge_hash: String,
+    created_time: u32,
+    expires_time: u32,
+    attempts: u32,
+    used: bool
+}
+
+pub async fn create_totp_tables(pool: &SqlitePool) -> Result<(), TotpError> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS totp_credentials (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT UNIQUE NOT NULL, secret TEXT NOT NULL, enabled INTEGER, created_time INTEGER, last_used_step INTEGER)")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS totp_recovery_codes (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT NOT NULL, code_hash TEXT UNIQUE NOT NULL, used INTEGER)")
+        .execute(pool).await?;
+    sqlx::query("CREATE TABLE IF NOT EXISTS login_challenges (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT NOT NULL, challenge_hash TEXT UNIQUE NOT NULL, created_time INTEGER, expires_time INTEGER, attempts INTEGER, used INTEGER)")
+        .execute(pool).await?;
+    Ok(())
+}
+
+fn build_totp(credential: &TotpCredential) -> Result<TOTP, TotpError> {
+    let secret = Secret::Encoded(credential.secret.clone()).to_bytes()?;
+    Ok(TOTP::new(Algorithm::SHA1, 6, 0, TOTP_STEP, secret, Some(TOTP_ISSUER.to_string()), credential.username.clone())?)
+}
+
+async fn get_credential(username: &str, pool: &SqlitePool) -> Result<Option<TotpCredential>, TotpError> {
+    let query = sqlx::query_as::<_, TotpCredential>("SELECT * FROM totp_credentials WHERE username = $1").bind(username);
+    Ok(query.fetch_optional(pool).await?)
+}
+
+pub async fn totp_enabled(username: &str, pool: &SqlitePool) -> Result<bool, TotpError> {
+    Ok(get_credential(username, pool).await?.map(|credential| credential.enabled).unwrap_or(false))
+}
+
+// a code is only accepted once, so the step it was generated for has to be newer than the last one used
+async fn verify_totp_code(credential: &TotpCredential, code: &str, pool: &SqlitePool) -> Result<bool, TotpError> {
+    let totp = build_totp(credential)?;
+    let current_step = now()? as u64 / TOTP_STEP;
+    for step in current_step.saturating_sub(TOTP_SKEW)..=current_step + TOTP_SKEW {
+        if !totp.check(code, step * TOTP_STEP) {
+            continue;
+        }
+        let result = sqlx::query("UPDATE totp_credentials SET last_used_step = $1 WHERE id = $2 AND (last_used_step IS NULL OR last_used_step < $1)")
+            .bind(step as i64)
+            .bind(credential.id)
+            .execute(pool).await?;
+        return Ok(result.rows_affected() == 1);
+    }
+    Ok(false)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase().replace('-', "")
+}
+
+async fn use_recovery_code(username: &str, code: &str, pool: &SqlitePool) -> Result<bool, TotpError> {
+    let result = sqlx::query("UPDATE totp_recovery_codes SET used = $1 WHERE username = $2 AND code_hash = $3 AND used = $4")
+        .bind(true)
+        .bind(username)
+        .bind(hash_secret(&normalize_recovery_code(code)))
+        .bind(false)
+        .execute(pool).await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Checks a code from the authenticator app, or one of the user's unused recovery codes.
+pub async fn verify_second_factor(username: &str, code: &str, pool: &SqlitePool) -> Result<bool, TotpError> {
+    let credential = match get_credential(username, pool).await? {
+        Some(credential) if credential.enabled => credential,
+        _ => return Err(TotpError::NotEnrolled)
+    };
+    if verify_totp_code(&credential, code.trim(), pool).await? {
+        return Ok(true);
+    }
+    use_recovery_code(username, code, pool).await
+}
+
+// recovery codes are shown once and only their hashes are kept, generating new ones replaces the old set
+async fn generate_recovery_codes(username: &str, pool: &SqlitePool) -> Result<Vec<String>, TotpError> {
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE username = $1")
+        .bind(username)
+        .execute(pool).await?;
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let secret = generate_secret();
+        let code = format!("{}-{}-{}", &secret[0..4], &secret[4..8], &secret[8..12]);
+        sqlx::query("INSERT INTO totp_recovery_codes (username, code_hash, used) values ($1, $2, $3)")
+            .bind(username)
+            .bind(hash_secret(&normalize_recovery_code(&code)))
+            .bind(false)
+            .execute(pool).await?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+pub async fn remove_totp(username: &str, pool: &SqlitePool) -> Result<(), TotpError> {
+    sqlx::query("DELETE FROM totp_credentials WHERE username = $1")
+        .bind(username)
+        .execute(pool).await?;
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE username = $1")
+        .bind(username)
+        .execute(pool).await?;
+    sqlx::query("DELETE FROM login_challenges WHERE username = $1")
+        .bind(username)
+        .execute(pool).await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpChallengeResponse {
+    pub totp_required: bool,
+    pub challenge_token: String,
+    pub expires_time: u32
+}
+
+/// Starts the second step of a login once the password has been checked.
+pub async fn create_login_challenge(username: &str, pool: &SqlitePool) -> Result<TotpChallengeResponse, TotpError> {
+    let time = now()?;
+    let challenge_token = generate_secret();
+    let expires_time = time + CHALLENGE_LIFETIME;
+
+    sqlx::query("DELETE FROM login_challenges WHERE expires_time < $1")
+        .bind(time)
+        .execute(pool).await?;
+    sqlx::query("INSERT INTO login_challenges (username, challenge_hash, created_time, expires_time, attempts, used) values ($1, $2, $3, $4, $5, $6)")
+        .bind(username)
+        .bind(hash_secret(&challenge_token))
+        .bind(time)
+        .bind(expires_time)
+        .bind(0)
+        .bind(false)
+        .execute(pool).await?;
+
+    Ok(TotpChallengeResponse { totp_required: true, challenge_token, expires_time })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    secret: String,
+    provisioning_uri: String,
+    qr_code_svg: String
+}
+
+pub async fn start_totp_enrollment(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if totp_enabled(&auth.username, &data.db_pool).await? {
+        return Err(TotpError::AlreadyEnabled.into());
+    }
+
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = Secret::Raw(secret.to_vec()).to_encoded().to_string();
+
+    // starting again replaces a previous enrollment that was never confirmed
+    let query = sqlx::query_as::<_, TotpCredential>("INSERT INTO totp_credentials (username, secret, enabled, created_time) values ($1, $2, $3, $4) ON CONFLICT(username) DO UPDATE SET secret = $2, enabled = $3, created_time = $4, last_used_step = NULL RETURNING *")
+        .bind(&auth.username)
+        .bind(&secret)
+        .bind(false)
+        .bind(now()?);
+    let credential: TotpCredential = query.fetch_one(&data.db_pool).await?;
+
+    let provisioning_uri = build_totp(&credential)?.get_url();
+    let qr_code_svg = QrCode::new(provisioning_uri.as_bytes()).map_err(TotpError::from)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+
+    AuditChange::new("totp", &auth.username).record(&req);
+    Ok(HttpResponse::Ok().json(TotpEnrollment { secret, provisioning_uri, qr_code_svg }))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TotpCode {
+    code: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>
+}
+
+pub async fn confirm_totp_enrollment(
+    req: HttpRequest,
+    confirm: web::Json<TotpCode>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    let credential = match get_credential(&auth.username, &data.db_pool).await? {
+        Some(credential) if credential.enabled => return Err(TotpError::AlreadyEnabled.into()),
+        Some(credential) => credential,
+        None => return Err(TotpError::NotEnrolled.into())
+    };
+    if !verify_totp_code(&credential, confirm.code.trim(), &data.db_pool).await? {
+        return Err(TotpError::InvalidCode.into());
+    }
+
+    sqlx::query("UPDATE totp_credentials SET enabled = $1 WHERE id = $2")
+        .bind(true)
+        .bind(credential.id)
+        .execute(&data.db_pool).await?;
+    let recovery_codes = generate_recovery_codes(&auth.username, &data.db_pool).await?;
+    AuditChange::new("totp", &auth.username).record(&req);
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn regenerate_recovery_codes(
+    req: HttpRequest,
+    confirm: web::Json<TotpCode>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if !verify_second_factor(&auth.username, &confirm.code, &data.db_pool).await? {
+        return Err(TotpError::InvalidCode.into());
+    }
+    let recovery_codes = generate_recovery_codes(&auth.username, &data.db_pool).await?;
+    AuditChange::new("totp", &auth.username).record(&req);
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn disable_totp(
+    req: HttpRequest,
+    confirm: web::Json<TotpCode>,
+    data: web::Data<AppState>,
+    auth: AuthenticatedUser
+) -> Result<impl Responder, AppError> {
+    if !verify_second_factor(&auth.username, &confirm.code, &data.db_pool).await? {
+        return Err(TotpError::InvalidCode.into());
+    }
+    remove_totp(&auth.username, &data.db_pool).await?;
+    AuditChange::new("totp", &auth.username).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+// for users who have lost both their authenticator and their recovery codes
+pub async fn reset_user_totp(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let user = get_user(*id, &data.db_pool).await?;
+    remove_totp(&user.username, &data.db_pool).await?;
+    AuditChange::new("totp", &user.username).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TotpLoginRequest {
+    challenge_token: String,
+    code: String,
+    #[serde(default)]
+    session: bool
+}
+
+pub async fn login_totp(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    login_attempt: web::Json<TotpLoginRequest>
+) -> Result<impl Responder, AppError> {
+    let query = sqlx::query_as::<_, LoginChallenge>("SELECT * FROM login_challenges WHERE challenge_hash = $1").bind(hash_secret(&login_attempt.challenge_token));
+    let challenge: LoginChallenge = match query.fetch_optional(&data.db_pool).await? {
+        Some(challenge) if !challenge.used && challenge.attempts < CHALLENGE_ATTEMPTS && challenge.expires_time >= now()? => challenge,
+        _ => return Err(TotpError::InvalidChallenge.into())
+    };
+
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let subjects = [("username", challenge.username.as_str()), ("ip", ip.as_str())];
+    let wait = check_login_allowed(&subjects, &data.login_throttle, &data.db_pool).await?;
+    if wait > 0 {
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", wait.to_string()))
+            .body(format!("429 - Too many failed login attempts, try again in {} seconds", wait)));
+    }
+
+    if !verify_second_factor(&challenge.username, &login_attempt.code, &data.db_pool).await? {
+        sqlx::query("UPDATE login_challenges SET attempts = attempts + 1 WHERE id = $1")
+            .bind(challenge.id)
+            .execute(&data.db_pool).await?;
+        record_login_failure(&subjects, &data.login_throttle, &data.db_pool).await?;
+        return Err(TotpError::InvalidCode.into());
+    }
+
+    // challenges are single use, only the request that flips used gets the tokens
+    let result = sqlx::query("UPDATE login_challenges SET used = $1 WHERE id = $2 AND used = $3")
+        .bind(true)
+        .bind(challenge.id)
+        .bind(false)
+        .execute(&data.db_pool).await?;
+    if result.rows_affected() == 0 {
+        return Err(TotpError::InvalidChallenge.into());
+    }
+    // only the username is cleared, the address keeps its failures until they expire
+    clear_login_failures(&subjects[..1], &data.db_pool).await?;
+
+    let user = get_user_by_username(&challenge.username, &data.db_pool).await?;
+    AuditChange::new("user", user.id).actor(&user.username).record(&req);
+    if login_attempt.session {
+        let session = start_session(&user.username, &data.db_pool).await?;
+        return Ok(session_login_response(&session));
+    }
+    let token = issue_access_token(&user, &data.jwt_keys)?;
+    let refresh_token = issue_refresh_token(&user.username, None, &data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(LoginResponse { token, refresh_token }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use crate::{test_pool, test_state};
+    use crate::users::create_user;
+
+    async fn enroll(username: &str, pool: &SqlitePool) -> TOTP {
+        let secret = Secret::Raw(b"work-dashboard-secret".to_vec()).to_encoded().to_string();
+        sqlx::query("INSERT INTO totp_credentials (username, secret, enabled, created_time) values ($1, $2, $3, $4)")
+            .bind(username)
+            .bind(&secret)
+            .bind(true)
+            .bind(now().unwrap())
+            .execute(pool).await.unwrap();
+        build_totp(&get_credential(username, pool).await.unwrap().unwrap()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn codes_cannot_be_replayed() {
+        let pool = test_pool().await;
+        let totp = enroll("jordan", &pool).await;
+        let time = now().unwrap() as u64;
+
+        assert!(!verify_second_factor("jordan", "000000x", &pool).await.unwrap());
+        assert!(verify_second_factor("jordan", &totp.generate(time), &pool).await.unwrap());
+        assert!(!verify_second_factor("jordan", &totp.generate(time), &pool).await.unwrap());
+        // a code from before the one just used is no good either, even while it is within the skew
+        assert!(!verify_second_factor("jordan", &totp.generate(time - TOTP_STEP), &pool).await.unwrap());
+        assert!(matches!(verify_second_factor("carol", "123456", &pool).await, Err(TotpError::NotEnrolled)));
+    }
+
+    #[actix_rt::test]
+    async fn recovery_codes_work_once() {
+        let pool = test_pool().await;
+        enroll("jordan", &pool).await;
+        let codes = generate_recovery_codes("jordan", &pool).await.unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+
+        assert!(verify_second_factor("jordan", &format!(" {} ", codes[0].to_uppercase()), &pool).await.unwrap());
+        assert!(!verify_second_factor("jordan", &codes[0], &pool).await.unwrap());
+        assert!(verify_second_factor("jordan", &codes[1].replace('-', ""), &pool).await.unwrap());
+
+        // generating a new set throws the old codes away
+        generate_recovery_codes("jordan", &pool).await.unwrap();
+        assert!(!verify_second_factor("jordan", &codes[2], &pool).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn challenges_only_allow_a_few_attempts() {
+        let pool = test_pool().await;
+        create_user("jordan", "JO", "pass123", "admin", &pool).await.unwrap();
+        let totp = enroll("jordan", &pool).await;
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/login/totp", web::post().to(login_totp))).await;
+        let login = |challenge: &str, code: &str| TestRequest::post()
+            .uri("/api/login/totp")
+            .set_json(serde_json::json!({ "challenge_token": challenge, "code": code }))
+            .to_request();
+
+        let challenge = create_login_challenge("jordan", &pool).await.unwrap().challenge_token;
+        for _ in 0..CHALLENGE_ATTEMPTS {
+            assert_eq!(call_service(&app, login(&challenge, "000000")).await.status(), StatusCode::UNAUTHORIZED);
+        }
+        let code = totp.generate(now().unwrap() as u64);
+        assert_eq!(call_service(&app, login(&challenge, &code)).await.status(), StatusCode::UNAUTHORIZED);
+        assert!(verify_second_factor("jordan", &code, &pool).await.unwrap());
+
+        // a good code uses the challenge up, once the throttle from the failures above is lifted
+        clear_login_failures(&[("username", "jordan"), ("ip", "")], &pool).await.unwrap();
+        let codes = generate_recovery_codes("jordan", &pool).await.unwrap();
+        let challenge = create_login_challenge("jordan", &pool).await.unwrap().challenge_token;
+        assert_eq!(call_service(&app, login(&challenge, &codes[0])).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, login(&challenge, &codes[1])).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,54 +1,267 @@
-use hmac::Hmac;
-use sha2::Sha256;
 use sqlx::{SqlitePool};
-use std::{
-    collections::{ BTreeMap },
-    time::{SystemTime, SystemTimeError }, 
-};
+use std::time::{SystemTime, SystemTimeError };
 use thiserror::Error;
 use bcrypt::{hash, verify, BcryptError};
-use jwt::{SignWithKey, VerifyWithKey, Header, Token};
-use actix_web::{HttpResponse, Responder, dev::ServiceRequest, web};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder, web, dev::{Payload, ServiceRequest}, error::{ErrorForbidden, ErrorUnauthorized}};
+use std::future::{ready, Ready};
 use actix_web_httpauth::extractors::{ bearer::{BearerAuth, Config}, AuthenticationError };
-use serde::{Serialize,Deserialize};
-use crate::AppState;
+use serde::{Serialize, Deserialize, Deserializer};
+use crate::{AppState, AppError, add_column_if_missing, generate_secret};
+use crate::jwt_keys::JwtKeys;
+use crate::api_keys::validate_api_key;
+use crate::refresh_tokens::issue_refresh_token;
+use crate::sessions::{SessionError, start_session, session_login_response, validate_session};
+use crate::ldap::ldap_login;
+use crate::totp::{totp_enabled, create_login_challenge};
+use crate::login_throttle::{check_login_allowed, record_login_failure, clear_login_failures};
+use crate::audit::AuditChange;
+use crate::scopes::GrantedScopes;
+use crate::roles::{ADMIN_ROLE, DEFAULT_ROLE, is_valid_role, role_permissions};
 
 const COST: u32 = 10;
+const ACCESS_TOKEN_LIFETIME: u64 = 3600;
+pub const TOKEN_ISSUER: &str = "workdashboard.com";
+pub const TOKEN_AUDIENCE: &str = "apps";
+// seconds a token's not-before may be ahead of our clock
+const CLOCK_SKEW: u64 = 30;
+// matches users who can lose the admin role without leaving no admins, it goes in the same statement as the
+// demotion or delete so two concurrent requests can't each count the other as the remaining admin
+pub(crate) const OTHER_ADMIN_REMAINS: &str = "(role != 'admin' OR EXISTS (SELECT 1 FROM users AS others WHERE others.role = 'admin' AND others.id != users.id))";
 
 #[derive(Error, Debug)]
 pub enum UserError {
     #[error(transparent)]
     HashingError(#[from] BcryptError),
     #[error(transparent)]
-    DatabaseError(#[from] sqlx::Error)
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    TimeError(#[from] SystemTimeError),
+    #[error(transparent)]
+    SigningError(#[from] jwt::Error),
+    #[error("user not found")]
+    NotFound,
+    #[error("cannot remove or demote the last admin user")]
+    LastAdmin,
+    #[error("unknown role {0}")]
+    InvalidRole(String)
 }
 
 #[derive(sqlx::FromRow, Serialize, Deserialize)]
 pub struct User {
-    id: u32,
-    username: String,
-    initials: String,
+    pub id: u32,
+    pub username: String,
+    pub initials: String,
+    #[serde(skip_serializing)]
     password: String,
-    is_admin: bool
+    pub role: String,
+    #[serde(skip_serializing)]
+    token_generation: u32
 }
 
 pub async fn create_user_table(pool: &SqlitePool) -> Result<(), UserError> {
-    sqlx::query("CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT UNIQUE NOT NULL, initials TEXT UNIQUE NOT NULL, password TEXT NOT NULL, is_admin INTEGER)",)
+    sqlx::query("CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT UNIQUE NOT NULL, initials TEXT UNIQUE NOT NULL, password TEXT NOT NULL, role TEXT NOT NULL DEFAULT 'member', token_generation INTEGER NOT NULL DEFAULT 0)",)
         .execute(pool).await?;
+    add_column_if_missing(pool, "users", "token_generation", "INTEGER NOT NULL DEFAULT 0").await?;
+    // databases from before roles only have the is_admin flag
+    if add_column_if_missing(pool, "users", "role", "TEXT NOT NULL DEFAULT 'member'").await? {
+        sqlx::query("UPDATE users SET role = $1 WHERE is_admin = 1")
+            .bind(ADMIN_ROLE)
+            .execute(pool).await?;
+    }
     Ok(())
 }
 
-pub async fn create_user(username: &str, initials: &str, password: &str, pool: &SqlitePool) -> Result<(), UserError> {
+pub async fn create_user(username: &str, initials: &str, password: &str, role: &str, pool: &SqlitePool) -> Result<User, UserError> {
+    if !is_valid_role(role) {
+        return Err(UserError::InvalidRole(role.to_string()));
+    }
     let secure_password = hash(password, COST)?;
-    sqlx::query("INSERT INTO users (username, initials, password) values ($1, $2, $3)",)
+    let user = sqlx::query_as::<_, User>("INSERT INTO users (username, initials, password, role) values ($1, $2, $3, $4) RETURNING *",)
         .bind(username)
         .bind(initials)
         .bind(secure_password)
+        .bind(role)
+        .fetch_one(pool).await?;
+
+    Ok(user)
+}
+
+pub async fn get_user(id: u32, pool: &SqlitePool) -> Result<User, UserError> {
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1").bind(id);
+    match query.fetch_optional(pool).await? {
+        Some(user) => Ok(user),
+        None => Err(UserError::NotFound)
+    }
+}
+
+pub async fn get_user_by_username(username: &str, pool: &SqlitePool) -> Result<User, UserError> {
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = $1").bind(username);
+    match query.fetch_optional(pool).await? {
+        Some(user) => Ok(user),
+        None => Err(UserError::NotFound)
+    }
+}
+
+// initials are unique so a number is added when the preferred or natural initials are already taken
+async fn available_initials(preferred: Option<&str>, name: Option<&str>, username: &str, pool: &SqlitePool) -> Result<String, UserError> {
+    let base: String = match (preferred.map(str::trim).filter(|initials| !initials.is_empty()), name) {
+        (Some(initials), _) => initials.to_string(),
+        (None, Some(name)) => name.split_whitespace().filter_map(|word| word.chars().next()).take(3).collect(),
+        (None, None) => username.chars().filter(|c| c.is_alphanumeric()).take(2).collect()
+    };
+    let base = base.to_uppercase();
+    let mut suffix = 1;
+    let mut initials = base.clone();
+    loop {
+        let taken: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE initials = $1")
+            .bind(&initials)
+            .fetch_one(pool).await?;
+        if taken == 0 {
+            return Ok(initials);
+        }
+        suffix += 1;
+        initials = format!("{}{}", base, suffix);
+    }
+}
+
+/// Creates a user who signs in through the directory or single sign-on. They never log in with a
+/// local password, so they get a random one nobody knows.
+pub async fn create_external_user(username: &str, preferred_initials: Option<&str>, name: Option<&str>, role: &str, pool: &SqlitePool) -> Result<User, UserError> {
+    let initials = available_initials(preferred_initials, name, username, pool).await?;
+    create_user(username, &initials, &generate_secret(), role, pool).await
+}
+
+pub async fn list_users(pool: &SqlitePool) -> Result<Vec<User>, UserError> {
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY username ASC");
+    Ok(query.fetch_all(pool).await?)
+}
+
+// sets a new password and invalidates every login token, refresh token, session and reset token issued before the change
+pub async fn reset_password(username: &str, password: &str, pool: &SqlitePool) -> Result<(), UserError> {
+    let secure_password = hash(password, COST)?;
+    let result = sqlx::query("UPDATE users SET password = $1, token_generation = token_generation + 1 WHERE username = $2")
+        .bind(secure_password)
+        .bind(username)
+        .execute(pool).await?;
+    if result.rows_affected() == 0 {
+        return Err(UserError::NotFound);
+    }
+
+    sqlx::query("UPDATE refresh_tokens SET revoked = $1 WHERE username = $2")
+        .bind(true)
+        .bind(username)
+        .execute(pool).await?;
+    sqlx::query("UPDATE sessions SET revoked = $1 WHERE username = $2")
+        .bind(true)
+        .bind(username)
+        .execute(pool).await?;
+    sqlx::query("DELETE FROM password_resets WHERE username = $1 AND used = $2")
+        .bind(username)
+        .bind(false)
         .execute(pool).await?;
 
     Ok(())
 }
 
+pub fn verify_password(user: &User, password: &str) -> Result<bool, UserError> {
+    Ok(verify(password, &user.password)?)
+}
+
+pub async fn get_users(
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let rows: Vec<User> = list_users(&data.db_pool).await?;
+
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+fn default_role() -> String {
+    DEFAULT_ROLE.to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewUser {
+    username: String,
+    initials: String,
+    password: String,
+    #[serde(default = "default_role")]
+    role: String
+}
+
+pub async fn add_user(
+    req: HttpRequest,
+    user: web::Json<NewUser>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let row = create_user(&user.username, &user.initials, &user.password, &user.role, &data.db_pool).await?;
+    AuditChange::new("user", row.id).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UpdateUser {
+    initials: Option<String>,
+    role: Option<String>
+}
+
+pub async fn update_user(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    update: web::Json<UpdateUser>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    if let Some(role) = &update.role {
+        if !is_valid_role(role) {
+            return Err(UserError::InvalidRole(role.clone()).into());
+        }
+    }
+
+    // the user is read in the same transaction, so a missing row can only mean the last admin guard
+    let mut transaction = data.db_pool.begin().await?;
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1").bind(*id).fetch_optional(&mut transaction).await? {
+        Some(user) => user,
+        None => return Err(UserError::NotFound.into())
+    };
+    let sql = format!("UPDATE users SET initials = $1, role = $2 WHERE id = $3 AND ($2 = $4 OR {}) RETURNING *", OTHER_ADMIN_REMAINS);
+    let query = sqlx::query_as::<_, User>(&sql)
+        .bind(update.initials.clone().unwrap_or_else(|| user.initials.clone()))
+        .bind(update.role.clone().unwrap_or_else(|| user.role.clone()))
+        .bind(user.id)
+        .bind(ADMIN_ROLE);
+    let row: User = match query.fetch_optional(&mut transaction).await? {
+        Some(row) => row,
+        None => return Err(UserError::LastAdmin.into())
+    };
+    transaction.commit().await?;
+    AuditChange::new("user", row.id).before(&user).after(&row).record(&req);
+
+    Ok(HttpResponse::Ok().json(row))
+}
+
+pub async fn delete_user(
+    req: HttpRequest,
+    id: web::Path<u32>,
+    data: web::Data<AppState>
+) -> Result<impl Responder, AppError> {
+    let mut transaction = data.db_pool.begin().await?;
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1").bind(*id).fetch_optional(&mut transaction).await? {
+        Some(user) => user,
+        None => return Err(UserError::NotFound.into())
+    };
+    let sql = format!("DELETE FROM users WHERE id = $1 AND {}", OTHER_ADMIN_REMAINS);
+    let result = sqlx::query(&sql)
+        .bind(user.id)
+        .execute(&mut transaction).await?;
+    if result.rows_affected() == 0 {
+        return Err(UserError::LastAdmin.into());
+    }
+    transaction.commit().await?;
+    AuditChange::new("user", user.id).before(&user).record(&req);
+
+    Ok(HttpResponse::Ok().body("success"))
+}
+
 fn get_time_since_epoch() -> Result<u64, SystemTimeError> {
     Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
 }
@@ -56,189 +269,383 @@ fn get_time_since_epoch() -> Result<u64, SystemTimeError> {
 #[derive(Serialize, Deserialize)]
 pub struct LoginRequest {
     username: String,
-    password: String
+    password: String,
+    // browsers ask for an HttpOnly session cookie instead of tokens they would have to store themselves
+    #[serde(default)]
+    session: bool
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginResponse {
-    token: String
+    pub token: String,
+    pub refresh_token: String
+}
+
+pub fn issue_access_token(user: &User, keys: &JwtKeys) -> Result<String, UserError> {
+    let time = get_time_since_epoch()?;
+    let claims = UserToken {
+        iss: TOKEN_ISSUER.to_string(),
+        aud: TOKEN_AUDIENCE.to_string(),
+        sub: user.username.clone(),
+        initials: user.initials.clone(),
+        iat: time,
+        nbf: time,
+        exp: Some(time + ACCESS_TOKEN_LIFETIME),
+        role: Some(user.role.clone()),
+        jti: None,
+        generation: Some(user.token_generation)
+    };
+
+    Ok(keys.sign(claims)?)
 }
 
 pub async fn login(
+    req: HttpRequest,
     data: web::Data<AppState>,
     login_attempt: web::Json<LoginRequest>,
 ) -> impl Responder {
-    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username=$1").bind(&login_attempt.username);
-    let user_record: User = match query.fetch_optional(&data.db_pool).await {
-        Ok(rows) => { 
-            match rows {
-                Some(row) => row,
-                None => return HttpResponse::Forbidden().body("403 - Incorrect username or password")
-            } 
-        }
-        Err(_) => return HttpResponse::Forbidden().body("403 - Login Failed")
-    };
+    // the peer address is used rather than forwarded headers which the client controls
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let subjects = [("username", login_attempt.username.as_str()), ("ip", ip.as_str())];
 
-    let password_matches: bool = match verify(&login_attempt.password, &user_record.password) {
-        Ok(password_matches) => { password_matches },
+    match check_login_allowed(&subjects, &data.login_throttle, &data.db_pool).await {
+        Ok(0) => {},
+        Ok(wait) => return HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", wait.to_string()))
+            .body(format!("429 - Too many failed login attempts, try again in {} seconds", wait)),
         Err(_) => return HttpResponse::Forbidden().body("403 - Login Failed")
-    };
+    }
 
-    match password_matches {
-        true => {
-            match get_time_since_epoch() {
-                Ok(time) => {
-                    let is_admin: String = match user_record.is_admin {
-                        true => "1".to_string(),
-                        false => "0".to_string()
-                    };
-                    let mut claims = BTreeMap::new();
-                    let iat = time.to_string();
-                    let exp = (time + 3600).to_string();
-                    claims.insert("iss", "workdashboard.com");
-                    claims.insert("aud", "apps");
-                    claims.insert("sub", &user_record.username);
-                    claims.insert("initials", &user_record.initials);
-                    claims.insert("is_admin", &is_admin);
-                    claims.insert("iat", &iat);
-                    claims.insert("nbf", &iat);
-                    claims.insert("exp", &exp);
-        
-                    let token = match claims.sign_with_key(&data.jwt_key) {
-                        Ok(token) => token,
-                        Err(_) => return HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
-                    };
-
-                    HttpResponse::Ok().json(LoginResponse { token })
-                },
-                Err(_e) => {
-                    HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
+    // directory users are checked first, local users still work when the directory rejects them or is unreachable
+    let mut user_record: Option<User> = None;
+    if let Some(ldap) = &data.ldap {
+        match ldap_login(&login_attempt.username, &login_attempt.password, ldap, &data.db_pool).await {
+            Ok(user) => user_record = user,
+            Err(e) => println!("LDAP login failed, falling back to local users - {}", e)
+        }
+    }
+
+    if user_record.is_none() {
+        let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username=$1").bind(&login_attempt.username);
+        user_record = match query.fetch_optional(&data.db_pool).await {
+            Ok(Some(row)) => {
+                match verify(&login_attempt.password, &row.password) {
+                    Ok(true) => Some(row),
+                    Ok(false) => None,
+                    Err(_) => return HttpResponse::Forbidden().body("403 - Login Failed")
                 }
             }
+            Ok(None) => None,
+            Err(_) => return HttpResponse::Forbidden().body("403 - Login Failed")
+        };
+    }
+
+    match user_record {
+        Some(user_record) => {
+            // users with two-factor authentication finish logging in through /api/login/totp
+            match totp_enabled(&user_record.username, &data.db_pool).await {
+                Ok(false) => {},
+                Ok(true) => return match create_login_challenge(&user_record.username, &data.db_pool).await {
+                    Ok(challenge) => {
+                        // nobody has logged in yet, /api/login/totp records the login once the code checks out
+                        AuditChange::new("login_challenge", user_record.id).record(&req);
+                        HttpResponse::Ok().json(challenge)
+                    },
+                    Err(_) => HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
+                },
+                Err(_) => return HttpResponse::Forbidden().body("403 - Login Failed")
+            }
+            // the address keeps its failures, otherwise logging into your own account would reset them
+            if let Err(e) = clear_login_failures(&subjects[..1], &data.db_pool).await {
+                println!("unable to clear failed logins - {:?}", e);
+            }
+            if login_attempt.session {
+                return match start_session(&user_record.username, &data.db_pool).await {
+                    Ok(session) => {
+                        AuditChange::new("user", user_record.id).actor(&user_record.username).record(&req);
+                        session_login_response(&session)
+                    },
+                    Err(_) => HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
+                };
+            }
+            let token = match issue_access_token(&user_record, &data.jwt_keys) {
+                Ok(token) => token,
+                Err(_) => return HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
+            };
+            let refresh_token = match issue_refresh_token(&user_record.username, None, &data.db_pool).await {
+                Ok(refresh_token) => refresh_token,
+                Err(_) => return HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
+            };
+
+            AuditChange::new("user", user_record.id).actor(&user_record.username).record(&req);
+
+            HttpResponse::Ok().json(LoginResponse { token, refresh_token })
         }
-        false => {
+        None => {
+            if let Err(e) = record_login_failure(&subjects, &data.login_throttle, &data.db_pool).await {
+                println!("unable to record failed login - {:?}", e);
+            }
             HttpResponse::Forbidden().body("403 - Incorrect username or password")
         }
     }
 }
 
-pub async fn get_api_key(
-    data: web::Data<AppState>,
-    auth: BearerAuth
-) -> impl Responder {
-    let token: Option<UserToken> = parse_token(auth.token(), data.jwt_key.clone());
-    let valid_token = match token {
-        Some(token) => { token },
-        None => return HttpResponse::Forbidden().body("Unable to validate User Identity")
-    };
+// an api key's scopes, or None when the token is a login token
+type TokenIdentity = (User, Option<Vec<String>>, Option<u32>);
 
-    match get_time_since_epoch() {
-        Ok(time) => {
-            if valid_token.is_admin == "1" {
-                let mut claims = BTreeMap::new();
-                let iat = time.to_string();
-                claims.insert("iss", "workdashboard.com");
-                claims.insert("aud", "apps");
-                claims.insert("sub", &valid_token.sub);
-                claims.insert("initials", &valid_token.initials);
-                claims.insert("is_admin", "0");
-                claims.insert("iat", &iat);
-                claims.insert("nbf", &iat);
-
-                let token = match claims.sign_with_key(&data.jwt_key) {
-                    Ok(token) => token,
-                    Err(_) => return HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
-                };
+async fn authenticate_token(token_str: &str, app_state: &AppState) -> Option<TokenIdentity> {
+    let valid_token = parse_token(token_str, &app_state.jwt_keys)?;
 
-                HttpResponse::Ok().json(LoginResponse { token })
-            } else {
-                return HttpResponse::Forbidden().body("Only admin users can generate API keys");
-            }
-        },
-        Err(_) => {
-            HttpResponse::InternalServerError().body("500 - Unexpected error generating token")
+    // api keys carry a jti instead of an expiry and must still exist and be unrevoked
+    let key_scopes: Option<Vec<String>> = match &valid_token.jti {
+        Some(jti) => Some(validate_api_key(jti, token_str, &app_state.db_pool).await.ok()??),
+        None => {
+            valid_token.exp?;
+            None
         }
+    };
+
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username=$1").bind(&valid_token.sub);
+    let user = query.fetch_optional(&app_state.db_pool).await.ok()??;
+
+    // login tokens issued before a password change are no longer accepted, api keys are revoked separately
+    if key_scopes.is_none() && valid_token.generation.unwrap_or_default() != user.token_generation {
+        return None;
     }
+
+    let api_key_id = valid_token.jti.as_ref().and_then(|jti| jti.parse().ok());
+    Some((user, key_scopes, api_key_id))
 }
 
-pub async fn bearer_auth_validator(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+async fn authenticate_session(req: &HttpRequest, app_state: &AppState) -> Result<Option<User>, SessionError> {
+    let session = match validate_session(req, &app_state.db_pool).await? {
+        Some(session) => session,
+        None => return Ok(None)
+    };
+
+    let query = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username=$1").bind(&session.username);
+    let user = match query.fetch_optional(&app_state.db_pool).await? {
+        Some(user) => user,
+        None => return Ok(None)
+    };
+
+    // sessions started before a password change end along with the login tokens
+    if session.token_generation != user.token_generation {
+        return Ok(None);
+    }
+    Ok(Some(user))
+}
+
+/// Accepts either an `Authorization: Bearer` header, used by devices and api keys, or the session cookie set
+/// for browsers by `/api/login`. A request with a header is never checked against the cookie.
+pub async fn bearer_auth_validator(req: ServiceRequest, credentials: Option<BearerAuth>) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
     let config = req
         .app_data::<Config>()
         .map(|data| data.as_ref().clone())
-        .unwrap_or_else(Default::default);
+        .unwrap_or_default();
 
     let app_state: &AppState = req.app_data::<web::Data<AppState>>().expect("AppState missing in request handler.");
 
-    let token_str = credentials.token();
-    let token: Result<Token<Header, BTreeMap<String, String>, _>,_> = VerifyWithKey::verify_with_key(token_str, &app_state.jwt_key);
-
-    match token {
-        Ok(valid_token) => {
-            // get seconds since the unix epoch default to the year 5138
-            let time_since_epoch: u64 = match get_time_since_epoch() {
-                Ok(time) => time,
-                Err(_) => 99999999999
-            };
+    let (user, key_scopes, api_key_id) = match &credentials {
+        Some(credentials) => match authenticate_token(credentials.token(), app_state).await {
+            Some(identity) => identity,
+            None => return Err((AuthenticationError::new(config).into(), req))
+        },
+        None => match authenticate_session(req.request(), app_state).await {
+            Ok(Some(user)) => (user, None, None),
+            Err(SessionError::InvalidCsrfToken) => return Err((ErrorForbidden("403 - Missing or invalid CSRF token"), req)),
+            _ => return Err((AuthenticationError::new(config).into(), req))
+        }
+    };
 
-            // get expiry of jwt token default to unix epoch on error 
-            let exp: u64 = match valid_token.claims().contains_key("exp") {
-                true => {
-                    match valid_token.claims()["exp"].parse() {
-                        Ok(value) => value,
-                        Err(_) => 0
-                    }
-                }
-                false => u32::MAX as u64
-            };
+    let permissions = match role_permissions(&user.role, &app_state.db_pool).await {
+        Ok(permissions) => permissions,
+        Err(_) => { return Err((AuthenticationError::new(config).into(), req)); }
+    };
+    // an api key can never do more than its owner's role currently allows
+    let scopes = match key_scopes {
+        Some(mut scopes) => {
+            scopes.retain(|scope| permissions.contains(scope));
+            scopes
+        },
+        None => permissions
+    };
+    req.extensions_mut().insert(GrantedScopes(scopes));
+    req.extensions_mut().insert(AuthenticatedUser {
+        username: user.username,
+        initials: user.initials,
+        api_key_id
+    });
 
-            if time_since_epoch > exp  {
-                return Err((AuthenticationError::new(config).into(), req));
-            }
+    Ok(req)
+}
 
-            let query = sqlx::query("SELECT * FROM users WHERE username=$1").bind(valid_token.claims()["sub"].clone());
-            let rows = match query.fetch_optional(&app_state.db_pool).await {
-                Ok(rows) => { rows }
-                Err(_) => { return Err((AuthenticationError::new(config).into(), req)); }
-            };
+/// The user behind the current request, inserted into the request extensions by `bearer_auth_validator`.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    pub initials: String,
+    pub api_key_id: Option<u32>
+}
 
-            match rows {
-                Some(_) => {},
-                None => { return Err((AuthenticationError::new(config).into(), req)); }
-            }
+// handlers take the user as an argument, the route is only reachable once the validator has run
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
 
-            Ok(req)
-        },
-        Err(_e) => Err((AuthenticationError::new(config).into(), req))
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<AuthenticatedUser>().cloned().ok_or_else(|| ErrorUnauthorized("401 - Unable to validate User Identity")))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserToken {
     pub iss: String,
     pub aud: String,
     pub sub: String,
     pub initials: String,
-    pub iat: String,
-    pub nbf: String,
-    pub exp: String,
-    pub is_admin: String
-}
-
-pub fn parse_token(token: &str, key: Hmac<Sha256>) -> Option<UserToken> {
-    let token: Result<Token<Header, BTreeMap<String, String>, _>,_> = VerifyWithKey::verify_with_key(token, &key);
-    match token {
-        Ok(token) => {
-            Some(UserToken {
-                iss: token.claims()["iss"].clone(),
-                aud: token.claims()["aud"].clone(),
-                sub: token.claims()["sub"].clone(),
-                initials: token.claims()["initials"].clone(),
-                iat: token.claims()["iat"].clone(),
-                nbf: token.claims()["nbf"].clone(),
-                exp: token.claims()["exp"].clone(),
-                is_admin: token.claims()["is_admin"].clone()
-            })
-        }
-        Err(_) => None
+    #[serde(deserialize_with = "legacy_number")]
+    pub iat: u64,
+    #[serde(deserialize_with = "legacy_number")]
+    pub nbf: u64,
+    #[serde(default, deserialize_with = "legacy_optional_number", skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    // informational only, permissions are looked up from the user's current role on every request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    // the user's token generation when a login token was issued, changing the password moves it on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u32>
+}
+
+// tokens issued before the claims were typed store every claim as a string, login tokens from then stay valid until they expire
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyClaim<T> {
+    Typed(T),
+    Text(String)
+}
+
+fn legacy_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match LegacyClaim::<u64>::deserialize(deserializer)? {
+        LegacyClaim::Typed(value) => Ok(value),
+        LegacyClaim::Text(value) => value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn legacy_optional_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    legacy_number(deserializer).map(Some)
+}
+
+/// Verifies the token signature and checks the issuer, audience, not-before and expiry claims.
+/// A missing `exp` is allowed here, `bearer_auth_validator` only accepts that for api keys.
+pub fn parse_token(token: &str, keys: &JwtKeys) -> Option<UserToken> {
+    let claims: UserToken = keys.verify(token).ok()?;
+    let time = get_time_since_epoch().ok()?;
+
+    if claims.iss != TOKEN_ISSUER || claims.aud != TOKEN_AUDIENCE {
+        return None;
     }
-}
\ No newline at end of file
+    if claims.nbf > time + CLOCK_SKEW {
+        return None;
+    }
+    match claims.exp {
+        Some(exp) if time > exp => None,
+        _ => Some(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use hmac::{Hmac, Mac};
+    use serde_json::json;
+    use crate::{test_pool, test_state};
+    use jwt::SignWithKey;
+    use sha2::Sha256;
+    use std::collections::BTreeMap;
+
+    // the claims /api/login issued before they were typed, signed without a kid
+    fn legacy_token(secret: &str, iss: &str, exp: u64) -> String {
+        let time = get_time_since_epoch().unwrap().to_string();
+        let exp = exp.to_string();
+        let claims = BTreeMap::from([
+            ("iss", iss), ("aud", TOKEN_AUDIENCE), ("sub", "jordan"), ("initials", "JO"),
+            ("is_admin", "1"), ("iat", time.as_str()), ("nbf", time.as_str()), ("exp", exp.as_str())
+        ]);
+        claims.sign_with_key(&Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn the_last_admin_cannot_be_demoted_or_deleted() {
+        let pool = test_pool().await;
+        let carol = create_user("carol", "CA", "pass123", ADMIN_ROLE, &pool).await.unwrap();
+        let dave = create_user("dave", "DA", "pass123", DEFAULT_ROLE, &pool).await.unwrap();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(test_state(&pool)))
+            .route("/api/users/{id}", web::put().to(update_user))
+            .route("/api/users/{id}", web::delete().to(delete_user))).await;
+        let update = |id: u32, body: serde_json::Value| TestRequest::put().uri(&format!("/api/users/{}", id)).set_json(body).to_request();
+        let delete = |id: u32| TestRequest::delete().uri(&format!("/api/users/{}", id)).to_request();
+
+        assert_eq!(call_service(&app, update(carol.id, json!({ "role": DEFAULT_ROLE }))).await.status(), StatusCode::CONFLICT);
+        assert_eq!(call_service(&app, delete(carol.id)).await.status(), StatusCode::CONFLICT);
+        // changes that keep the role are still allowed
+        assert_eq!(call_service(&app, update(carol.id, json!({ "initials": "CB" }))).await.status(), StatusCode::OK);
+
+        // a missing user is not found rather than blocked by the guard
+        assert_eq!(call_service(&app, update(999, json!({ "role": DEFAULT_ROLE }))).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(call_service(&app, delete(999)).await.status(), StatusCode::NOT_FOUND);
+
+        // once another admin exists the first can step down or be removed
+        assert_eq!(call_service(&app, update(dave.id, json!({ "role": ADMIN_ROLE }))).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, update(carol.id, json!({ "role": DEFAULT_ROLE }))).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, delete(dave.id)).await.status(), StatusCode::CONFLICT);
+        assert_eq!(call_service(&app, delete(carol.id)).await.status(), StatusCode::OK);
+        assert_eq!(get_user(dave.id, &pool).await.unwrap().role, ADMIN_ROLE);
+    }
+
+    #[test]
+    fn legacy_string_claims_are_still_read() {
+        let keys = JwtKeys::new("1", "secret");
+        let time = get_time_since_epoch().unwrap();
+
+        let claims = parse_token(&legacy_token("secret", TOKEN_ISSUER, time + 600), &keys).unwrap();
+        assert_eq!(claims.sub, "jordan");
+        assert_eq!(claims.exp, Some(time + 600));
+        assert!(claims.iat >= time && claims.nbf == claims.iat);
+        assert_eq!((claims.role, claims.jti, claims.generation), (None, None, None));
+
+        assert!(parse_token(&legacy_token("secret", TOKEN_ISSUER, time - 600), &keys).is_none());
+        assert!(parse_token(&legacy_token("secret", "elsewhere.com", time + 600), &keys).is_none());
+        assert!(parse_token(&legacy_token("other secret", TOKEN_ISSUER, time + 600), &keys).is_none());
+    }
+
+    #[test]
+    fn typed_claims_round_trip() {
+        let keys = JwtKeys::new("1", "secret");
+        let time = get_time_since_epoch().unwrap();
+        let claims = UserToken {
+            iss: TOKEN_ISSUER.to_string(),
+            aud: TOKEN_AUDIENCE.to_string(),
+            sub: "jordan".to_string(),
+            initials: "JO".to_string(),
+            iat: time,
+            nbf: time,
+            exp: None,
+            role: None,
+            jti: Some("7".to_string()),
+            generation: None
+        };
+        let parsed = parse_token(&keys.sign(&claims).unwrap(), &keys).unwrap();
+        assert_eq!((parsed.exp, parsed.jti.as_deref()), (None, Some("7")));
+
+        let not_yet = UserToken { nbf: time + CLOCK_SKEW + 60, ..claims };
+        assert!(parse_token(&keys.sign(&not_yet).unwrap(), &keys).is_none());
+    }
+}